@@ -0,0 +1,68 @@
+//! Attribute macros for the [malloc-info](https://docs.rs/malloc-info) crate. Use them through the
+//! `macros` feature of that crate rather than depending on this one directly.
+
+use proc_macro::TokenStream;
+use quote::quote;
+use syn::parse::Parser;
+use syn::{parse_macro_input, ItemFn, LitStr};
+
+/// Record the glibc heap delta and duration of every call to the annotated function.
+///
+/// The function body is run inside `malloc_info::measure`, and the result is emitted as a
+/// `tracing` event with the target `malloc_info::profile`, under the function's module path and
+/// name, e.g. `my_crate::loader::load`. Pass `name = "..."` to record it under another name
+/// instead.
+///
+/// ```rust,ignore
+/// #[malloc_info::malloc_profile]
+/// fn load(path: &Path) -> io::Result<Vec<u8>> {
+///     std::fs::read(path)
+/// }
+/// ```
+///
+/// `async` functions aren't supported.
+#[proc_macro_attribute]
+pub fn malloc_profile(args: TokenStream, item: TokenStream) -> TokenStream {
+    let mut name = None;
+    let parser = syn::meta::parser(|meta| {
+        if meta.path.is_ident("name") {
+            name = Some(meta.value()?.parse::<LitStr>()?);
+            Ok(())
+        } else {
+            Err(meta.error("expected `name = \"...\"`"))
+        }
+    });
+    if let Err(error) = parser.parse(args) {
+        return error.into_compile_error().into();
+    }
+
+    let ItemFn {
+        attrs,
+        vis,
+        sig,
+        block,
+    } = parse_macro_input!(item as ItemFn);
+    if let Some(asyncness) = sig.asyncness {
+        return syn::Error::new_spanned(
+            asyncness,
+            "`malloc_profile` doesn't support async functions",
+        )
+        .into_compile_error()
+        .into();
+    }
+
+    let name = match name {
+        Some(name) => quote!(#name),
+        None => {
+            let ident = sig.ident.to_string();
+            quote!(::core::concat!(::core::module_path!(), "::", #ident))
+        }
+    };
+    quote! {
+        #(#attrs)*
+        #vis #sig {
+            ::malloc_info::__private::profile(#name, || #block)
+        }
+    }
+    .into()
+}
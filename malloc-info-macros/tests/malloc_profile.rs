@@ -0,0 +1,128 @@
+use malloc_info::malloc_profile;
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::sync::{Arc, Mutex};
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::{Event, Metadata, Subscriber};
+
+#[malloc_profile]
+fn allocate(len: usize) -> Vec<u8> {
+    vec![1; len]
+}
+
+#[malloc_profile(name = "parse")]
+fn parse(input: &str) -> Result<u64, std::num::ParseIntError> {
+    if input.is_empty() {
+        return Ok(0);
+    }
+    let value = input.parse()?;
+    Ok(value)
+}
+
+struct Counter(u64);
+
+impl Counter {
+    #[malloc_profile]
+    fn bump<T: Into<u64>>(&mut self, by: T) -> u64 {
+        self.0 += by.into();
+        self.0
+    }
+
+    #[malloc_profile]
+    fn into_inner(self) -> u64 {
+        self.0
+    }
+}
+
+#[test]
+fn functions() {
+    assert_eq!(allocate(1 << 20).len(), 1 << 20);
+    assert_eq!(parse(""), Ok(0));
+    assert_eq!(parse("42"), Ok(42));
+    assert!(parse("lots").is_err());
+}
+
+#[test]
+fn methods() {
+    let mut counter = Counter(0);
+    assert_eq!(counter.bump(2u8), 2);
+    assert_eq!(counter.bump(3u32), 5);
+    assert_eq!(counter.into_inner(), 5);
+}
+
+/// An event recorded by [`Capture`]: its target and fields, formatted
+type Captured = (String, HashMap<String, String>);
+
+/// A subscriber recording every event
+#[derive(Default)]
+struct Capture(Arc<Mutex<Vec<Captured>>>);
+
+impl Subscriber for Capture {
+    fn enabled(&self, _: &Metadata<'_>) -> bool {
+        true
+    }
+
+    fn new_span(&self, _: &Attributes<'_>) -> Id {
+        Id::from_u64(1)
+    }
+
+    fn record(&self, _: &Id, _: &Record<'_>) {}
+
+    fn record_follows_from(&self, _: &Id, _: &Id) {}
+
+    fn event(&self, event: &Event<'_>) {
+        let mut fields = Fields::default();
+        event.record(&mut fields);
+        let target = event.metadata().target().to_owned();
+        self.0.lock().unwrap().push((target, fields.0));
+    }
+
+    fn enter(&self, _: &Id) {}
+
+    fn exit(&self, _: &Id) {}
+}
+
+#[derive(Default)]
+struct Fields(HashMap<String, String>);
+
+impl Visit for Fields {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.insert(field.name().to_owned(), value.to_owned());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+        self.0.insert(field.name().to_owned(), format!("{value:?}"));
+    }
+}
+
+/// Run `f` with a [`Capture`] subscriber for the current thread, returning the events recorded
+fn capture(f: impl FnOnce()) -> Vec<Captured> {
+    let capture = Capture::default();
+    let events = Arc::clone(&capture.0);
+    tracing::subscriber::with_default(capture, f);
+    let events = events.lock().unwrap();
+    events.clone()
+}
+
+#[test]
+fn events() {
+    let events = capture(|| {
+        allocate(1 << 20);
+        parse("42").unwrap();
+    });
+    assert_eq!(events.len(), 2, "{events:?}");
+
+    let functions: Vec<_> = events
+        .iter()
+        .map(|(target, fields)| {
+            assert_eq!(target, "malloc_info::profile");
+            for field in ["in_use", "duration_us"] {
+                let value = fields.get(field).expect(field);
+                value.parse::<i64>().expect("number");
+            }
+            fields["function"].as_str()
+        })
+        .collect();
+    assert_eq!(functions, [concat!(module_path!(), "::allocate"), "parse"]);
+}
@@ -0,0 +1,366 @@
+//! A memory budget for application-level load shedding, with headroom queries and alert rules
+//! relative to the budget.
+//!
+//! A [`MemoryBudget`] is a limit in bytes, either fixed with [`MemoryBudget::new`] or taken from
+//! the memory limit of the process's cgroup with [`MemoryBudget::from_cgroup`]. It is compared
+//! against a single figure for the memory used by the process, [`Usage::used`], which combines the
+//! resident set size with the heap in use reported by `malloc_info`:
+//!
+//! ```rust
+//! use malloc_info::budget::MemoryBudget;
+//!
+//! let budget = MemoryBudget::new(4 << 30)
+//!     .with_alert("warning", 0.8)
+//!     .with_alert("critical", 0.95);
+//! if budget.fraction_used()? > 0.9 {
+//!     // Shed load
+//! }
+//! # Ok::<(), malloc_info::Error>(())
+//! ```
+//!
+//! Every query without a [`Usage`] argument takes a fresh one. To answer several queries from the
+//! same numbers, take a [`Usage`] once with [`Usage::current`] and use the `*_of` variants.
+
+use crate::{malloc_info_summary, Error, ErrorRepr, MallocSummary};
+#[cfg(feature = "serialize")]
+use serde::Serialize;
+use std::io;
+use std::path::Path;
+
+/// Limits at or above this are how cgroup v1 reports that there is no limit, as the largest
+/// page-aligned `i64`
+const V1_UNLIMITED: u64 = 1 << 62;
+
+/// Memory used by the process, as compared against a [`MemoryBudget`]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serialize", derive(Serialize))]
+pub struct Usage {
+    /// Resident set size of the process in bytes
+    pub rss: u64,
+
+    /// Summary of the glibc heap taken alongside the resident set size
+    pub summary: MallocSummary,
+}
+
+impl Usage {
+    /// Read the resident set size from `/proc/self/statm` and take a [`MallocSummary`]
+    pub fn current() -> Result<Self, Error> {
+        let statm = std::fs::read_to_string("/proc/self/statm").map_err(ErrorRepr::Io)?;
+        let pages = statm
+            .split_whitespace()
+            .nth(1)
+            .and_then(|pages| pages.parse::<u64>().ok())
+            .ok_or_else(|| {
+                ErrorRepr::Io(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "malformed /proc/self/statm",
+                ))
+            })?;
+        // SAFETY: sysconf has no preconditions
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+        Ok(Self {
+            rss: pages.saturating_mul(page_size.max(0) as u64),
+            summary: malloc_info_summary()?,
+        })
+    }
+
+    /// Bytes in use by the heap: memory obtained from the system minus what is held in free
+    /// chunks, plus chunks allocated directly with `mmap`
+    pub fn heap_in_use(&self) -> u64 {
+        self.summary
+            .system_current
+            .saturating_sub(self.summary.fast)
+            .saturating_sub(self.summary.rest)
+            .saturating_add(self.summary.mmap)
+    }
+
+    /// The figure compared against the budget: the larger of the resident set size and the heap in
+    /// use. The resident set size is what the kernel charges to the cgroup and normally dominates,
+    /// but it lags behind the heap while freshly allocated pages haven't been touched yet.
+    pub fn used(&self) -> u64 {
+        self.rss.max(self.heap_in_use())
+    }
+}
+
+/// An alert which fires once usage reaches a fraction of the budget
+#[derive(Debug, Clone, PartialEq)]
+pub struct AlertRule {
+    /// Name of the alert, such as `warning`
+    pub name: String,
+
+    /// Fraction of the budget at which the alert fires
+    pub fraction: f64,
+}
+
+/// A limit on the memory used by the process, see the [module documentation](self)
+#[derive(Debug, Clone, PartialEq)]
+pub struct MemoryBudget {
+    limit: u64,
+    rules: Vec<AlertRule>,
+}
+
+impl MemoryBudget {
+    /// A budget of `limit` bytes
+    pub fn new(limit: u64) -> Self {
+        Self {
+            limit,
+            rules: Vec::new(),
+        }
+    }
+
+    /// A budget of the memory limit of the cgroup the process is in: `memory.max` under cgroup v2,
+    /// or `memory.limit_in_bytes` under cgroup v1. Limits set on the ancestors of the cgroup apply
+    /// too, so the lowest limit between the cgroup and the root is taken. Returns `None` if none of
+    /// them has a limit or the process isn't in a memory cgroup.
+    pub fn from_cgroup() -> io::Result<Option<Self>> {
+        let cgroups = std::fs::read_to_string("/proc/self/cgroup")?;
+        Ok(cgroup_limit(Path::new("/sys/fs/cgroup"), &cgroups)?.map(Self::new))
+    }
+
+    /// Add an alert named `name` which fires once usage reaches `fraction` of the budget
+    pub fn with_alert(mut self, name: impl Into<String>, fraction: f64) -> Self {
+        self.rules.push(AlertRule {
+            name: name.into(),
+            fraction,
+        });
+        self
+    }
+
+    /// The limit in bytes
+    pub fn limit(&self) -> u64 {
+        self.limit
+    }
+
+    /// The alert rules, in the order they were added
+    pub fn rules(&self) -> &[AlertRule] {
+        &self.rules
+    }
+
+    /// Bytes left before the budget is used up, or zero once it is
+    pub fn headroom(&self) -> Result<u64, Error> {
+        Ok(self.headroom_of(&Usage::current()?))
+    }
+
+    /// Like [`headroom`](Self::headroom), for `usage`
+    pub fn headroom_of(&self, usage: &Usage) -> u64 {
+        self.limit.saturating_sub(usage.used())
+    }
+
+    /// Fraction of the budget in use, which exceeds 1.0 once usage is over the budget
+    pub fn fraction_used(&self) -> Result<f64, Error> {
+        Ok(self.fraction_used_of(&Usage::current()?))
+    }
+
+    /// Like [`fraction_used`](Self::fraction_used), for `usage`. A zero budget is fully used by
+    /// any usage at all.
+    pub fn fraction_used_of(&self, usage: &Usage) -> f64 {
+        match (self.limit, usage.used()) {
+            (_, 0) => 0.0,
+            (0, _) => f64::INFINITY,
+            (limit, used) => used as f64 / limit as f64,
+        }
+    }
+
+    /// The alerts which fire for the current usage
+    pub fn alerts(&self) -> Result<Vec<&AlertRule>, Error> {
+        Ok(self.alerts_of(&Usage::current()?))
+    }
+
+    /// Like [`alerts`](Self::alerts), for `usage`
+    pub fn alerts_of(&self, usage: &Usage) -> Vec<&AlertRule> {
+        let fraction = self.fraction_used_of(usage);
+        self.rules
+            .iter()
+            .filter(|rule| fraction >= rule.fraction)
+            .collect()
+    }
+}
+
+/// Memory limit of the cgroups listed in `cgroups`, the contents of `/proc/<pid>/cgroup`, with the
+/// cgroup filesystem mounted at `root`. The cgroup v1 memory controller is preferred on hybrid
+/// hierarchies, where it is the one enforcing the limit.
+fn cgroup_limit(root: &Path, cgroups: &str) -> io::Result<Option<u64>> {
+    // Path of the cgroup in the v2 hierarchy, if the process is in one
+    let mut v2 = None;
+    for line in cgroups.lines() {
+        let mut fields = line.splitn(3, ':');
+        let (controllers, path) = match (fields.next(), fields.next(), fields.next()) {
+            (Some(_), Some(controllers), Some(path)) => (controllers, path),
+            _ => continue,
+        };
+        let path = Path::new(path.trim_start_matches('/'));
+        if controllers
+            .split(',')
+            .any(|controller| controller == "memory")
+        {
+            return lowest_limit(&root.join("memory"), path, "memory.limit_in_bytes");
+        }
+        if controllers.is_empty() {
+            v2 = Some(path);
+        }
+    }
+    match v2 {
+        Some(path) => lowest_limit(root, path, "memory.max"),
+        None => Ok(None),
+    }
+}
+
+/// Lowest limit in `file` of the cgroup at `path` under the hierarchy mounted at `root` and of
+/// each of its ancestors. cgroup v1's way of saying there is no limit counts as none.
+fn lowest_limit(root: &Path, path: &Path, file: &str) -> io::Result<Option<u64>> {
+    let mut lowest = None;
+    for cgroup in path.ancestors() {
+        let limit =
+            read_limit(&root.join(cgroup).join(file))?.filter(|limit| *limit < V1_UNLIMITED);
+        lowest = lowest.into_iter().chain(limit).min();
+    }
+    Ok(lowest)
+}
+
+/// Read a limit from `file`, or `None` if there is no such file or it says `max`
+fn read_limit(file: &Path) -> io::Result<Option<u64>> {
+    let value = match std::fs::read_to_string(file) {
+        Ok(value) => value,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    match value.trim() {
+        "max" => Ok(None),
+        value => value.parse().map(Some).map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("invalid memory limit `{value}` in {}", file.display()),
+            )
+        }),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn usage(rss: u64, system_current: u64, rest: u64) -> Usage {
+        Usage {
+            rss,
+            summary: MallocSummary {
+                system_current,
+                rest,
+                ..Default::default()
+            },
+        }
+    }
+
+    #[test]
+    fn headroom() {
+        let budget = MemoryBudget::new(1000);
+        assert_eq!(budget.headroom_of(&usage(400, 300, 0)), 600);
+        // The heap counts when it is ahead of the resident set size
+        assert_eq!(budget.headroom_of(&usage(400, 700, 100)), 400);
+        assert_eq!(budget.headroom_of(&usage(1500, 0, 0)), 0);
+        assert_eq!(budget.fraction_used_of(&usage(250, 0, 0)), 0.25);
+        assert_eq!(budget.fraction_used_of(&usage(1500, 0, 0)), 1.5);
+
+        let zero = MemoryBudget::new(0);
+        assert_eq!(zero.fraction_used_of(&usage(0, 0, 0)), 0.0);
+        assert_eq!(zero.fraction_used_of(&usage(1, 0, 0)), f64::INFINITY);
+    }
+
+    #[test]
+    fn alerts() {
+        let budget = MemoryBudget::new(1000)
+            .with_alert("warning", 0.8)
+            .with_alert("critical", 0.95);
+        let names = |rss| -> Vec<_> {
+            budget
+                .alerts_of(&usage(rss, 0, 0))
+                .into_iter()
+                .map(|rule| rule.name.as_str())
+                .collect()
+        };
+        assert!(names(500).is_empty());
+        assert_eq!(names(800), ["warning"]);
+        assert_eq!(names(2000), ["warning", "critical"]);
+        assert_eq!(budget.rules().len(), 2);
+    }
+
+    #[test]
+    fn current() {
+        let usage = Usage::current().expect("usage");
+        assert!(usage.rss > 0);
+        assert!(usage.used() >= usage.rss);
+        assert!(MemoryBudget::new(u64::MAX).headroom().expect("headroom") > 0);
+    }
+
+    /// Write `files` under a fresh directory standing in for `/sys/fs/cgroup`
+    fn cgroup_root(name: &str, files: &[(&str, &str)]) -> PathBuf {
+        let root =
+            std::env::temp_dir().join(format!("malloc-info-cgroup-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        for (path, contents) in files {
+            let path = root.join(path);
+            std::fs::create_dir_all(path.parent().expect("parent")).expect("create dir");
+            std::fs::write(path, contents).expect("write");
+        }
+        root
+    }
+
+    #[test]
+    fn cgroup_v2() {
+        let root = cgroup_root(
+            "v2",
+            &[
+                ("system.slice/app.service/memory.max", "536870912\n"),
+                ("user.slice/memory.max", "max\n"),
+            ],
+        );
+        let limit = |cgroups| cgroup_limit(&root, cgroups).expect("limit");
+        assert_eq!(limit("0::/system.slice/app.service\n"), Some(512 << 20));
+        assert_eq!(limit("0::/user.slice\n"), None);
+        assert_eq!(limit("0::/missing\n"), None);
+        assert_eq!(limit(""), None);
+        std::fs::remove_dir_all(root).expect("clean up");
+    }
+
+    #[test]
+    fn cgroup_ancestors() {
+        let root = cgroup_root(
+            "ancestors",
+            &[
+                ("system.slice/memory.max", "268435456\n"),
+                ("system.slice/app.service/memory.max", "536870912\n"),
+                ("system.slice/other.service/memory.max", "max\n"),
+                ("memory/docker/memory.limit_in_bytes", "1073741824\n"),
+                (
+                    "memory/docker/abc/memory.limit_in_bytes",
+                    "9223372036854771712\n",
+                ),
+            ],
+        );
+        let limit = |cgroups| cgroup_limit(&root, cgroups).expect("limit");
+        assert_eq!(limit("0::/system.slice/app.service\n"), Some(256 << 20));
+        assert_eq!(limit("0::/system.slice/other.service\n"), Some(256 << 20));
+        assert_eq!(limit("0::/system.slice/missing.service\n"), Some(256 << 20));
+        assert_eq!(limit("4:memory:/docker/abc\n"), Some(1 << 30));
+        std::fs::remove_dir_all(root).expect("clean up");
+    }
+
+    #[test]
+    fn cgroup_v1() {
+        let root = cgroup_root(
+            "v1",
+            &[
+                ("memory/docker/abc/memory.limit_in_bytes", "1073741824\n"),
+                ("memory/memory.limit_in_bytes", "9223372036854771712\n"),
+                ("docker/abc/memory.max", "4096\n"),
+                ("bad/memory.max", "lots\n"),
+            ],
+        );
+        let limit = |cgroups| cgroup_limit(&root, cgroups);
+        let hybrid = "5:cpu,cpuacct:/docker/abc\n4:memory:/docker/abc\n0::/docker/abc\n";
+        assert_eq!(limit(hybrid).expect("limit"), Some(1 << 30));
+        assert_eq!(limit("4:memory:/\n").expect("limit"), None);
+        assert!(limit("0::/bad\n").is_err());
+        std::fs::remove_dir_all(root).expect("clean up");
+    }
+}
@@ -0,0 +1,122 @@
+//! Information about the runtime environment snapshots are taken in, for segmenting them by glibc
+//! version and allocator.
+
+#[cfg(feature = "serialize")]
+use serde::Serialize;
+use std::ffi::CStr;
+use std::sync::{Mutex, PoisonError};
+
+/// The result of [`build_info`], which never changes, so that it is only detected once. A `Mutex`
+/// rather than a `OnceLock`, which is newer than the minimum supported Rust version.
+static BUILD_INFO: Mutex<Option<BuildInfo>> = Mutex::new(None);
+
+/// Which allocator serves `malloc` in this process
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serialize",
+    derive(Serialize),
+    serde(rename_all = "kebab-case")
+)]
+pub enum Allocator {
+    /// glibc's own malloc, the one `malloc_info` reports on
+    Glibc,
+
+    /// `malloc` is interposed by another allocator, for example with `LD_PRELOAD`. `malloc_info`
+    /// then only reports the glibc heap, which the program mostly doesn't use.
+    Interposed,
+
+    /// The allocator couldn't be determined, for example because the program is statically linked
+    Unknown,
+}
+
+impl Allocator {
+    /// Name of the allocator kind, as used in metrics
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Allocator::Glibc => "glibc",
+            Allocator::Interposed => "interposed",
+            Allocator::Unknown => "unknown",
+        }
+    }
+}
+
+/// The runtime environment of this process
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serialize", derive(Serialize))]
+pub struct BuildInfo {
+    /// Version of glibc the process is running against, as reported by `gnu_get_libc_version`
+    pub glibc_version: String,
+
+    /// Version of this crate
+    pub crate_version: &'static str,
+
+    /// Which allocator serves `malloc`
+    pub allocator: Allocator,
+}
+
+/// Detect the runtime environment of this process. It is only detected on the first call, and
+/// later calls return the same information.
+///
+/// The allocator is detected by comparing the `malloc` symbol the process resolves with the one
+/// defined by glibc. A Rust `#[global_allocator]` doesn't replace `malloc`, so it isn't detected;
+/// see the caveats in the [crate documentation](crate).
+pub fn build_info() -> BuildInfo {
+    BUILD_INFO
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .get_or_insert_with(detect)
+        .clone()
+}
+
+fn detect() -> BuildInfo {
+    // SAFETY: `gnu_get_libc_version` returns a pointer to a static, NUL-terminated string
+    let glibc_version = unsafe { CStr::from_ptr(libc::gnu_get_libc_version()) };
+    BuildInfo {
+        glibc_version: glibc_version.to_string_lossy().into_owned(),
+        crate_version: env!("CARGO_PKG_VERSION"),
+        allocator: allocator(),
+    }
+}
+
+fn allocator() -> Allocator {
+    const LIBC: &[u8] = b"libc.so.6\0";
+    const MALLOC: &[u8] = b"malloc\0";
+
+    // SAFETY: both names are NUL-terminated. RTLD_NOLOAD only looks up the already loaded glibc,
+    // and the reference it takes is released before returning.
+    unsafe {
+        let glibc = libc::dlopen(LIBC.as_ptr().cast(), libc::RTLD_LAZY | libc::RTLD_NOLOAD);
+        if glibc.is_null() {
+            return Allocator::Unknown;
+        }
+        let glibc_malloc = libc::dlsym(glibc, MALLOC.as_ptr().cast());
+        let malloc = libc::dlsym(libc::RTLD_DEFAULT, MALLOC.as_ptr().cast());
+        libc::dlclose(glibc);
+
+        if glibc_malloc.is_null() || malloc.is_null() {
+            Allocator::Unknown
+        } else if glibc_malloc == malloc {
+            Allocator::Glibc
+        } else {
+            Allocator::Interposed
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn detect() {
+        let info = build_info();
+        assert!(
+            info.glibc_version.starts_with("2."),
+            "{}",
+            info.glibc_version
+        );
+        assert_eq!(info.crate_version, env!("CARGO_PKG_VERSION"));
+        assert_eq!(info.allocator, Allocator::Glibc);
+        assert_eq!(build_info(), info);
+    }
+}
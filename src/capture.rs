@@ -0,0 +1,243 @@
+//! Capturing off the calling thread.
+//!
+//! `malloc_info` takes every arena lock in turn, so a capture can stall behind a thread holding one
+//! of them for a long allocation. Latency-sensitive callers such as game loops and real-time
+//! services can hand the capture to a dedicated worker thread instead, and pick the result up
+//! once it is ready. No async runtime is involved.
+//!
+//! The worker is started on first use and lives for the rest of the process. Captures are queued
+//! and run one at a time.
+//!
+//! Tokio users can instead await [`malloc_info_blocking`] with the `tokio` feature, which runs the
+//! capture on tokio's blocking thread pool.
+
+use crate::{info, Error, ErrorRepr};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{
+    self, Receiver, RecvTimeoutError, SendError, Sender, SyncSender, TryRecvError,
+};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::thread;
+use std::time::Duration;
+
+/// Outcome of [`try_capture`]
+#[derive(Debug)]
+pub enum TryCapture {
+    /// The capture finished within the budget
+    Ready(Result<info::Malloc, Error>),
+
+    /// The capture is still running. Its result can be collected from the handle.
+    Pending(CaptureHandle),
+}
+
+/// Handle to a capture running on the worker thread. Dropping the handle cancels the capture.
+#[derive(Debug)]
+pub struct CaptureHandle {
+    rx: Receiver<Result<info::Malloc, Error>>,
+    cancelled: Arc<AtomicBool>,
+    done: bool,
+}
+
+impl CaptureHandle {
+    /// Check whether the capture has finished, without blocking. Returns `None` while the capture
+    /// is in progress, and after its result has already been taken.
+    pub fn poll(&mut self) -> Option<Result<info::Malloc, Error>> {
+        if self.done {
+            return None;
+        }
+        let result = match self.rx.try_recv() {
+            Ok(result) => result,
+            Err(TryRecvError::Empty) => return None,
+            Err(TryRecvError::Disconnected) => Err(ErrorRepr::WorkerExited.into()),
+        };
+        self.done = true;
+        Some(result)
+    }
+
+    /// Block for at most `timeout` waiting for the capture to finish. Returns `None` if it is
+    /// still in progress after that, or if its result has already been taken.
+    pub fn wait(&mut self, timeout: Duration) -> Option<Result<info::Malloc, Error>> {
+        if self.done {
+            return None;
+        }
+        let result = match self.rx.recv_timeout(timeout) {
+            Ok(result) => result,
+            Err(RecvTimeoutError::Timeout) => return None,
+            Err(RecvTimeoutError::Disconnected) => Err(ErrorRepr::WorkerExited.into()),
+        };
+        self.done = true;
+        Some(result)
+    }
+
+    /// Cancel the capture. If the worker hasn't started it yet it is skipped entirely, otherwise
+    /// its result is discarded. A capture already inside glibc can't be interrupted.
+    pub fn cancel(self) {
+        // Cancellation happens on drop
+    }
+}
+
+impl Drop for CaptureHandle {
+    fn drop(&mut self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+}
+
+/// A capture queued for the worker thread
+struct Job {
+    tx: SyncSender<Result<info::Malloc, Error>>,
+    cancelled: Arc<AtomicBool>,
+}
+
+/// Queue of the worker thread, if it has been started
+static WORKER: Mutex<Option<Sender<Job>>> = Mutex::new(None);
+
+/// Queue a capture on the worker thread and return a handle to its result
+pub fn capture_async() -> CaptureHandle {
+    let (tx, rx) = mpsc::sync_channel(1);
+    let cancelled = Arc::new(AtomicBool::new(false));
+    let job = Job {
+        tx,
+        cancelled: Arc::clone(&cancelled),
+    };
+
+    if let Err((job, e)) = submit(job) {
+        // The channel has room for exactly this one result
+        let _ = job.tx.send(Err(ErrorRepr::Spawn(e).into()));
+    }
+
+    CaptureHandle {
+        rx,
+        cancelled,
+        done: false,
+    }
+}
+
+/// Start a capture on the worker thread and wait at most `budget` for it to finish.
+///
+/// If the capture completes in time its result is returned as [`TryCapture::Ready`]. Otherwise
+/// [`TryCapture::Pending`] is returned and the capture carries on in the background, its result
+/// available from the [`CaptureHandle`]. Whatever state the arena locks are in, the calling thread
+/// waits at most `budget` for the capture itself, and a zero budget doesn't wait for it at all.
+/// Handing the capture to the worker thread still allocates, and spawns the worker if it isn't
+/// running, before the budget starts.
+pub fn try_capture(budget: Duration) -> TryCapture {
+    let mut handle = capture_async();
+    match handle.wait(budget) {
+        Some(result) => TryCapture::Ready(result),
+        None => TryCapture::Pending(handle),
+    }
+}
+
+/// Hand `job` to the worker thread, starting it if it isn't running
+fn submit(job: Job) -> Result<(), (Job, std::io::Error)> {
+    let mut worker = WORKER.lock().unwrap_or_else(PoisonError::into_inner);
+
+    let job = match worker.as_ref() {
+        Some(queue) => match queue.send(job) {
+            Ok(()) => return Ok(()),
+            // The worker has exited, so start a new one
+            Err(SendError(job)) => job,
+        },
+        None => job,
+    };
+
+    let (queue, jobs) = mpsc::channel();
+    if let Err(e) = thread::Builder::new()
+        .name("malloc-info".into())
+        .spawn(move || work(jobs))
+    {
+        return Err((job, e));
+    }
+
+    // If the worker has somehow already exited, the handle reports it when polled
+    let _ = queue.send(job);
+    *worker = Some(queue);
+    Ok(())
+}
+
+/// Capture [`malloc_info`](crate::malloc_info) on tokio's blocking thread pool with
+/// [`spawn_blocking`](tokio::task::spawn_blocking), keeping a capture stalled on an arena lock off
+/// the async worker threads.
+///
+/// # Panics
+/// Panics if called outside of a tokio runtime.
+#[cfg(feature = "tokio")]
+pub async fn malloc_info_blocking() -> Result<info::Malloc, Error> {
+    match tokio::task::spawn_blocking(crate::malloc_info).await {
+        Ok(result) => result,
+        Err(e) => Err(ErrorRepr::Join(e).into()),
+    }
+}
+
+/// Body of the worker thread
+fn work(jobs: Receiver<Job>) {
+    for job in jobs {
+        if job.cancelled.load(Ordering::Relaxed) {
+            continue;
+        }
+        // The handle may have been dropped in the meantime, which is fine
+        let _ = job.tx.send(crate::malloc_info());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Instant;
+
+    #[test]
+    fn ready() {
+        match try_capture(Duration::from_secs(10)) {
+            TryCapture::Ready(result) => {
+                result.expect("malloc_info");
+            }
+            TryCapture::Pending(_) => panic!("capture did not finish"),
+        }
+    }
+
+    #[test]
+    fn pending() {
+        let start = Instant::now();
+        let result = match try_capture(Duration::ZERO) {
+            TryCapture::Ready(result) => result,
+            TryCapture::Pending(mut handle) => loop {
+                if let Some(result) = handle.poll() {
+                    assert!(handle.poll().is_none());
+                    break result;
+                }
+                assert!(start.elapsed() < Duration::from_secs(10));
+                thread::sleep(Duration::from_millis(1));
+            },
+        };
+        result.expect("malloc_info");
+    }
+
+    #[test]
+    fn wait() {
+        let mut handle = capture_async();
+        handle
+            .wait(Duration::from_secs(10))
+            .expect("capture did not finish")
+            .expect("malloc_info");
+        assert!(handle.wait(Duration::ZERO).is_none());
+    }
+
+    #[cfg(feature = "tokio")]
+    #[tokio::test]
+    async fn blocking() {
+        let info = malloc_info_blocking().await.expect("malloc_info");
+        assert!(!info.heaps.is_empty());
+    }
+
+    #[test]
+    fn cancel() {
+        let handles = (0..8).map(|_| capture_async()).collect::<Vec<_>>();
+        handles.into_iter().for_each(CaptureHandle::cancel);
+
+        // The worker is still usable after skipping cancelled captures
+        capture_async()
+            .wait(Duration::from_secs(10))
+            .expect("capture did not finish")
+            .expect("malloc_info");
+    }
+}
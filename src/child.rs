@@ -0,0 +1,110 @@
+//! Measuring a workload in a forked child process, isolating its heap usage from everything the
+//! parent has already allocated.
+
+use crate::trace::Phase;
+use crate::{capture_fresh, info, Error, ErrorRepr};
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::os::unix::io::FromRawFd;
+use std::panic::{self, AssertUnwindSafe};
+
+/// Fork, run `f` in the child process, and return the output of `malloc_info` as captured in the
+/// child once `f` has returned.
+///
+/// The child starts with a copy of the parent's heap, so the snapshot still includes everything the
+/// parent had allocated at the time of the fork, but none of the allocations the parent makes
+/// afterwards. The raw XML is sent back to the parent over a pipe and parsed there, and the child
+/// exits without running destructors or `atexit` handlers.
+///
+/// An error is returned if `f` panics or the child otherwise fails to deliver a snapshot.
+///
+/// # Caveats
+/// Only the calling thread exists in the child. If the parent is multi-threaded, `f` must not
+/// depend on locks which another thread could have been holding at the time of the fork. glibc
+/// makes `malloc` itself safe to use in the child.
+pub fn measure_in_child(f: impl FnOnce()) -> Result<info::Malloc, Error> {
+    fn measure_in_child(f: impl FnOnce()) -> Result<info::Malloc, ErrorRepr> {
+        let mut fds = [0; 2];
+
+        // SAFETY: `fds` has room for the two file descriptors `pipe2` writes
+        if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } != 0 {
+            return Err(ErrorRepr::last_os_error());
+        }
+
+        // SAFETY: `pipe2` succeeded, so both file descriptors are valid, and nothing else owns them
+        let (mut read, write) = unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) };
+
+        // SAFETY: `fork` is always safe to call. See the caveats on this function for what the
+        // closure may do in the child.
+        let pid = unsafe { libc::fork() };
+        if pid < 0 {
+            return Err(ErrorRepr::last_os_error());
+        }
+        if pid == 0 {
+            drop(read);
+            child(f, write);
+        }
+        drop(write);
+
+        let mut xml = Vec::new();
+        let read_result = read.read_to_end(&mut xml);
+
+        let mut status = 0;
+        // SAFETY: `pid` is our own child process and `status` is valid for writes
+        while unsafe { libc::waitpid(pid, &mut status, 0) } < 0 {
+            let error = io::Error::last_os_error();
+            if error.kind() != io::ErrorKind::Interrupted {
+                return Err(ErrorRepr::LibC(error));
+            }
+        }
+        if !libc::WIFEXITED(status) || libc::WEXITSTATUS(status) != 0 {
+            return Err(ErrorRepr::Child(status));
+        }
+        read_result.map_err(ErrorRepr::Io)?;
+
+        let phase = Phase::parse(xml.len());
+        let info: info::Malloc = quick_xml::de::from_reader(xml.as_slice())?;
+        phase.record_arenas(info.heaps.len());
+        Ok(info)
+    }
+    measure_in_child(f).map_err(Error::from)
+}
+
+/// Body of the child process: run `f`, then write the raw output of `malloc_info` to `pipe`
+fn child(f: impl FnOnce(), mut pipe: File) -> ! {
+    let status = if panic::catch_unwind(AssertUnwindSafe(f)).is_err() {
+        101
+    } else {
+        match capture_fresh() {
+            Ok(mem_stream) => match pipe.write_all(mem_stream.as_ref()) {
+                Ok(()) => 0,
+                Err(_) => 2,
+            },
+            Err(_) => 1,
+        }
+    };
+
+    // SAFETY: `_exit` is always safe to call, and skips the parent's destructors and atexit
+    // handlers, which must not run twice
+    unsafe { libc::_exit(status) }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::info::TotalType;
+
+    #[test]
+    fn large_allocation() {
+        const SIZE: usize = 64 << 20;
+
+        let info = measure_in_child(|| std::mem::forget(vec![1u8; SIZE])).expect("measure");
+        let mmap = info.total_size(TotalType::Mmap).expect("mmap total");
+        assert!(mmap >= SIZE as u64, "{mmap} < {SIZE}");
+    }
+
+    #[test]
+    fn panic() {
+        assert!(measure_in_child(|| panic!("oops")).is_err());
+    }
+}
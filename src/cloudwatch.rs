@@ -0,0 +1,231 @@
+//! Serializing snapshots into the CloudWatch
+//! [Embedded Metric Format](https://docs.aws.amazon.com/AmazonCloudWatch/latest/monitoring/CloudWatch_Embedded_Metric_Format_Specification.html).
+//!
+//! Lambda functions, and ECS tasks using the `awslogs` or FireLens log drivers, turn EMF documents
+//! printed to standard output into CloudWatch metrics, so [`print()`] is all it takes to publish a
+//! snapshot. Each snapshot becomes one document for the process as a whole, and one per arena with
+//! an additional `Arena` dimension. Every document holds the metrics below, with their types
+//! spelled out as in the XML:
+//!
+//! | Metric | Unit | |
+//! |---|---|---|
+//! | `Arenas` | `Count` | Number of arenas, in the process document only |
+//! | `FastBytes`, `RestBytes`, `MmapBytes` | `Bytes` | Bytes in free or mmapped chunks |
+//! | `FastChunks`, `RestChunks`, `MmapChunks` | `Count` | Number of free or mmapped chunks |
+//! | `SystemCurrentBytes`, `SystemMaxBytes` | `Bytes` | Bytes obtained from the system |
+//! | `AspaceTotalBytes`, `AspaceMprotectBytes`, `AspaceSubheapsBytes` | `Bytes` | Address space used |
+//!
+//! ```rust,no_run
+//! let info = malloc_info::malloc_info().expect("malloc_info");
+//! malloc_info::cloudwatch::print(&info, "MyService", &[("Function", "resize-images")])
+//!     .expect("print metrics");
+//! ```
+
+use crate::info;
+use serde_json::{json, Map, Value};
+use std::io::{self, Write};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Name of the dimension holding the arena number
+pub const ARENA_DIMENSION: &str = "Arena";
+
+/// Print the documents for `info` to standard output, one per line, timestamped now.
+pub fn print(info: &info::Malloc, namespace: &str, dimensions: &[(&str, &str)]) -> io::Result<()> {
+    let mut stdout = io::stdout().lock();
+    for line in render(info, namespace, dimensions) {
+        writeln!(stdout, "{line}")?;
+    }
+    stdout.flush()
+}
+
+/// Render `info` as EMF documents timestamped now. See [`render_at`].
+pub fn render(info: &info::Malloc, namespace: &str, dimensions: &[(&str, &str)]) -> Vec<String> {
+    render_at(info, namespace, dimensions, SystemTime::now())
+}
+
+/// Render `info` as EMF documents in `namespace`, each serialized on a single line. The metrics
+/// of every document are reported under the `dimensions`, a list of `(name, value)` pairs, which
+/// for per-arena documents are followed by [`ARENA_DIMENSION`].
+pub fn render_at(
+    info: &info::Malloc,
+    namespace: &str,
+    dimensions: &[(&str, &str)],
+    timestamp: SystemTime,
+) -> Vec<String> {
+    let timestamp = timestamp
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_millis() as u64);
+
+    let mut process = Document::new(dimensions);
+    process.metric("Arenas", "Count", info.heaps.len() as u64);
+    process.entries(&info.total, &info.system, &info.aspace);
+    let mut documents = vec![process];
+
+    for heap in &info.heaps {
+        let mut arena = Document::new(dimensions);
+        arena.dimension(ARENA_DIMENSION, &heap.nr.to_string());
+        arena.entries(&heap.total, &heap.system, &heap.aspace);
+        documents.push(arena);
+    }
+
+    documents
+        .into_iter()
+        .map(|document| document.finish(namespace, timestamp).to_string())
+        .collect()
+}
+
+/// An EMF document under construction
+struct Document {
+    dimensions: Vec<Value>,
+    metrics: Vec<Value>,
+    members: Map<String, Value>,
+}
+
+impl Document {
+    fn new(dimensions: &[(&str, &str)]) -> Self {
+        let mut document = Self {
+            dimensions: Vec::new(),
+            metrics: Vec::new(),
+            members: Map::new(),
+        };
+        for (name, value) in dimensions {
+            document.dimension(name, value);
+        }
+        document
+    }
+
+    fn dimension(&mut self, name: &str, value: &str) {
+        self.dimensions.push(name.into());
+        self.members.insert(name.into(), value.into());
+    }
+
+    /// Add a metric, summing values for names which are already present, as unrecognized types
+    /// all map to the same name
+    fn metric(&mut self, name: &str, unit: &str, value: u64) {
+        match self.members.get_mut(name) {
+            Some(existing) => *existing = (existing.as_u64().unwrap_or(0) + value).into(),
+            None => {
+                self.metrics.push(json!({ "Name": name, "Unit": unit }));
+                self.members.insert(name.into(), value.into());
+            }
+        }
+    }
+
+    fn entries(&mut self, total: &[info::Total], system: &[info::System], aspace: &[info::Aspace]) {
+        for total in total {
+            let r#type = pascal_case(total.r#type.as_str());
+            self.metric(&format!("{type}Bytes"), "Bytes", total.size);
+            self.metric(&format!("{type}Chunks"), "Count", total.count);
+        }
+        for system in system {
+            let r#type = pascal_case(system.r#type.as_str());
+            self.metric(&format!("System{type}Bytes"), "Bytes", system.size);
+        }
+        for aspace in aspace {
+            let r#type = pascal_case(aspace.r#type.as_str());
+            self.metric(&format!("Aspace{type}Bytes"), "Bytes", aspace.size);
+        }
+    }
+
+    fn finish(self, namespace: &str, timestamp: u64) -> Value {
+        let mut members = self.members;
+        members.insert(
+            "_aws".into(),
+            json!({
+                "Timestamp": timestamp,
+                "CloudWatchMetrics": [{
+                    "Namespace": namespace,
+                    "Dimensions": [self.dimensions],
+                    "Metrics": self.metrics,
+                }],
+            }),
+        );
+        Value::Object(members)
+    }
+}
+
+/// Upper-case the first letter of `name`
+fn pascal_case(name: &str) -> String {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .map(|first| first.to_ascii_uppercase().to_string() + chars.as_str())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Duration;
+
+    // Taken from the malloc_info(3) man-page
+    const XML: &str = r#"
+<malloc version="1">
+<heap nr="0">
+<sizes>
+</sizes>
+<total type="fast" count="0" size="0"/>
+<total type="rest" count="0" size="0"/>
+<system type="current" size="135168"/>
+<system type="max" size="135168"/>
+<aspace type="total" size="135168"/>
+<aspace type="mprotect" size="135168"/>
+</heap>
+<total type="fast" count="0" size="0"/>
+<total type="rest" count="0" size="0"/>
+<system type="current" size="135168"/>
+<system type="max" size="135168"/>
+<aspace type="total" size="135168"/>
+<aspace type="mprotect" size="135168"/>
+</malloc>
+"#;
+
+    #[test]
+    fn render_documents() {
+        let info: info::Malloc = quick_xml::de::from_str(XML).expect("parse XML");
+        let timestamp = UNIX_EPOCH + Duration::from_millis(1_700_000_000_123);
+        let lines = render_at(&info, "App", &[("Service", "api")], timestamp);
+        assert_eq!(lines.len(), 2);
+        assert!(lines.iter().all(|line| !line.contains('\n')));
+
+        let process: Value = serde_json::from_str(&lines[0]).expect("JSON");
+        assert_eq!(process["Service"], "api");
+        assert_eq!(process["Arenas"], 1);
+        assert_eq!(process["SystemCurrentBytes"], 135168);
+        assert_eq!(process["RestChunks"], 0);
+        let aws = &process["_aws"];
+        assert_eq!(aws["Timestamp"], 1_700_000_000_123u64);
+        let directive = &aws["CloudWatchMetrics"][0];
+        assert_eq!(directive["Namespace"], "App");
+        assert_eq!(directive["Dimensions"], json!([["Service"]]));
+        let metrics = directive["Metrics"].as_array().expect("metrics");
+        assert_eq!(metrics.len(), 1 + 4 + 2 + 2);
+        assert!(metrics.contains(&json!({ "Name": "AspaceMprotectBytes", "Unit": "Bytes" })));
+        assert!(metrics.contains(&json!({ "Name": "FastChunks", "Unit": "Count" })));
+
+        let arena: Value = serde_json::from_str(&lines[1]).expect("JSON");
+        assert_eq!(arena["Arena"], "0");
+        assert_eq!(arena["AspaceTotalBytes"], 135168);
+        assert!(arena.get("Arenas").is_none());
+        let directive = &arena["_aws"]["CloudWatchMetrics"][0];
+        assert_eq!(directive["Dimensions"], json!([["Service", "Arena"]]));
+        assert_eq!(directive["Metrics"].as_array().expect("metrics").len(), 8);
+    }
+
+    #[test]
+    fn render_live() {
+        let info = crate::malloc_info().expect("malloc_info");
+        let lines = render(&info, "Test", &[]);
+        assert_eq!(lines.len(), 1 + info.heaps.len());
+        for line in lines {
+            let document: Value = serde_json::from_str(&line).expect("JSON");
+            assert!(document["_aws"]["Timestamp"].as_u64().expect("timestamp") > 0);
+        }
+    }
+
+    #[test]
+    fn pascal() {
+        assert_eq!(pascal_case("mprotect"), "Mprotect");
+        assert_eq!(pascal_case(""), "");
+    }
+}
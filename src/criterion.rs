@@ -0,0 +1,161 @@
+//! A [Criterion](https://docs.rs/criterion) measurement reporting glibc heap growth instead of
+//! wall time, for tracking allocation regressions in benchmarks.
+//!
+//! ```rust,no_run
+//! use criterion::Criterion;
+//! use malloc_info::criterion::HeapGrowth;
+//!
+//! fn bench(c: &mut Criterion<HeapGrowth>) {
+//!     c.bench_function("collect", |b| {
+//!         b.iter_with_large_drop(|| (0..1024u32).collect::<Vec<_>>())
+//!     });
+//! }
+//!
+//! criterion::criterion_group! {
+//!     name = benches;
+//!     config = Criterion::default().with_measurement(HeapGrowth);
+//!     targets = bench
+//! }
+//! criterion::criterion_main!(benches);
+//! ```
+
+use crate::{malloc_info_summary, MallocDelta, MallocSummary};
+use ::criterion::measurement::{Measurement, ValueFormatter};
+use ::criterion::Throughput;
+
+/// Measures the change in bytes in use, as in [`MallocDelta::in_use`], across a batch of
+/// iterations. Criterion divides it by the number of iterations, so benchmarks report the heap
+/// growth per iteration.
+///
+/// Memory freed before the end of the batch doesn't count. [`Bencher::iter`] drops the value
+/// returned by each iteration inside the measured loop, so it only shows what the routine leaks
+/// and what glibc keeps around. To count what the routine returns, use
+/// [`Bencher::iter_with_large_drop`] or [`Bencher::iter_batched_ref`], which keep the returned
+/// values alive until the measurement has ended. Allocations made by other threads in the meantime
+/// count as well.
+///
+/// [`Bencher::iter`]: ::criterion::Bencher::iter
+/// [`Bencher::iter_with_large_drop`]: ::criterion::Bencher::iter_with_large_drop
+/// [`Bencher::iter_batched_ref`]: ::criterion::Bencher::iter_batched_ref
+///
+/// # Panics
+/// Panics if a snapshot can't be taken.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct HeapGrowth;
+
+impl Measurement for HeapGrowth {
+    type Intermediate = MallocSummary;
+    type Value = i64;
+
+    fn start(&self) -> MallocSummary {
+        malloc_info_summary().expect("malloc_info_summary")
+    }
+
+    fn end(&self, before: MallocSummary) -> i64 {
+        let after = malloc_info_summary().expect("malloc_info_summary");
+        MallocDelta::between(&before, &after).in_use()
+    }
+
+    fn add(&self, v1: &i64, v2: &i64) -> i64 {
+        v1.saturating_add(*v2)
+    }
+
+    fn zero(&self) -> i64 {
+        0
+    }
+
+    fn to_f64(&self, value: &i64) -> f64 {
+        *value as f64
+    }
+
+    fn formatter(&self) -> &dyn ValueFormatter {
+        &BytesFormatter
+    }
+}
+
+/// Formats values in bytes with binary prefixes
+struct BytesFormatter;
+
+impl ValueFormatter for BytesFormatter {
+    fn scale_values(&self, typical_value: f64, values: &mut [f64]) -> &'static str {
+        const UNITS: [&str; 4] = ["B", "KiB", "MiB", "GiB"];
+
+        let mut scale = 0;
+        let mut typical = typical_value.abs();
+        while typical >= 1024.0 && scale < UNITS.len() - 1 {
+            typical /= 1024.0;
+            scale += 1;
+        }
+        let factor = 1024f64.powi(scale as i32);
+        for value in values {
+            *value /= factor;
+        }
+        UNITS[scale]
+    }
+
+    fn scale_throughputs(
+        &self,
+        _typical_value: f64,
+        throughput: &Throughput,
+        values: &mut [f64],
+    ) -> &'static str {
+        let (per, unit) = match *throughput {
+            Throughput::Bits(bits) => (bits, "B/bit"),
+            Throughput::Bytes(bytes) | Throughput::BytesDecimal(bytes) => (bytes, "B/B"),
+            Throughput::Elements(elements) | Throughput::ElementsAndBytes { elements, .. } => {
+                (elements, "B/elem")
+            }
+        };
+        for value in values {
+            *value /= per.max(1) as f64;
+        }
+        unit
+    }
+
+    fn scale_for_machines(&self, _values: &mut [f64]) -> &'static str {
+        "B"
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn measurement() {
+        let before = HeapGrowth.start();
+        let growth = HeapGrowth.end(before);
+        assert_eq!(HeapGrowth.add(&growth, &HeapGrowth.zero()), growth);
+        assert_eq!(HeapGrowth.add(&i64::MAX, &1), i64::MAX);
+        assert_eq!(HeapGrowth.to_f64(&-4096), -4096.0);
+    }
+
+    #[test]
+    fn format() {
+        let formatter = HeapGrowth.formatter();
+
+        let mut values = [512.0, 3.0 * 1024.0 * 1024.0];
+        assert_eq!(
+            formatter.scale_values(2.0 * 1024.0 * 1024.0, &mut values),
+            "MiB"
+        );
+        assert_eq!(values, [512.0 / 1024.0 / 1024.0, 3.0]);
+
+        let mut values = [-2048.0];
+        assert_eq!(formatter.scale_values(-2048.0, &mut values), "KiB");
+        assert_eq!(values, [-2.0]);
+
+        let mut values = [100.0];
+        assert_eq!(formatter.scale_values(100.0, &mut values), "B");
+        assert_eq!(values, [100.0]);
+
+        let mut values = [4096.0];
+        let unit = formatter.scale_throughputs(4096.0, &Throughput::Elements(16), &mut values);
+        assert_eq!(unit, "B/elem");
+        assert_eq!(values, [256.0]);
+
+        let mut values = [4096.0];
+        assert_eq!(formatter.scale_for_machines(&mut values), "B");
+        assert_eq!(values, [4096.0]);
+    }
+}
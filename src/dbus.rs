@@ -0,0 +1,182 @@
+//! A D-Bus interface exposing heap state, for inspecting long-running desktop applications with
+//! tools such as `d-feet` or `busctl`.
+//!
+//! [`MallocInfoInterface`] implements the `io.github.Zetier.MallocInfo1` interface:
+//!
+//! - Read-only `t` properties `Arenas`, `SystemCurrent`, `SystemMax`, `Fast`, `Rest`, `Mmap` and
+//!   `MmapCount`, the fields of a fresh [`MallocSummary`] taken on every read. They change all the
+//!   time, so no `PropertiesChanged` signals are emitted for them.
+//! - A `Dump() -> s` method returning the raw XML output of `malloc_info`.
+//!
+//! [`serve_session`] serves it at [`PATH`] on a new connection to the session bus:
+//!
+//! ```rust,no_run
+//! let _connection = malloc_info::dbus::serve_session()?;
+//! // The interface is served for as long as the connection is kept open
+//! # Ok::<(), malloc_info::Error>(())
+//! ```
+//!
+//! ```text
+//! $ busctl --user get-property <unique name> /io/github/Zetier/MallocInfo \
+//!     io.github.Zetier.MallocInfo1 SystemCurrent
+//! t 2310144
+//! ```
+
+use crate::{Error, ErrorRepr, MallocSummary};
+use zbus::blocking::connection::Builder;
+use zbus::blocking::Connection;
+use zbus::fdo;
+
+/// Name of the interface
+pub const INTERFACE: &str = "io.github.Zetier.MallocInfo1";
+
+/// Object path the interface is served at by [`serve_session`]
+pub const PATH: &str = "/io/github/Zetier/MallocInfo";
+
+/// The `io.github.Zetier.MallocInfo1` interface, for serving on other buses or at other paths with
+/// [`Builder::serve_at`] or [`zbus::blocking::ObjectServer::at`]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct MallocInfoInterface;
+
+#[zbus::interface(name = "io.github.Zetier.MallocInfo1")]
+impl MallocInfoInterface {
+    /// The raw XML output of `malloc_info`
+    fn dump(&self) -> fdo::Result<String> {
+        crate::malloc_info_xml().map_err(failed)
+    }
+
+    /// Number of arenas
+    #[zbus(property(emits_changed_signal = "false"))]
+    fn arenas(&self) -> fdo::Result<u64> {
+        summary().map(|summary| summary.arenas as u64)
+    }
+
+    /// Bytes currently obtained from the system
+    #[zbus(property(emits_changed_signal = "false"))]
+    fn system_current(&self) -> fdo::Result<u64> {
+        summary().map(|summary| summary.system_current)
+    }
+
+    /// Maximum bytes ever obtained from the system
+    #[zbus(property(emits_changed_signal = "false"))]
+    fn system_max(&self) -> fdo::Result<u64> {
+        summary().map(|summary| summary.system_max)
+    }
+
+    /// Bytes held in free fastbin chunks
+    #[zbus(property(emits_changed_signal = "false"))]
+    fn fast(&self) -> fdo::Result<u64> {
+        summary().map(|summary| summary.fast)
+    }
+
+    /// Bytes held in all other free chunks
+    #[zbus(property(emits_changed_signal = "false"))]
+    fn rest(&self) -> fdo::Result<u64> {
+        summary().map(|summary| summary.rest)
+    }
+
+    /// Bytes allocated directly with `mmap`
+    #[zbus(property(emits_changed_signal = "false"))]
+    fn mmap(&self) -> fdo::Result<u64> {
+        summary().map(|summary| summary.mmap)
+    }
+
+    /// Number of chunks allocated directly with `mmap`
+    #[zbus(property(emits_changed_signal = "false"))]
+    fn mmap_count(&self) -> fdo::Result<u64> {
+        summary().map(|summary| summary.mmap_count)
+    }
+}
+
+/// Connect to the session bus and serve [`MallocInfoInterface`] at [`PATH`] on it, until the
+/// returned connection is dropped
+pub fn serve_session() -> Result<Connection, Error> {
+    fn serve_session() -> zbus::Result<Connection> {
+        Builder::session()?
+            .serve_at(PATH, MallocInfoInterface)?
+            .build()
+    }
+    let connection = serve_session().map_err(ErrorRepr::from)?;
+    Ok(connection)
+}
+
+fn summary() -> fdo::Result<MallocSummary> {
+    crate::malloc_info_summary().map_err(failed)
+}
+
+fn failed(e: impl std::fmt::Display) -> fdo::Error {
+    fdo::Error::Failed(e.to_string())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::collections::HashMap;
+    use std::os::unix::net::UnixStream;
+    use zbus::zvariant::OwnedValue;
+
+    #[test]
+    fn p2p() {
+        let (server, client) = UnixStream::pair().expect("socket pair");
+        let guid = zbus::Guid::generate();
+        let server = std::thread::spawn(move || {
+            Builder::async_io_unix_stream(server)
+                .server(guid)
+                .expect("server")
+                .p2p()
+                .serve_at(PATH, MallocInfoInterface)
+                .expect("serve_at")
+                .build()
+                .expect("server connection")
+        });
+        let client = Builder::async_io_unix_stream(client)
+            .p2p()
+            .build()
+            .expect("client connection");
+        let _server = server.join().expect("server thread");
+
+        let reply = client
+            .call_method(None::<()>, PATH, Some(INTERFACE), "Dump", &())
+            .expect("Dump");
+        let xml: String = reply.body().deserialize().expect("string");
+        assert!(xml.starts_with("<malloc "));
+
+        let reply = client
+            .call_method(
+                None::<()>,
+                PATH,
+                Some("org.freedesktop.DBus.Properties"),
+                "Get",
+                &(INTERFACE, "SystemCurrent"),
+            )
+            .expect("Get");
+        let value: OwnedValue = reply.body().deserialize().expect("variant");
+        assert!(u64::try_from(value).expect("u64") > 0);
+
+        let reply = client
+            .call_method(
+                None::<()>,
+                PATH,
+                Some("org.freedesktop.DBus.Properties"),
+                "GetAll",
+                &INTERFACE,
+            )
+            .expect("GetAll");
+        let properties: HashMap<String, OwnedValue> =
+            reply.body().deserialize().expect("properties");
+        let mut names: Vec<_> = properties.keys().map(String::as_str).collect();
+        names.sort_unstable();
+        assert_eq!(
+            names,
+            [
+                "Arenas",
+                "Fast",
+                "Mmap",
+                "MmapCount",
+                "Rest",
+                "SystemCurrent",
+                "SystemMax"
+            ]
+        );
+    }
+}
@@ -0,0 +1,406 @@
+//! Field-by-field differences between two full snapshots, see [`Malloc::diff`].
+//!
+//! Where [`MallocDelta`](crate::MallocDelta) covers the headline numbers of two summaries, a
+//! [`MallocDiff`] covers everything in the snapshots: the document-level entries, and the entries
+//! and size bins of each arena. Entries are matched by type, arenas by number and bins by kind and
+//! range. Anything present in only one of the snapshots is compared against zero.
+//!
+//! ```rust
+//! let before = malloc_info::malloc_info()?;
+//! let buffers: Vec<_> = (0..100).map(|_| vec![0u8; 1024]).collect();
+//! let after = malloc_info::malloc_info()?;
+//! for heap in before.diff(&after).heaps {
+//!     println!("arena {}: {:+} bytes free", heap.nr, heap.free());
+//! }
+//! # drop(buffers);
+//! # Ok::<(), malloc_info::Error>(())
+//! ```
+
+use crate::info::{
+    Aspace, AspaceType, Heap, Malloc, Size, SizeKind, System, SystemType, Total, TotalType,
+};
+use crate::measure::difference;
+#[cfg(feature = "serialize")]
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+/// Change in a [`Total`] entry
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serialize", derive(Serialize))]
+pub struct TotalDelta {
+    /// Type of the entry
+    pub r#type: TotalType,
+
+    /// Change in the number of chunks
+    pub count: i64,
+
+    /// Change in bytes
+    pub size: i64,
+}
+
+/// Change in a [`System`] entry
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serialize", derive(Serialize))]
+pub struct SystemDelta {
+    /// Type of the entry
+    pub r#type: SystemType,
+
+    /// Change in bytes
+    pub size: i64,
+}
+
+/// Change in an [`Aspace`] entry
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serialize", derive(Serialize))]
+pub struct AspaceDelta {
+    /// Type of the entry
+    pub r#type: AspaceType,
+
+    /// Change in bytes
+    pub size: i64,
+}
+
+/// Change in a size bin of an arena
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serialize", derive(Serialize))]
+pub struct SizeDelta {
+    /// Kind of the bin
+    pub kind: SizeKind,
+
+    /// Smallest chunk size the bin holds
+    pub from: u64,
+
+    /// Largest chunk size the bin holds
+    pub to: u64,
+
+    /// Change in bytes in the bin's free chunks
+    pub total: i64,
+
+    /// Change in the number of free chunks in the bin
+    pub count: i64,
+}
+
+/// Changes in an arena
+#[derive(Debug, Default, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serialize", derive(Serialize))]
+pub struct HeapDiff {
+    /// Arena number
+    pub nr: usize,
+
+    /// Changes in the size bins, ordered by kind and range
+    pub sizes: Vec<SizeDelta>,
+
+    /// Changes in the totals of the arena's free chunks, ordered by type
+    pub total: Vec<TotalDelta>,
+
+    /// Changes in the memory the arena obtained from the system, ordered by type
+    pub system: Vec<SystemDelta>,
+
+    /// Changes in the address space used by the arena, ordered by type
+    pub aspace: Vec<AspaceDelta>,
+}
+
+impl HeapDiff {
+    /// Change in bytes held in the arena's free chunks, fastbins included
+    pub fn free(&self) -> i64 {
+        self.total
+            .iter()
+            .filter(|total| matches!(total.r#type, TotalType::Fast | TotalType::Rest))
+            .fold(0, |sum, total| sum.saturating_add(total.size))
+    }
+}
+
+/// Changes between two snapshots, see [`Malloc::diff`]
+#[derive(Debug, Default, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serialize", derive(Serialize))]
+pub struct MallocDiff {
+    /// Changes in each arena, ordered by number
+    pub heaps: Vec<HeapDiff>,
+
+    /// Changes in the document-level totals, ordered by type
+    pub total: Vec<TotalDelta>,
+
+    /// Changes in the document-level system memory entries, ordered by type
+    pub system: Vec<SystemDelta>,
+
+    /// Changes in the document-level address space entries, ordered by type
+    pub aspace: Vec<AspaceDelta>,
+}
+
+impl MallocDiff {
+    /// Changes in the arena numbered `nr`, if it is in either snapshot
+    pub fn heap(&self, nr: usize) -> Option<&HeapDiff> {
+        self.heaps
+            .binary_search_by_key(&nr, |heap| heap.nr)
+            .ok()
+            .map(|at| &self.heaps[at])
+    }
+
+    /// Whether nothing changed between the snapshots
+    pub fn is_unchanged(&self) -> bool {
+        let unchanged = |total: &[TotalDelta], system: &[SystemDelta], aspace: &[AspaceDelta]| {
+            total
+                .iter()
+                .all(|total| total.count == 0 && total.size == 0)
+                && system.iter().all(|system| system.size == 0)
+                && aspace.iter().all(|aspace| aspace.size == 0)
+        };
+        unchanged(&self.total, &self.system, &self.aspace)
+            && self.heaps.iter().all(|heap| {
+                unchanged(&heap.total, &heap.system, &heap.aspace)
+                    && heap
+                        .sizes
+                        .iter()
+                        .all(|size| size.total == 0 && size.count == 0)
+            })
+    }
+}
+
+impl Malloc {
+    /// The changes from this snapshot to `after`: every field of the result is the value in
+    /// `after` minus the value in `self`, saturating at the bounds of `i64`. See the
+    /// [`diff`](crate::diff) module for how entries are matched.
+    ///
+    /// Both snapshots are compared in their [canonical form](Malloc::canonicalize), so entries of
+    /// the same type appearing more than once are merged first.
+    pub fn diff(&self, after: &Malloc) -> MallocDiff {
+        let mut before = self.clone();
+        let mut after = after.clone();
+        before.canonicalize();
+        after.canonicalize();
+
+        let empty = Heap::default();
+        let heaps = join(
+            &before.heaps,
+            &after.heaps,
+            |heap| heap.nr,
+            |nr, before, after| heap_diff(nr, before.unwrap_or(&empty), after.unwrap_or(&empty)),
+        );
+        MallocDiff {
+            heaps,
+            total: total_deltas(&before.total, &after.total),
+            system: system_deltas(&before.system, &after.system),
+            aspace: aspace_deltas(&before.aspace, &after.aspace),
+        }
+    }
+}
+
+fn heap_diff(nr: usize, before: &Heap, after: &Heap) -> HeapDiff {
+    HeapDiff {
+        nr,
+        sizes: join(
+            before.sizes.as_deref().unwrap_or_default(),
+            after.sizes.as_deref().unwrap_or_default(),
+            |size| (size.kind, size.from, size.to),
+            |(kind, from, to), before, after| SizeDelta {
+                kind,
+                from,
+                to,
+                total: delta(before, after, |size: &Size| size.total),
+                count: delta(before, after, |size: &Size| size.count),
+            },
+        ),
+        total: total_deltas(&before.total, &after.total),
+        system: system_deltas(&before.system, &after.system),
+        aspace: aspace_deltas(&before.aspace, &after.aspace),
+    }
+}
+
+fn total_deltas(before: &[Total], after: &[Total]) -> Vec<TotalDelta> {
+    join(
+        before,
+        after,
+        |total| total.r#type,
+        |r#type, before, after| TotalDelta {
+            r#type,
+            count: delta(before, after, |total| total.count),
+            size: delta(before, after, |total| total.size),
+        },
+    )
+}
+
+fn system_deltas(before: &[System], after: &[System]) -> Vec<SystemDelta> {
+    join(
+        before,
+        after,
+        |system| system.r#type,
+        |r#type, before, after| SystemDelta {
+            r#type,
+            size: delta(before, after, |system| system.size),
+        },
+    )
+}
+
+fn aspace_deltas(before: &[Aspace], after: &[Aspace]) -> Vec<AspaceDelta> {
+    join(
+        before,
+        after,
+        |aspace| aspace.r#type,
+        |r#type, before, after| AspaceDelta {
+            r#type,
+            size: delta(before, after, |aspace| aspace.size),
+        },
+    )
+}
+
+/// Pair up the entries of `before` and `after` with the same key, and turn each pair into a delta
+/// with `diff`, in key order. Entries are expected to have unique keys on each side.
+fn join<'a, T, K: Ord, D>(
+    before: &'a [T],
+    after: &'a [T],
+    key: impl Fn(&T) -> K,
+    diff: impl Fn(K, Option<&'a T>, Option<&'a T>) -> D,
+) -> Vec<D> {
+    let mut pairs = BTreeMap::new();
+    for entry in before {
+        pairs.entry(key(entry)).or_insert((None, None)).0 = Some(entry);
+    }
+    for entry in after {
+        pairs.entry(key(entry)).or_insert((None, None)).1 = Some(entry);
+    }
+    pairs
+        .into_iter()
+        .map(|(key, (before, after))| diff(key, before, after))
+        .collect()
+}
+
+/// Change in the field read by `field` from `before` to `after`, either of which counts as zero if
+/// missing
+fn delta<T>(before: Option<&T>, after: Option<&T>, field: impl Fn(&T) -> u64) -> i64 {
+    difference(before.map_or(0, &field), after.map_or(0, &field))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const BEFORE: &str = r#"<malloc version="1">
+<heap nr="0">
+<sizes>
+<size from="17" to="32" total="64" count="2"/>
+<unsorted from="49" to="49" total="49" count="1"/>
+</sizes>
+<total type="fast" count="2" size="64"/>
+<total type="rest" count="1" size="49"/>
+<system type="current" size="135168"/>
+<system type="max" size="135168"/>
+<aspace type="total" size="135168"/>
+<aspace type="mprotect" size="135168"/>
+</heap>
+<total type="fast" count="2" size="64"/>
+<total type="rest" count="1" size="49"/>
+<total type="mmap" count="0" size="0"/>
+<system type="current" size="135168"/>
+<system type="max" size="135168"/>
+<aspace type="total" size="135168"/>
+<aspace type="mprotect" size="135168"/>
+</malloc>"#;
+
+    const AFTER: &str = r#"<malloc version="1">
+<heap nr="0">
+<sizes>
+<size from="17" to="32" total="32" count="1"/>
+<size from="33" to="48" total="96" count="2"/>
+</sizes>
+<total type="fast" count="3" size="128"/>
+<total type="rest" count="0" size="0"/>
+<system type="current" size="200704"/>
+<system type="max" size="200704"/>
+<aspace type="total" size="200704"/>
+<aspace type="mprotect" size="200704"/>
+</heap>
+<heap nr="1">
+<sizes>
+</sizes>
+<total type="fast" count="0" size="0"/>
+<total type="rest" count="0" size="0"/>
+<system type="current" size="135168"/>
+<system type="max" size="135168"/>
+<aspace type="total" size="135168"/>
+<aspace type="mprotect" size="135168"/>
+</heap>
+<total type="fast" count="3" size="128"/>
+<total type="rest" count="0" size="0"/>
+<total type="mmap" count="1" size="266240"/>
+<system type="current" size="335872"/>
+<system type="max" size="335872"/>
+<aspace type="total" size="335872"/>
+<aspace type="mprotect" size="335872"/>
+</malloc>"#;
+
+    #[test]
+    fn diff() {
+        let before: Malloc = BEFORE.parse().expect("parse XML");
+        let after: Malloc = AFTER.parse().expect("parse XML");
+        let diff = before.diff(&after);
+
+        assert_eq!(
+            diff.total,
+            [
+                TotalDelta {
+                    r#type: TotalType::Fast,
+                    count: 1,
+                    size: 64,
+                },
+                TotalDelta {
+                    r#type: TotalType::Rest,
+                    count: -1,
+                    size: -49,
+                },
+                TotalDelta {
+                    r#type: TotalType::Mmap,
+                    count: 1,
+                    size: 266240,
+                },
+            ]
+        );
+        assert_eq!(
+            diff.system,
+            [
+                SystemDelta {
+                    r#type: SystemType::Current,
+                    size: 200704,
+                },
+                SystemDelta {
+                    r#type: SystemType::Max,
+                    size: 200704,
+                },
+            ]
+        );
+        assert_eq!(diff.aspace.len(), 2);
+
+        let heap = diff.heap(0).expect("arena 0");
+        assert_eq!(heap.free(), 15);
+        let bins: Vec<_> = heap
+            .sizes
+            .iter()
+            .map(|size| (size.kind, size.from, size.total, size.count))
+            .collect();
+        assert_eq!(
+            bins,
+            [
+                (SizeKind::Size, 17, -32, -1),
+                (SizeKind::Size, 33, 96, 2),
+                (SizeKind::Unsorted, 49, -49, -1),
+            ]
+        );
+
+        // Only in the later snapshot, so compared against an empty arena
+        let new = diff.heap(1).expect("arena 1");
+        assert!(new.sizes.is_empty());
+        assert_eq!(new.system[0].size, 135168);
+        assert!(diff.heap(2).is_none());
+
+        assert_eq!(after.diff(&before).total[2].size, -266240);
+        assert!(!diff.is_unchanged());
+    }
+
+    #[test]
+    fn unchanged() {
+        let info: Malloc = AFTER.parse().expect("parse XML");
+        let diff = info.diff(&info);
+        assert!(diff.is_unchanged());
+        assert_eq!(diff.heaps.len(), 2);
+        assert!(Malloc::default().diff(&Malloc::default()).is_unchanged());
+    }
+}
@@ -0,0 +1,284 @@
+//! Sending snapshots to the Datadog agent over DogStatsD.
+//!
+//! Every metric is a gauge. Document-level totals are named `malloc.*`, and the same numbers for
+//! each arena `malloc.arena.*`, tagged with `arena:<nr>`:
+//!
+//! | Metric | Tags | |
+//! |---|---|---|
+//! | `malloc.arenas` | | Number of arenas |
+//! | `malloc.total.bytes`, `malloc.arena.total.bytes` | `type:fast\|rest\|mmap` | Bytes in free or mmapped chunks |
+//! | `malloc.total.chunks`, `malloc.arena.total.chunks` | `type:fast\|rest\|mmap` | Number of free or mmapped chunks |
+//! | `malloc.system.bytes`, `malloc.arena.system.bytes` | `type:current\|max` | Bytes obtained from the system |
+//! | `malloc.aspace.bytes`, `malloc.arena.aspace.bytes` | `type:total\|mprotect\|subheaps` | Address space used |
+//!
+//! The `malloc` prefix can be changed with [`DogStatsd::with_prefix`]. Extra tags are attached
+//! to every metric, and [`DogStatsd::from_env`] picks up the agent address and the unified
+//! service tags from the environment variables set by the Datadog tooling.
+//!
+//! ```rust,no_run
+//! use malloc_info::dogstatsd::DogStatsd;
+//!
+//! let client = DogStatsd::from_env()
+//!     .expect("DogStatsD socket")
+//!     .with_tag("team", "platform");
+//! client
+//!     .send(&malloc_info::malloc_info().expect("malloc_info"))
+//!     .expect("send metrics");
+//! ```
+
+use crate::info;
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+
+/// Environment variable holding the host of the agent
+pub const ENV_HOST: &str = "DD_AGENT_HOST";
+
+/// Environment variable holding the DogStatsD port of the agent
+pub const ENV_PORT: &str = "DD_DOGSTATSD_PORT";
+
+/// Default DogStatsD port of the agent
+pub const DEFAULT_PORT: u16 = 8125;
+
+/// Largest datagram sent, which fits in the MTU of common networks without fragmentation
+const MAX_DATAGRAM: usize = 1432;
+
+/// Unified service tags and the environment variables they are taken from by
+/// [`DogStatsd::from_env`]
+const SERVICE_TAGS: [(&str, &str); 3] = [
+    ("env", "DD_ENV"),
+    ("service", "DD_SERVICE"),
+    ("version", "DD_VERSION"),
+];
+
+/// A DogStatsD client sending snapshots as gauges over UDP
+#[derive(Debug)]
+pub struct DogStatsd {
+    socket: UdpSocket,
+    prefix: String,
+    tags: Vec<String>,
+}
+
+impl DogStatsd {
+    /// Send to the agent at `addr`, using the first address it resolves to
+    pub fn new(addr: impl ToSocketAddrs) -> io::Result<Self> {
+        let addr = addr.to_socket_addrs()?.next().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::NotFound, "agent address did not resolve")
+        })?;
+        let local: SocketAddr = if addr.is_ipv4() {
+            ([0, 0, 0, 0], 0).into()
+        } else {
+            ([0u16; 8], 0).into()
+        };
+        let socket = UdpSocket::bind(local)?;
+        socket.connect(addr)?;
+        Ok(Self {
+            socket,
+            prefix: "malloc".into(),
+            tags: Vec::new(),
+        })
+    }
+
+    /// Send to the agent at [`ENV_HOST`] and [`ENV_PORT`], defaulting to `127.0.0.1` and
+    /// [`DEFAULT_PORT`], and tag every metric with the `env`, `service` and `version` unified
+    /// service tags from `DD_ENV`, `DD_SERVICE` and `DD_VERSION` where they are set.
+    pub fn from_env() -> io::Result<Self> {
+        let host = std::env::var(ENV_HOST).unwrap_or_else(|_| "127.0.0.1".into());
+        let port = match std::env::var(ENV_PORT) {
+            Ok(port) => port.parse().map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("invalid {ENV_PORT}: {port}"),
+                )
+            })?,
+            Err(_) => DEFAULT_PORT,
+        };
+
+        let mut client = Self::new((host.as_str(), port))?;
+        for (key, var) in SERVICE_TAGS {
+            if let Ok(value) = std::env::var(var) {
+                client = client.with_tag(key, &value);
+            }
+        }
+        Ok(client)
+    }
+
+    /// Name metrics `<prefix>.*` instead of `malloc.*`. Characters DogStatsD reserves in names,
+    /// `:` as well as those reserved in tags, are replaced with underscores.
+    pub fn with_prefix(mut self, prefix: &str) -> Self {
+        self.prefix = sanitize_name(prefix);
+        self
+    }
+
+    /// Attach the tag `key:value` to every metric. Characters DogStatsD reserves, `|`, `,`, `#`
+    /// and line breaks, are replaced with underscores, as is `:` in `key`.
+    pub fn with_tag(mut self, key: &str, value: &str) -> Self {
+        self.tags
+            .push(format!("{}:{}", sanitize_name(key), sanitize(value)));
+        self
+    }
+
+    /// Send the metrics for `info`, packing as many as fit into each datagram
+    pub fn send(&self, info: &info::Malloc) -> io::Result<()> {
+        let mut datagram = String::with_capacity(MAX_DATAGRAM);
+        for line in render(info, &self.prefix, &self.tags) {
+            if !datagram.is_empty() && datagram.len() + 1 + line.len() > MAX_DATAGRAM {
+                self.socket.send(datagram.as_bytes())?;
+                datagram.clear();
+            }
+            if !datagram.is_empty() {
+                datagram.push('\n');
+            }
+            datagram.push_str(&line);
+        }
+        if !datagram.is_empty() {
+            self.socket.send(datagram.as_bytes())?;
+        }
+        Ok(())
+    }
+}
+
+/// Render `info` as DogStatsD gauge lines, one per metric, named `<prefix>.*` and tagged with
+/// `tags`, each of the form `key:value`
+pub fn render(info: &info::Malloc, prefix: &str, tags: &[String]) -> Vec<String> {
+    let mut lines = Vec::new();
+    let mut gauge = |name: &str, value: u64, extra: &[String]| {
+        let mut line = format!("{prefix}.{name}:{value}|g");
+        for (i, tag) in tags.iter().chain(extra).enumerate() {
+            line.push_str(if i == 0 { "|#" } else { "," });
+            line.push_str(tag);
+        }
+        lines.push(line);
+    };
+
+    gauge("arenas", info.heaps.len() as u64, &[]);
+    let entries = |gauge: &mut dyn FnMut(&str, u64, &[String]),
+                   scope: &str,
+                   extra: &[String],
+                   total: &[info::Total],
+                   system: &[info::System],
+                   aspace: &[info::Aspace]| {
+        let with_type = |r#type: &str| {
+            let mut tags = extra.to_vec();
+            tags.push(format!("type:{type}"));
+            tags
+        };
+        for total in total {
+            let tags = with_type(total.r#type.as_str());
+            gauge(&format!("{scope}total.bytes"), total.size, &tags);
+            gauge(&format!("{scope}total.chunks"), total.count, &tags);
+        }
+        for system in system {
+            let tags = with_type(system.r#type.as_str());
+            gauge(&format!("{scope}system.bytes"), system.size, &tags);
+        }
+        for aspace in aspace {
+            let tags = with_type(aspace.r#type.as_str());
+            gauge(&format!("{scope}aspace.bytes"), aspace.size, &tags);
+        }
+    };
+
+    entries(&mut gauge, "", &[], &info.total, &info.system, &info.aspace);
+    for heap in &info.heaps {
+        let arena = [format!("arena:{}", heap.nr)];
+        entries(
+            &mut gauge,
+            "arena.",
+            &arena,
+            &heap.total,
+            &heap.system,
+            &heap.aspace,
+        );
+    }
+    lines
+}
+
+/// Replace the characters DogStatsD reserves with underscores
+fn sanitize(value: &str) -> String {
+    value
+        .chars()
+        .map(|c| match c {
+            '|' | ',' | '#' | '\n' | '\r' => '_',
+            c => c,
+        })
+        .collect()
+}
+
+/// Replace the characters DogStatsD reserves with underscores, including the `:` which ends a
+/// metric name or tag key
+fn sanitize_name(name: &str) -> String {
+    sanitize(&name.replace(':', "_"))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Duration;
+
+    // Taken from the malloc_info(3) man-page
+    const XML: &str = r#"
+<malloc version="1">
+<heap nr="0">
+<sizes>
+</sizes>
+<total type="fast" count="0" size="0"/>
+<total type="rest" count="0" size="0"/>
+<system type="current" size="135168"/>
+<system type="max" size="135168"/>
+<aspace type="total" size="135168"/>
+<aspace type="mprotect" size="135168"/>
+</heap>
+<total type="fast" count="0" size="0"/>
+<total type="rest" count="0" size="0"/>
+<system type="current" size="135168"/>
+<system type="max" size="135168"/>
+<aspace type="total" size="135168"/>
+<aspace type="mprotect" size="135168"/>
+</malloc>
+"#;
+
+    #[test]
+    fn render_lines() {
+        let info: info::Malloc = quick_xml::de::from_str(XML).expect("parse XML");
+        let lines = render(&info, "malloc", &["service:api".into()]);
+        assert_eq!(lines[0], "malloc.arenas:1|g|#service:api");
+        assert!(lines.contains(&"malloc.system.bytes:135168|g|#service:api,type:current".into()));
+        assert!(lines.contains(&"malloc.total.chunks:0|g|#service:api,type:rest".into()));
+        assert!(lines.contains(
+            &"malloc.arena.aspace.bytes:135168|g|#service:api,arena:0,type:mprotect".into()
+        ));
+        assert_eq!(lines.len(), 1 + 2 * (4 + 2 + 2));
+
+        let lines = render(&info, "app.heap", &[]);
+        assert_eq!(lines[0], "app.heap.arenas:1|g");
+        assert!(lines.contains(&"app.heap.arena.total.bytes:0|g|#arena:0,type:fast".into()));
+    }
+
+    #[test]
+    fn send() {
+        let agent = UdpSocket::bind("127.0.0.1:0").expect("bind agent");
+        agent
+            .set_read_timeout(Some(Duration::from_secs(10)))
+            .expect("set timeout");
+        let client = DogStatsd::new(agent.local_addr().expect("agent address"))
+            .expect("client")
+            .with_prefix("test|app:heap")
+            .with_tag("pod", "a,b#c")
+            .with_tag("k8s:ns", "default:prod");
+
+        let info = crate::malloc_info().expect("malloc_info");
+        client.send(&info).expect("send");
+
+        let expected = render(&info, &client.prefix, &client.tags);
+        let mut received = Vec::new();
+        let mut buf = [0u8; 65536];
+        while received.len() < expected.len() {
+            let len = agent.recv(&mut buf).expect("receive");
+            assert!(len <= MAX_DATAGRAM);
+            let datagram = std::str::from_utf8(&buf[..len]).expect("UTF-8");
+            received.extend(datagram.lines().map(str::to_owned));
+        }
+        assert_eq!(received, expected);
+        assert!(received[0].starts_with("test_app_heap.arenas:"));
+        assert!(received[0].ends_with("|#pod:a_b_c,k8s_ns:default:prod"));
+    }
+}
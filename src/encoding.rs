@@ -0,0 +1,102 @@
+//! Encoding snapshots into formats other than the XML they are parsed from.
+
+use crate::{info, Error, ErrorRepr};
+
+#[cfg(feature = "bincode")]
+impl info::Malloc {
+    /// Encode the snapshot with bincode's standard configuration. This is the most compact
+    /// encoding offered, intended for shipping large numbers of samples.
+    pub fn to_bincode(&self) -> Result<Vec<u8>, Error> {
+        bincode::serde::encode_to_vec(self, bincode::config::standard())
+            .map_err(|e| ErrorRepr::from(e).into())
+    }
+
+    /// Decode a snapshot previously encoded with [`Malloc::to_bincode`](info::Malloc::to_bincode)
+    pub fn from_bincode(bytes: &[u8]) -> Result<Self, Error> {
+        bincode::serde::decode_from_slice(bytes, bincode::config::standard())
+            .map(|(info, _)| info)
+            .map_err(|e| ErrorRepr::from(e).into())
+    }
+}
+
+#[cfg(feature = "cbor")]
+impl info::Malloc {
+    /// Encode the snapshot as CBOR, for constrained collectors or for embedding in signed (COSE)
+    /// diagnostic bundles
+    pub fn to_cbor(&self) -> Result<Vec<u8>, Error> {
+        let mut bytes = Vec::new();
+        ciborium::into_writer(self, &mut bytes).map_err(ErrorRepr::from)?;
+        Ok(bytes)
+    }
+
+    /// Decode a snapshot from CBOR, as encoded by [`Malloc::to_cbor`](info::Malloc::to_cbor)
+    pub fn from_cbor(bytes: &[u8]) -> Result<Self, Error> {
+        ciborium::from_reader(bytes).map_err(|e| ErrorRepr::from(e).into())
+    }
+}
+
+#[cfg(feature = "msgpack")]
+impl info::Malloc {
+    /// Encode the snapshot as MessagePack. Structs are encoded as maps keyed by field name, so
+    /// that msgpack-native pipelines (Fluent Bit, Vector and the like) can make sense of the
+    /// output without a schema.
+    pub fn to_msgpack(&self) -> Result<Vec<u8>, Error> {
+        rmp_serde::to_vec_named(self).map_err(|e| ErrorRepr::from(e).into())
+    }
+
+    /// Decode a snapshot from MessagePack, as encoded by
+    /// [`Malloc::to_msgpack`](info::Malloc::to_msgpack)
+    pub fn from_msgpack(bytes: &[u8]) -> Result<Self, Error> {
+        rmp_serde::from_slice(bytes).map_err(|e| ErrorRepr::from(e).into())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[cfg(feature = "cbor")]
+    #[test]
+    fn cbor_round_trip() {
+        let info = crate::malloc_info().expect("malloc_info");
+        let bytes = info.to_cbor().expect("encode");
+        assert_eq!(info::Malloc::from_cbor(&bytes).expect("decode"), info);
+    }
+
+    #[cfg(feature = "cbor")]
+    #[test]
+    fn cbor_invalid() {
+        assert!(info::Malloc::from_cbor(&[0xff; 4]).is_err());
+    }
+
+    #[cfg(feature = "msgpack")]
+    #[test]
+    fn msgpack_round_trip() {
+        let info = crate::malloc_info().expect("malloc_info");
+        let bytes = info.to_msgpack().expect("encode");
+        assert_eq!(info::Malloc::from_msgpack(&bytes).expect("decode"), info);
+    }
+
+    #[cfg(feature = "msgpack")]
+    #[test]
+    fn msgpack_named() {
+        let info = crate::malloc_info().expect("malloc_info");
+        let bytes = info.to_msgpack().expect("encode");
+        let needle = b"version";
+        assert!(bytes.windows(needle.len()).any(|window| window == needle));
+    }
+
+    #[cfg(feature = "bincode")]
+    #[test]
+    fn bincode_round_trip() {
+        let info = crate::malloc_info().expect("malloc_info");
+        let bytes = info.to_bincode().expect("encode");
+        assert_eq!(info::Malloc::from_bincode(&bytes).expect("decode"), info);
+    }
+
+    #[cfg(feature = "bincode")]
+    #[test]
+    fn bincode_invalid() {
+        assert!(info::Malloc::from_bincode(&[0xff; 4]).is_err());
+    }
+}
@@ -0,0 +1,71 @@
+//! Reading configuration from environment variables, shared by the exporter and the pusher.
+
+use std::io;
+
+/// Read the environment variable `name`, if it is set. A value which isn't valid Unicode is an
+/// error rather than unset.
+pub(crate) fn var(name: &str) -> io::Result<Option<String>> {
+    match std::env::var(name) {
+        Ok(value) => Ok(Some(value)),
+        Err(std::env::VarError::NotPresent) => Ok(None),
+        Err(e) => Err(invalid_input(format!("{name}: {e}"))),
+    }
+}
+
+/// Read labels from the environment variable `name`, as comma separated `name=value` pairs. No
+/// labels are read if it is unset. Label names must be valid Prometheus label names, other than
+/// the ones used by the crate itself.
+pub(crate) fn labels(name: &str) -> io::Result<Vec<(String, String)>> {
+    match var(name)? {
+        Some(labels) => parse_labels(name, &labels),
+        None => Ok(Vec::new()),
+    }
+}
+
+/// Parse `labels`, the value of the environment variable `name`, see [`labels`]
+fn parse_labels(name: &str, labels: &str) -> io::Result<Vec<(String, String)>> {
+    let labels = labels
+        .split(',')
+        .filter(|label| !label.trim().is_empty())
+        .map(|label| match label.split_once('=') {
+            Some((key, value)) => Ok((key.trim().to_owned(), value.trim().to_owned())),
+            None => Err(invalid_input(format!(
+                "{name}: expected name=value, got {label}"
+            ))),
+        })
+        .collect::<io::Result<Vec<_>>>()?;
+    crate::labels::validate(labels.iter().map(|(key, _)| key.as_str()))
+        .map_err(|e| invalid_input(format!("{name}: {e}")))?;
+    Ok(labels)
+}
+
+pub(crate) fn invalid_input(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn labels() {
+        assert_eq!(
+            parse_labels("LABELS", " service = api ,, region=eu-1,").expect("labels"),
+            [
+                ("service".to_owned(), "api".to_owned()),
+                ("region".to_owned(), "eu-1".to_owned())
+            ]
+        );
+        assert!(parse_labels("LABELS", "").expect("labels").is_empty());
+
+        let error = parse_labels("LABELS", "service").expect_err("no value");
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+        assert!(error.to_string().starts_with("LABELS: "));
+
+        for labels in ["pod-name=x", "type=heap", "version=1", "pod=a,pod=b"] {
+            let error = parse_labels("LABELS", labels).expect_err(labels);
+            assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+            assert!(error.to_string().starts_with("LABELS: "));
+        }
+    }
+}
@@ -0,0 +1,1024 @@
+//! A standalone HTTP server exposing snapshots to scrapers, on its own threads and without any web
+//! framework. Each connection is served on a thread of its own, so a slow client doesn't hold up
+//! the others.
+//!
+//! Each request takes a fresh snapshot. The server answers:
+//!
+//! - `GET /metrics`: the snapshot in the Prometheus text exposition format, see
+//!   [`prometheus::render_with`] and [`Config::semantics`].
+//! - `GET /json`: the full snapshot as JSON, with the [`BuildInfo`](crate::BuildInfo) of the
+//!   process added under `build_info`.
+//! - `GET /malloc`: either of the above, or a minimal HTML page for humans, depending on the
+//!   `Accept` header of the request. Clients accepting anything get the Prometheus format.
+//! - `GET /healthz`: health of the exporter itself. It takes a [`MallocSummary`](crate::MallocSummary),
+//!   which is cheap, and answers with JSON holding the outcome, how long the capture took and the
+//!   age in seconds of the last successful capture by any endpoint. The status is
+//!   `503 Service Unavailable` if the capture failed, unless the last successful one is no older
+//!   than [`Config::max_capture_age`]. It doesn't depend on scrapes, so it stays healthy while
+//!   nothing scrapes the exporter.
+//!
+//! Requests other than `/healthz` can be required to carry a bearer token, see [`Config::token`].
+//! With the
+//! `exporter-tls` feature, the server can also terminate TLS itself, see [`Config::tls`].
+//!
+//! ```no_run
+//! # use malloc_info::exporter::{serve_exporter, Config};
+//! let exporter = serve_exporter(Config::from_env()?)?;
+//! println!("serving malloc metrics on {}", exporter.local_addr());
+//! # Ok::<(), std::io::Error>(())
+//! ```
+
+use crate::env::{self, invalid_input};
+use crate::prometheus;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+#[cfg(feature = "exporter-tls")]
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Environment variable holding the address to listen on
+pub const ENV_ADDR: &str = "MALLOC_INFO_EXPORTER_ADDR";
+
+/// Environment variable holding extra labels for every series, as comma separated `name=value`
+/// pairs
+pub const ENV_LABELS: &str = "MALLOC_INFO_EXPORTER_LABELS";
+
+/// Environment variable holding the metrics to expose as counters, as a comma separated list of
+/// names with or without their `malloc_info_` prefix. See [`prometheus::Semantics::counters`].
+pub const ENV_COUNTERS: &str = "MALLOC_INFO_EXPORTER_COUNTERS";
+
+/// Environment variable enabling [`prometheus::Semantics::split_max`] when set to `1` or `true`
+pub const ENV_SPLIT_MAX: &str = "MALLOC_INFO_EXPORTER_SPLIT_MAX";
+
+/// Environment variable holding the bearer token requests must present
+pub const ENV_TOKEN: &str = "MALLOC_INFO_EXPORTER_TOKEN";
+
+/// Environment variable holding the maximum age of the last successful capture, in seconds, for
+/// which `/healthz` tolerates failing captures
+pub const ENV_MAX_CAPTURE_AGE: &str = "MALLOC_INFO_EXPORTER_MAX_CAPTURE_AGE";
+
+/// Environment variable holding the path to the PEM encoded certificate chain to serve TLS with
+#[cfg(feature = "exporter-tls")]
+pub const ENV_TLS_CERT: &str = "MALLOC_INFO_EXPORTER_TLS_CERT";
+
+/// Environment variable holding the path to the PEM encoded private key to serve TLS with
+#[cfg(feature = "exporter-tls")]
+pub const ENV_TLS_KEY: &str = "MALLOC_INFO_EXPORTER_TLS_KEY";
+
+/// Address listened on by default
+pub const DEFAULT_ADDR: &str = "127.0.0.1:9464";
+
+/// Timeout for reading a request from, and writing a response to, a client
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Upper bound on the size of a request head
+const MAX_REQUEST: usize = 8 << 10;
+
+/// Configuration of the exporter server
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct Config {
+    /// Address to listen on
+    pub addr: SocketAddr,
+
+    /// Extra labels attached to every Prometheus series. Names must be valid Prometheus label
+    /// names, and not ones the crate uses itself, see [`prometheus::render`].
+    pub labels: Vec<(String, String)>,
+
+    /// Which Prometheus metrics are gauges and which are counters
+    pub semantics: prometheus::Semantics,
+
+    /// Token requests must present in an `Authorization: Bearer <token>` header. Requests without
+    /// it are answered with `401 Unauthorized`. Any request is accepted when this is `None`.
+    pub token: Option<String>,
+
+    /// Age of the last successful capture up to which `/healthz` tolerates its own capture
+    /// failing, reporting the exporter as degraded rather than failing. `/healthz` fails as soon as
+    /// its capture does when this is `None`.
+    pub max_capture_age: Option<Duration>,
+
+    /// Certificate and key to terminate TLS with. The server only accepts plaintext connections
+    /// when this is `None`.
+    #[cfg(feature = "exporter-tls")]
+    pub tls: Option<TlsConfig>,
+}
+
+/// Certificate and key files for serving TLS
+#[cfg(feature = "exporter-tls")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TlsConfig {
+    /// PEM file holding the certificate chain, starting with the server's own certificate
+    pub cert: PathBuf,
+
+    /// PEM file holding the private key of the server's certificate
+    pub key: PathBuf,
+}
+
+impl Default for Config {
+    /// Listen on [`DEFAULT_ADDR`], without extra labels
+    fn default() -> Self {
+        Self {
+            addr: DEFAULT_ADDR.parse().expect("valid default address"),
+            labels: Vec::new(),
+            semantics: prometheus::Semantics::default(),
+            token: None,
+            max_capture_age: None,
+            #[cfg(feature = "exporter-tls")]
+            tls: None,
+        }
+    }
+}
+
+impl Config {
+    /// Read the configuration from the environment, falling back to the defaults for anything
+    /// unset. See [`ENV_ADDR`], [`ENV_LABELS`], [`ENV_COUNTERS`], [`ENV_SPLIT_MAX`], [`ENV_TOKEN`]
+    /// and [`ENV_MAX_CAPTURE_AGE`], and with the `exporter-tls` feature
+    /// `ENV_TLS_CERT` and `ENV_TLS_KEY`, which must be set together.
+    pub fn from_env() -> io::Result<Self> {
+        let mut config = Self::default();
+        if let Some(addr) = env::var(ENV_ADDR)? {
+            config.addr = addr.to_socket_addrs()?.next().ok_or_else(|| {
+                invalid_input(format!("{ENV_ADDR}: {addr} did not resolve to an address"))
+            })?;
+        }
+        config.labels = env::labels(ENV_LABELS)?;
+        if let Some(counters) = env::var(ENV_COUNTERS)? {
+            config.semantics.counters = counters
+                .split(',')
+                .map(str::trim)
+                .filter(|name| !name.is_empty())
+                .map(|name| {
+                    prometheus::Metric::from_name(name).ok_or_else(|| {
+                        invalid_input(format!("{ENV_COUNTERS}: unknown metric {name}"))
+                    })
+                })
+                .collect::<io::Result<_>>()?;
+        }
+        if let Some(split_max) = env::var(ENV_SPLIT_MAX)? {
+            config.semantics.split_max = match split_max.trim() {
+                "1" | "true" => true,
+                "0" | "false" | "" => false,
+                _ => {
+                    return Err(invalid_input(format!(
+                        "{ENV_SPLIT_MAX}: expected true or false, got {split_max}"
+                    )))
+                }
+            };
+        }
+        config.token = env::var(ENV_TOKEN)?.filter(|token| !token.is_empty());
+        if let Some(age) = env::var(ENV_MAX_CAPTURE_AGE)? {
+            let seconds = age.parse().map_err(|_| {
+                invalid_input(format!(
+                    "{ENV_MAX_CAPTURE_AGE}: invalid number of seconds {age}"
+                ))
+            })?;
+            config.max_capture_age = Some(Duration::from_secs(seconds));
+        }
+        #[cfg(feature = "exporter-tls")]
+        {
+            config.tls = match (env::var(ENV_TLS_CERT)?, env::var(ENV_TLS_KEY)?) {
+                (Some(cert), Some(key)) => Some(TlsConfig {
+                    cert: cert.into(),
+                    key: key.into(),
+                }),
+                (None, None) => None,
+                _ => {
+                    return Err(invalid_input(format!(
+                        "{ENV_TLS_CERT} and {ENV_TLS_KEY} must be set together"
+                    )))
+                }
+            };
+        }
+        Ok(config)
+    }
+}
+
+/// Handle to a running exporter server. Dropping the handle leaves the server running for the
+/// rest of the process; call [`Exporter::shutdown`] to stop it.
+#[derive(Debug)]
+pub struct Exporter {
+    addr: SocketAddr,
+    shutdown: Arc<AtomicBool>,
+    thread: thread::JoinHandle<()>,
+}
+
+impl Exporter {
+    /// Address the server is listening on
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Stop the server and wait for its thread to exit
+    pub fn shutdown(self) {
+        self.shutdown.store(true, Ordering::SeqCst);
+        // Wake the server up from accept(). If this fails, the server is already gone.
+        let _ = TcpStream::connect(self.addr);
+        let _ = self.thread.join();
+    }
+}
+
+/// Bind `config.addr` and serve snapshots on a new thread. See the [module documentation](self)
+/// for the endpoints served.
+///
+/// An error is returned if `config.labels` has an invalid or reserved name. With the
+/// `exporter-tls` feature, the certificate and key in `config.tls` are loaded before binding, and
+/// an error is returned if they are unusable.
+pub fn serve_exporter(config: Config) -> io::Result<Exporter> {
+    crate::labels::validate(config.labels.iter().map(|(name, _)| name.as_str()))?;
+    let server = Arc::new(Server {
+        #[cfg(feature = "exporter-tls")]
+        tls: config.tls.as_ref().map(tls_config).transpose()?,
+        config,
+        last_capture: Mutex::new(None),
+    });
+    let listener = TcpListener::bind(server.config.addr)?;
+    let addr = listener.local_addr()?;
+    let shutdown = Arc::new(AtomicBool::new(false));
+
+    let thread = {
+        let shutdown = Arc::clone(&shutdown);
+        thread::Builder::new()
+            .name("malloc-info-exporter".into())
+            .spawn(move || {
+                for stream in listener.incoming() {
+                    if shutdown.load(Ordering::SeqCst) {
+                        break;
+                    }
+                    // A misbehaving client only affects its own connection
+                    if let Ok(stream) = stream {
+                        let server = Arc::clone(&server);
+                        let _ = thread::Builder::new()
+                            .name("malloc-info-exporter-client".into())
+                            .spawn(move || server.accept(stream));
+                    }
+                }
+            })?
+    };
+
+    Ok(Exporter {
+        addr,
+        shutdown,
+        thread,
+    })
+}
+
+/// The parts of a request the server looks at
+struct Request {
+    method: String,
+    path: String,
+    headers: Vec<(String, String)>,
+}
+
+impl Request {
+    /// Read a request head from `reader`, reading no more than [`MAX_REQUEST`] bytes. Returns the
+    /// response to send instead if the head is malformed or too large.
+    fn read(reader: &mut impl BufRead) -> io::Result<Result<Self, Response>> {
+        let too_large =
+            || Response::error("431 Request Header Fields Too Large", "request too large\n");
+        let mut remaining = MAX_REQUEST as u64;
+        let request_line = match read_line(reader, &mut remaining)? {
+            Some(line) => line,
+            None => return Ok(Err(too_large())),
+        };
+
+        let mut headers = Vec::new();
+        loop {
+            let line = match read_line(reader, &mut remaining)? {
+                Some(line) => line,
+                None => return Ok(Err(too_large())),
+            };
+            if line.is_empty() || line == "\r\n" || line == "\n" {
+                break;
+            }
+            if let Some((name, value)) = line.split_once(':') {
+                headers.push((name.trim().to_owned(), value.trim().to_owned()));
+            }
+        }
+
+        let mut parts = request_line.split_whitespace();
+        let (method, target) = match (parts.next(), parts.next()) {
+            (Some(method), Some(target)) => (method, target),
+            _ => {
+                return Ok(Err(Response::error(
+                    "400 Bad Request",
+                    "malformed request\n",
+                )))
+            }
+        };
+        let path = target.split('?').next().unwrap_or_default();
+        Ok(Ok(Self {
+            method: method.to_owned(),
+            path: path.to_owned(),
+            headers,
+        }))
+    }
+
+    /// Value of the first header called `name`, ignoring case
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Check the bearer token of the request against `token`
+    fn authorized(&self, token: &str) -> bool {
+        let presented = self.header("Authorization").and_then(|value| {
+            let (scheme, credentials) = value.split_once(' ')?;
+            scheme
+                .eq_ignore_ascii_case("Bearer")
+                .then(|| credentials.trim())
+        });
+        presented.map_or(false, |presented| {
+            constant_time_eq(presented.as_bytes(), token.as_bytes())
+        })
+    }
+}
+
+/// Formats a snapshot can be served in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Format {
+    Prometheus,
+    Json,
+    Html,
+}
+
+impl Format {
+    /// Every format, in order of preference when a client accepts several equally
+    const ALL: [Format; 3] = [Format::Prometheus, Format::Json, Format::Html];
+
+    fn content_type(self) -> &'static str {
+        match self {
+            Format::Prometheus => "text/plain; version=0.0.4; charset=utf-8",
+            Format::Json => "application/json",
+            Format::Html => "text/html; charset=utf-8",
+        }
+    }
+
+    fn media_type(self) -> (&'static str, &'static str) {
+        match self {
+            Format::Prometheus => ("text", "plain"),
+            Format::Json => ("application", "json"),
+            Format::Html => ("text", "html"),
+        }
+    }
+
+    /// Pick the format to answer with given the `Accept` header of a request, or `None` if the
+    /// client accepts none of them.
+    ///
+    /// Each format gets the quality of the most specific media range matching it, and the one with
+    /// the highest quality wins.
+    fn negotiate(accept: Option<&str>) -> Option<Self> {
+        let accept = match accept {
+            Some(accept) if !accept.trim().is_empty() => accept,
+            _ => return Some(Format::Prometheus),
+        };
+
+        let mut ranges = Vec::new();
+        for range in accept.split(',') {
+            let mut params = range.split(';');
+            let (r#type, subtype) = match params.next().unwrap_or_default().trim().split_once('/') {
+                Some(media_type) => media_type,
+                None => continue,
+            };
+            let quality = params
+                .filter_map(|param| param.trim().strip_prefix("q="))
+                .find_map(|q| q.trim().parse::<f32>().ok())
+                .unwrap_or(1.0);
+            ranges.push((r#type.trim(), subtype.trim(), quality));
+        }
+
+        let mut best = None;
+        for format in Self::ALL {
+            let (r#type, subtype) = format.media_type();
+            let quality = ranges
+                .iter()
+                .filter_map(|&(range_type, range_subtype, quality)| {
+                    let specificity = match (range_type, range_subtype) {
+                        ("*", "*") => 0,
+                        (t, "*") if t.eq_ignore_ascii_case(r#type) => 1,
+                        (t, s)
+                            if t.eq_ignore_ascii_case(r#type)
+                                && s.eq_ignore_ascii_case(subtype) =>
+                        {
+                            2
+                        }
+                        _ => return None,
+                    };
+                    Some((specificity, quality))
+                })
+                .max_by_key(|&(specificity, _)| specificity)
+                .map_or(0.0, |(_, quality)| quality);
+            if quality > 0.0 && best.map_or(true, |(_, best)| quality > best) {
+                best = Some((format, quality));
+            }
+        }
+        best.map(|(format, _)| format)
+    }
+}
+
+/// Render `info` as JSON, with the [`BuildInfo`](crate::BuildInfo) added under `build_info`
+fn json(info: &crate::info::Malloc) -> serde_json::Result<String> {
+    let mut json = serde_json::to_value(info)?;
+    if let Some(object) = json.as_object_mut() {
+        object.insert(
+            "build_info".into(),
+            serde_json::to_value(crate::build_info())?,
+        );
+    }
+    serde_json::to_string(&json)
+}
+
+/// Render `info` as a minimal HTML page
+fn html(info: &crate::info::Malloc) -> String {
+    use std::fmt::Write as _;
+
+    let mut out = String::from(
+        "<!DOCTYPE html>\n<html>\n<head><title>malloc_info</title></head>\n<body>\n\
+         <h1>malloc_info</h1>\n",
+    );
+    let build_info = crate::build_info();
+    let _ = writeln!(
+        out,
+        "<p>{} arenas, glibc {}, {} allocator, malloc-info {}</p>",
+        info.heaps.len(),
+        build_info.glibc_version,
+        build_info.allocator.as_str(),
+        build_info.crate_version
+    );
+    out.push_str("<table>\n<tr><th>Entry</th><th>Type</th><th>Count</th><th>Bytes</th></tr>\n");
+    let rows = info
+        .total
+        .iter()
+        .map(|total| {
+            (
+                "total",
+                total.r#type.as_str(),
+                Some(total.count),
+                total.size,
+            )
+        })
+        .chain(
+            info.system
+                .iter()
+                .map(|system| ("system", system.r#type.as_str(), None, system.size)),
+        )
+        .chain(
+            info.aspace
+                .iter()
+                .map(|aspace| ("aspace", aspace.r#type.as_str(), None, aspace.size)),
+        );
+    for (entry, r#type, count, size) in rows {
+        let count = count.map(|count| count.to_string()).unwrap_or_default();
+        let _ = writeln!(
+            out,
+            "<tr><td>{entry}</td><td>{}</td><td>{count}</td><td>{size}</td></tr>",
+            r#type
+        );
+    }
+    out.push_str("</table>\n</body>\n</html>\n");
+    out
+}
+
+/// A response to send back to a client
+struct Response {
+    status: &'static str,
+    content_type: &'static str,
+    headers: Vec<(&'static str, &'static str)>,
+    body: String,
+}
+
+impl Response {
+    fn ok(content_type: &'static str, body: String) -> Self {
+        Self {
+            status: "200 OK",
+            content_type,
+            headers: Vec::new(),
+            body,
+        }
+    }
+
+    fn error(status: &'static str, body: impl Into<String>) -> Self {
+        Self {
+            status,
+            content_type: "text/plain; charset=utf-8",
+            headers: Vec::new(),
+            body: body.into(),
+        }
+    }
+
+    fn header(mut self, name: &'static str, value: &'static str) -> Self {
+        self.headers.push((name, value));
+        self
+    }
+}
+
+/// State of the server, shared by the threads serving connections
+struct Server {
+    config: Config,
+    #[cfg(feature = "exporter-tls")]
+    tls: Option<Arc<rustls::ServerConfig>>,
+    last_capture: Mutex<Option<Instant>>,
+}
+
+impl Server {
+    /// When the last successful capture was taken, if there was one
+    fn last_capture(&self) -> Option<Instant> {
+        *self
+            .last_capture
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Record a successful capture taken at `at`
+    fn set_last_capture(&self, at: Instant) {
+        *self
+            .last_capture
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) = Some(at);
+    }
+
+    /// Serve a single request on a newly accepted connection
+    fn accept(&self, mut stream: TcpStream) -> io::Result<()> {
+        stream.set_read_timeout(Some(CLIENT_TIMEOUT))?;
+        stream.set_write_timeout(Some(CLIENT_TIMEOUT))?;
+
+        #[cfg(feature = "exporter-tls")]
+        if let Some(tls) = &self.tls {
+            let connection = rustls::ServerConnection::new(Arc::clone(tls)).map_err(tls_error)?;
+            let mut stream = rustls::StreamOwned::new(connection, stream);
+            self.handle(&mut stream)?;
+            stream.conn.send_close_notify();
+            return stream.flush();
+        }
+
+        self.handle(&mut stream)
+    }
+
+    /// Serve a single request on `stream`
+    fn handle(&self, stream: &mut (impl Read + Write)) -> io::Result<()> {
+        let response = match Request::read(&mut BufReader::new(&mut *stream))? {
+            Ok(request) => self.respond(&request),
+            Err(response) => response,
+        };
+
+        write!(
+            stream,
+            "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n",
+            response.status,
+            response.content_type,
+            response.body.len()
+        )?;
+        for (name, value) in &response.headers {
+            write!(stream, "{name}: {value}\r\n")?;
+        }
+        stream.write_all(b"\r\n")?;
+        stream.write_all(response.body.as_bytes())?;
+        stream.flush()
+    }
+
+    /// Build the response to `request`
+    fn respond(&self, request: &Request) -> Response {
+        if request.path == "/healthz" {
+            return self.health(request);
+        }
+        if let Some(token) = &self.config.token {
+            if !request.authorized(token) {
+                return Response::error("401 Unauthorized", "unauthorized\n")
+                    .header("WWW-Authenticate", "Bearer");
+            }
+        }
+
+        let format = match request.path.as_str() {
+            "/metrics" => Some(Format::Prometheus),
+            "/json" => Some(Format::Json),
+            "/malloc" => Format::negotiate(request.header("Accept")),
+            _ => return Response::error("404 Not Found", "not found\n"),
+        };
+        if request.method != "GET" {
+            return Response::error("405 Method Not Allowed", "method not allowed\n");
+        }
+        let vary = request.path == "/malloc";
+        let format = match format {
+            Some(format) => format,
+            None => {
+                return Response::error("406 Not Acceptable", "not acceptable\n")
+                    .header("Vary", "Accept")
+            }
+        };
+
+        let info = match crate::malloc_info() {
+            Ok(info) => info,
+            Err(e) => return Response::error("500 Internal Server Error", format!("{e}\n")),
+        };
+        self.set_last_capture(Instant::now());
+        let response = match format {
+            Format::Prometheus => {
+                let labels = self
+                    .config
+                    .labels
+                    .iter()
+                    .map(|(name, value)| (name.as_str(), value.as_str()))
+                    .collect::<Vec<_>>();
+                match prometheus::render_with(&info, &labels, &self.config.semantics) {
+                    Ok(text) => Response::ok(format.content_type(), text),
+                    Err(e) => Response::error("500 Internal Server Error", format!("{e}\n")),
+                }
+            }
+            Format::Json => match json(&info) {
+                Ok(json) => Response::ok(format.content_type(), json),
+                Err(e) => Response::error("500 Internal Server Error", format!("{e}\n")),
+            },
+            Format::Html => Response::ok(format.content_type(), html(&info)),
+        };
+        if vary {
+            response.header("Vary", "Accept")
+        } else {
+            response
+        }
+    }
+
+    /// Build the response to a `/healthz` request
+    fn health(&self, request: &Request) -> Response {
+        if request.method != "GET" {
+            return Response::error("405 Method Not Allowed", "method not allowed\n");
+        }
+
+        let start = Instant::now();
+        let capture = crate::malloc_info_summary().map(|_| start.elapsed());
+        self.health_of(capture)
+    }
+
+    /// Build the response to a `/healthz` request whose capture took `capture`, or failed
+    fn health_of(&self, capture: Result<Duration, crate::Error>) -> Response {
+        if capture.is_ok() {
+            self.set_last_capture(Instant::now());
+        }
+        let age = self.last_capture().map(|at| at.elapsed());
+        let (status, healthy) = match (&capture, self.config.max_capture_age, age) {
+            (Ok(_), _, _) => ("ok", true),
+            (Err(_), Some(max), Some(age)) if age <= max => ("degraded", true),
+            (Err(_), _, _) => ("failing", false),
+        };
+        let body = serde_json::json!({
+            "status": status,
+            "capture_seconds": capture.as_ref().ok().map(Duration::as_secs_f64),
+            "error": capture.as_ref().err().map(ToString::to_string),
+            "last_capture_age_seconds": age.map(|age| age.as_secs_f64()),
+        });
+        Response {
+            status: if healthy {
+                "200 OK"
+            } else {
+                "503 Service Unavailable"
+            },
+            ..Response::ok("application/json", body.to_string())
+        }
+    }
+}
+
+/// Read a line from `reader`, reading no more than `remaining` bytes and deducting what was read.
+/// Returns `None` if the limit is reached before the end of the line, and an empty line at the end
+/// of the stream.
+fn read_line(reader: &mut impl BufRead, remaining: &mut u64) -> io::Result<Option<String>> {
+    let mut line = String::new();
+    let read = reader.by_ref().take(*remaining).read_line(&mut line)?;
+    *remaining -= read as u64;
+    if *remaining == 0 && !line.ends_with('\n') {
+        return Ok(None);
+    }
+    Ok(Some(line))
+}
+
+/// Compare `a` and `b` in time depending only on their lengths, so as not to leak how much of a
+/// secret an attacker has guessed right
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+/// Load the certificate chain and private key named in `tls`
+#[cfg(feature = "exporter-tls")]
+fn tls_config(tls: &TlsConfig) -> io::Result<Arc<rustls::ServerConfig>> {
+    use rustls::pki_types::pem::{self, PemObject};
+    use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+
+    let pem_error =
+        |path: &PathBuf, e: pem::Error| invalid_input(format!("{}: {e}", path.display()));
+    let certs = CertificateDer::pem_file_iter(&tls.cert)
+        .and_then(Iterator::collect::<Result<Vec<_>, _>>)
+        .map_err(|e| pem_error(&tls.cert, e))?;
+    let key = PrivateKeyDer::from_pem_file(&tls.key).map_err(|e| pem_error(&tls.key, e))?;
+
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let config = rustls::ServerConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .map_err(tls_error)?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(tls_error)?;
+    Ok(Arc::new(config))
+}
+
+#[cfg(feature = "exporter-tls")]
+fn tls_error(e: rustls::Error) -> io::Error {
+    invalid_input(format!("TLS: {e}"))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Send `request` to `addr` and return the status line and body of the response
+    fn request(addr: SocketAddr, request: &str) -> (String, String) {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(request.as_bytes()).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        let status = head.lines().next().unwrap().to_owned();
+        (status, body.to_owned())
+    }
+
+    fn config() -> Config {
+        Config {
+            addr: "127.0.0.1:0".parse().unwrap(),
+            labels: vec![("service".into(), "test".into())],
+            ..Config::default()
+        }
+    }
+
+    fn start() -> Exporter {
+        serve_exporter(config()).expect("serve")
+    }
+
+    #[test]
+    fn metrics() {
+        let exporter = start();
+        let (status, body) = request(exporter.local_addr(), "GET /metrics HTTP/1.1\r\n\r\n");
+        assert_eq!(status, "HTTP/1.1 200 OK");
+        assert!(body.contains("\nmalloc_info_arenas{service=\"test\"} "));
+        exporter.shutdown();
+    }
+
+    #[test]
+    fn invalid_labels() {
+        for name in ["pod-name", "type", "glibc_version"] {
+            let mut config = config();
+            config.labels.push((name.into(), "x".into()));
+            let error = serve_exporter(config).expect_err(name);
+            assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+        }
+    }
+
+    #[test]
+    fn json() {
+        let exporter = start();
+        let (status, body) = request(
+            exporter.local_addr(),
+            "GET /json?pretty HTTP/1.1\r\nHost: x\r\n\r\n",
+        );
+        assert_eq!(status, "HTTP/1.1 200 OK");
+        let json: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert!(json["heaps"].is_array());
+        assert_eq!(
+            json["build_info"]["glibc_version"],
+            crate::build_info().glibc_version
+        );
+        exporter.shutdown();
+    }
+
+    #[test]
+    fn errors() {
+        let exporter = start();
+        let addr = exporter.local_addr();
+        assert!(request(addr, "GET /nope HTTP/1.1\r\n\r\n")
+            .0
+            .contains(" 404 "));
+        assert!(request(addr, "POST /metrics HTTP/1.1\r\n\r\n")
+            .0
+            .contains(" 405 "));
+        assert!(request(addr, "nonsense\r\n\r\n").0.contains(" 400 "));
+        exporter.shutdown();
+    }
+
+    #[test]
+    fn slow_client() {
+        let exporter = start();
+        let addr = exporter.local_addr();
+
+        // A client trickling its request in, which keeps its connection open
+        let mut slow = TcpStream::connect(addr).unwrap();
+        slow.write_all(b"GET /metrics HTTP/1.1\r\n").unwrap();
+        thread::sleep(Duration::from_millis(50));
+
+        let (status, _) = request(addr, "GET /healthz HTTP/1.1\r\n\r\n");
+        assert_eq!(status, "HTTP/1.1 200 OK");
+        let (status, _) = request(addr, "GET /metrics HTTP/1.1\r\n\r\n");
+        assert_eq!(status, "HTTP/1.1 200 OK");
+
+        slow.write_all(b"\r\n").unwrap();
+        let mut response = String::new();
+        slow.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        exporter.shutdown();
+    }
+
+    #[test]
+    fn oversized_header() {
+        let exporter = start();
+        // A header line without an end, exactly filling the limit so that the server reads all of
+        // it before answering
+        let mut head = String::from("GET /metrics HTTP/1.1\r\nX-Padding: ");
+        head.push_str(&"a".repeat(MAX_REQUEST - head.len()));
+        let (status, _) = request(exporter.local_addr(), &head);
+        assert_eq!(status, "HTTP/1.1 431 Request Header Fields Too Large");
+        exporter.shutdown();
+    }
+
+    #[test]
+    fn negotiate() {
+        use Format::*;
+
+        assert_eq!(Format::negotiate(None), Some(Prometheus));
+        assert_eq!(Format::negotiate(Some("*/*")), Some(Prometheus));
+        assert_eq!(Format::negotiate(Some("application/json")), Some(Json));
+        assert_eq!(
+            Format::negotiate(Some(
+                "text/html,application/xhtml+xml,application/xml;q=0.9,*/*;q=0.8"
+            )),
+            Some(Html)
+        );
+        assert_eq!(
+            Format::negotiate(Some(
+                "application/openmetrics-text;version=1.0.0;q=0.6,\
+                 text/plain;version=0.0.4;q=0.5,*/*;q=0.1"
+            )),
+            Some(Prometheus)
+        );
+        assert_eq!(
+            Format::negotiate(Some("text/*;q=0.5, application/json")),
+            Some(Json)
+        );
+        assert_eq!(
+            Format::negotiate(Some("text/plain;q=0, text/*")),
+            Some(Html)
+        );
+        assert_eq!(Format::negotiate(Some("image/png")), None);
+    }
+
+    #[test]
+    fn malloc() {
+        let exporter = start();
+        let addr = exporter.local_addr();
+
+        let (status, body) = request(
+            addr,
+            "GET /malloc HTTP/1.1\r\nAccept: application/json\r\n\r\n",
+        );
+        assert_eq!(status, "HTTP/1.1 200 OK");
+        assert!(serde_json::from_str::<serde_json::Value>(&body).is_ok());
+
+        let (_, body) = request(addr, "GET /malloc HTTP/1.1\r\nAccept: text/html\r\n\r\n");
+        assert!(body.starts_with("<!DOCTYPE html>"));
+        assert!(body.contains(" allocator, malloc-info "));
+        assert!(body.contains("<tr><td>system</td><td>current</td><td></td><td>"));
+
+        let (_, body) = request(addr, "GET /malloc HTTP/1.1\r\n\r\n");
+        assert!(body.starts_with("# HELP "));
+
+        let (status, _) = request(addr, "GET /malloc HTTP/1.1\r\nAccept: image/png\r\n\r\n");
+        assert_eq!(status, "HTTP/1.1 406 Not Acceptable");
+        exporter.shutdown();
+    }
+
+    #[test]
+    fn token() {
+        let exporter = serve_exporter(Config {
+            token: Some("secret".into()),
+            ..config()
+        })
+        .expect("serve");
+        let addr = exporter.local_addr();
+
+        let (status, _) = request(addr, "GET /metrics HTTP/1.1\r\n\r\n");
+        assert_eq!(status, "HTTP/1.1 401 Unauthorized");
+        let (status, _) = request(
+            addr,
+            "GET /metrics HTTP/1.1\r\nAuthorization: Bearer guess\r\n\r\n",
+        );
+        assert_eq!(status, "HTTP/1.1 401 Unauthorized");
+        let (status, _) = request(
+            addr,
+            "GET /metrics HTTP/1.1\r\nauthorization: bearer secret\r\n\r\n",
+        );
+        assert_eq!(status, "HTTP/1.1 200 OK");
+        exporter.shutdown();
+    }
+
+    #[test]
+    fn health() {
+        let exporter = serve_exporter(Config {
+            token: Some("secret".into()),
+            ..config()
+        })
+        .expect("serve");
+
+        // Answered without a token, and without any scrape
+        let (status, body) = request(exporter.local_addr(), "GET /healthz HTTP/1.1\r\n\r\n");
+        assert_eq!(status, "HTTP/1.1 200 OK");
+        let json: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(json["status"], "ok");
+        assert!(json["capture_seconds"].as_f64().unwrap() >= 0.0);
+        assert!(json["error"].is_null());
+        assert!(json["last_capture_age_seconds"].as_f64().unwrap() < 3600.0);
+        exporter.shutdown();
+    }
+
+    #[test]
+    fn health_failing() {
+        let server = |max_capture_age| Server {
+            config: Config {
+                max_capture_age,
+                ..config()
+            },
+            #[cfg(feature = "exporter-tls")]
+            tls: None,
+            last_capture: Mutex::new(None),
+        };
+        let failed = || Err(crate::ErrorRepr::Stats("wedged".into()).into());
+        let json = |response: &Response| -> serde_json::Value {
+            serde_json::from_str(&response.body).unwrap()
+        };
+
+        let server = server(Some(Duration::from_secs(3600)));
+        let response = server.health_of(failed());
+        assert_eq!(response.status, "503 Service Unavailable");
+        assert_eq!(json(&response)["status"], "failing");
+        assert!(json(&response)["last_capture_age_seconds"].is_null());
+
+        server.health_of(Ok(Duration::from_millis(1)));
+        let response = server.health_of(failed());
+        assert_eq!(response.status, "200 OK");
+        assert_eq!(json(&response)["status"], "degraded");
+        assert!(json(&response)["error"]
+            .as_str()
+            .unwrap()
+            .contains("wedged"));
+
+        server.set_last_capture(Instant::now() - Duration::from_secs(7200));
+        assert_eq!(server.health_of(failed()).status, "503 Service Unavailable");
+    }
+
+    #[cfg(feature = "exporter-tls")]
+    #[test]
+    fn tls() {
+        use rustls::pki_types::{pem::PemObject, CertificateDer};
+
+        let testdata = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("testdata");
+        let exporter = serve_exporter(Config {
+            tls: Some(TlsConfig {
+                cert: testdata.join("cert.pem"),
+                key: testdata.join("key.pem"),
+            }),
+            ..config()
+        })
+        .expect("serve");
+
+        let mut roots = rustls::RootCertStore::empty();
+        roots
+            .add(CertificateDer::from_pem_file(testdata.join("ca.pem")).unwrap())
+            .unwrap();
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let client = rustls::ClientConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let connection =
+            rustls::ClientConnection::new(Arc::new(client), "localhost".try_into().unwrap())
+                .unwrap();
+        let tcp = TcpStream::connect(exporter.local_addr()).unwrap();
+        let mut stream = rustls::StreamOwned::new(connection, tcp);
+        stream.write_all(b"GET /metrics HTTP/1.1\r\n\r\n").unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+
+        // Plaintext requests are refused
+        let mut plain = TcpStream::connect(exporter.local_addr()).unwrap();
+        plain.write_all(b"GET /metrics HTTP/1.1\r\n\r\n").unwrap();
+        let mut response = Vec::new();
+        let _ = plain.read_to_end(&mut response);
+        assert!(!response.starts_with(b"HTTP/1.1 200"));
+
+        exporter.shutdown();
+    }
+
+    #[test]
+    fn default_config() {
+        assert_eq!(Config::default().addr.port(), 9464);
+    }
+}
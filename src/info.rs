@@ -3,10 +3,18 @@
 //! A best effort was made to account for all edge cases in the XML output of `malloc_info`, but
 //! there may be some cases that are not accounted for. If you find one, please open an issue.
 
+use crate::{Error, ErrorRepr};
 use serde::Deserialize;
+#[cfg(feature = "serialize")]
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::ops::{Deref, RangeInclusive};
+use std::slice;
+use std::str::FromStr;
 
 /// Types of arena space
-#[derive(Deserialize, Debug, PartialEq, Eq)]
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serialize", derive(Serialize))]
 #[serde(rename_all = "kebab-case")]
 pub enum AspaceType {
     Total,
@@ -16,18 +24,33 @@ pub enum AspaceType {
     Other,
 }
 
+impl AspaceType {
+    /// Name of the type, as in the `type` attribute of the XML output. Unknown types are named
+    /// `other`.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AspaceType::Total => "total",
+            AspaceType::Mprotect => "mprotect",
+            AspaceType::Subheaps => "subheaps",
+            AspaceType::Other => "other",
+        }
+    }
+}
+
 /// Arena space information
-#[derive(Deserialize, Debug, PartialEq, Eq)]
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serialize", derive(Serialize))]
 #[serde(rename_all = "kebab-case")]
 pub struct Aspace {
-    #[serde(rename = "@type")]
+    #[serde(alias = "@type")]
     pub r#type: AspaceType,
-    #[serde(rename = "@size")]
-    pub size: usize,
+    #[serde(alias = "@size")]
+    pub size: u64,
 }
 
 /// Types of system memory
-#[derive(Deserialize, Debug, PartialEq, Eq)]
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serialize", derive(Serialize))]
 #[serde(rename_all = "kebab-case")]
 pub enum SystemType {
     Current,
@@ -36,18 +59,32 @@ pub enum SystemType {
     Other,
 }
 
+impl SystemType {
+    /// Name of the type, as in the `type` attribute of the XML output. Unknown types are named
+    /// `other`.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SystemType::Current => "current",
+            SystemType::Max => "max",
+            SystemType::Other => "other",
+        }
+    }
+}
+
 /// System memory information
-#[derive(Deserialize, Debug, PartialEq, Eq)]
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serialize", derive(Serialize))]
 #[serde(rename_all = "kebab-case")]
 pub struct System {
-    #[serde(rename = "@type")]
+    #[serde(alias = "@type")]
     pub r#type: SystemType,
-    #[serde(rename = "@size")]
-    pub size: usize,
+    #[serde(alias = "@size")]
+    pub size: u64,
 }
 
 /// Types of total memory
-#[derive(Deserialize, Debug, PartialEq, Eq)]
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serialize", derive(Serialize))]
 #[serde(rename_all = "kebab-case")]
 pub enum TotalType {
     Fast,
@@ -57,77 +94,642 @@ pub enum TotalType {
     Other,
 }
 
+impl TotalType {
+    /// Name of the type, as in the `type` attribute of the XML output. Unknown types are named
+    /// `other`.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TotalType::Fast => "fast",
+            TotalType::Rest => "rest",
+            TotalType::Mmap => "mmap",
+            TotalType::Other => "other",
+        }
+    }
+}
+
 /// Total memory information
-#[derive(Deserialize, Debug, PartialEq, Eq)]
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serialize", derive(Serialize))]
 #[serde(rename_all = "kebab-case")]
 pub struct Total {
-    #[serde(rename = "@type")]
+    #[serde(alias = "@type")]
     pub r#type: TotalType,
-    #[serde(rename = "@count")]
-    pub count: usize,
-    #[serde(rename = "@size")]
-    pub size: usize,
+    #[serde(alias = "@count")]
+    pub count: u64,
+    #[serde(alias = "@size")]
+    pub size: u64,
+}
+
+/// Kinds of size bins
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serialize", derive(Serialize))]
+#[serde(rename_all = "kebab-case")]
+pub enum SizeKind {
+    /// A regular bin, `<size>` in the XML output
+    #[default]
+    Size,
+
+    /// The unsorted bin, `<unsorted>` in the XML output
+    Unsorted,
+}
+
+/// A free chunk size bin of an arena.
+///
+/// Bins order by kind, with regular bins before the unsorted one, then by range.
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serialize", derive(Serialize), serde(into = "RawSize"))]
+#[serde(from = "RawSize")]
+pub struct Size {
+    /// Kind of the bin
+    pub kind: SizeKind,
+
+    /// Smallest chunk size the bin holds
+    pub from: u64,
+
+    /// Largest chunk size the bin holds
+    pub to: u64,
+
+    /// Bytes in the bin's free chunks
+    pub total: u64,
+
+    /// Number of free chunks in the bin
+    pub count: u64,
+}
+
+impl Size {
+    /// Range of chunk sizes the bin holds
+    pub fn range(&self) -> RangeInclusive<u64> {
+        self.from..=self.to
+    }
+
+    /// Number of distinct chunk sizes the bin holds
+    pub fn width(&self) -> u64 {
+        self.to.saturating_sub(self.from).saturating_add(1)
+    }
+
+    /// Whether this is the unsorted bin
+    pub fn is_unsorted(&self) -> bool {
+        self.kind == SizeKind::Unsorted
+    }
+
+    /// Average size of the bin's free chunks, or `None` if it has none
+    pub fn average_chunk_size(&self) -> Option<f64> {
+        (self.count > 0).then(|| self.total as f64 / self.count as f64)
+    }
 }
 
-/// Size information for an arena or the whole heap
-#[derive(Deserialize, Debug, PartialEq, Eq)]
+/// Representation of a [`Size`] in the XML output, where the kind of a bin is the name of its
+/// element
+#[derive(Deserialize)]
+#[cfg_attr(feature = "serialize", derive(Serialize))]
 #[serde(rename_all = "kebab-case")]
-pub enum Size {
-    Size {
-        #[serde(rename = "@from")]
-        from: usize,
-        #[serde(rename = "@to")]
-        to: usize,
-        #[serde(rename = "@total")]
-        total: usize,
-        #[serde(rename = "@count")]
-        count: usize,
-    },
-    Unsorted {
-        #[serde(rename = "@from")]
-        from: usize,
-        #[serde(rename = "@to")]
-        to: usize,
-        #[serde(rename = "@total")]
-        total: usize,
-        #[serde(rename = "@count")]
-        count: usize,
-    },
+enum RawSize {
+    Size(RawBin),
+    Unsorted(RawBin),
+}
+
+#[derive(Deserialize)]
+#[cfg_attr(feature = "serialize", derive(Serialize))]
+struct RawBin {
+    #[serde(alias = "@from")]
+    from: u64,
+    #[serde(alias = "@to")]
+    to: u64,
+    #[serde(alias = "@total")]
+    total: u64,
+    #[serde(alias = "@count")]
+    count: u64,
+}
+
+impl From<RawSize> for Size {
+    fn from(raw: RawSize) -> Self {
+        let (kind, bin) = match raw {
+            RawSize::Size(bin) => (SizeKind::Size, bin),
+            RawSize::Unsorted(bin) => (SizeKind::Unsorted, bin),
+        };
+        Size {
+            kind,
+            from: bin.from,
+            to: bin.to,
+            total: bin.total,
+            count: bin.count,
+        }
+    }
+}
+
+#[cfg(feature = "serialize")]
+impl From<Size> for RawSize {
+    fn from(size: Size) -> Self {
+        let bin = RawBin {
+            from: size.from,
+            to: size.to,
+            total: size.total,
+            count: size.count,
+        };
+        match size.kind {
+            SizeKind::Size => RawSize::Size(bin),
+            SizeKind::Unsorted => RawSize::Unsorted(bin),
+        }
+    }
 }
 
 /// Wrapper type for sizes, which may be an array of XML elements
-#[derive(Deserialize, Debug, PartialEq, Eq)]
+#[derive(Deserialize, Debug, Default, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serialize", derive(Serialize))]
 #[serde(rename_all = "kebab-case")]
 pub struct Sizes {
-    #[serde(rename = "$value")]
+    #[serde(alias = "$value")]
     pub sizes: Option<Vec<Size>>,
 }
 
-/// Arena-specific heap information
-#[derive(Deserialize, Debug, PartialEq, Eq)]
+impl Sizes {
+    /// Bins holding at least one free chunk
+    fn free_bins(&self) -> impl Iterator<Item = &Size> {
+        self.iter().filter(|size| size.count > 0)
+    }
+
+    /// Bytes in the free chunks of every bin. Returns `None` on overflow.
+    pub fn checked_total(&self) -> Option<u64> {
+        self.iter()
+            .try_fold(0u64, |sum, size| sum.checked_add(size.total))
+    }
+
+    /// Bytes in the free chunks of every bin, saturating at `u64::MAX` instead of overflowing
+    pub fn saturating_total(&self) -> u64 {
+        self.iter()
+            .fold(0, |sum, size| sum.saturating_add(size.total))
+    }
+
+    /// Number of free chunks in every bin. Returns `None` on overflow.
+    pub fn checked_count(&self) -> Option<u64> {
+        self.iter()
+            .try_fold(0u64, |sum, size| sum.checked_add(size.count))
+    }
+
+    /// Number of free chunks in every bin, saturating at `u64::MAX` instead of overflowing
+    pub fn saturating_count(&self) -> u64 {
+        self.iter()
+            .fold(0, |sum, size| sum.saturating_add(size.count))
+    }
+}
+
+impl Deref for Sizes {
+    type Target = [Size];
+
+    /// The bins, empty if there are none
+    fn deref(&self) -> &[Size] {
+        self.sizes.as_deref().unwrap_or_default()
+    }
+}
+
+impl<'a> IntoIterator for &'a Sizes {
+    type Item = &'a Size;
+    type IntoIter = slice::Iter<'a, Size>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+/// Arena-specific heap information.
+///
+/// Arenas order by number first, so a sorted slice of them can be searched by number with
+/// [`binary_search_by_key`](slice::binary_search_by_key).
+#[derive(Deserialize, Debug, Default, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serialize", derive(Serialize))]
 #[serde(rename_all = "kebab-case")]
 pub struct Heap {
     /// Arena number
-    #[serde(rename = "@nr")]
+    #[serde(alias = "@nr")]
     pub nr: usize,
 
     /// Arena sizes
     pub sizes: Option<Sizes>,
+
+    /// Totals of the arena's free chunks
+    #[serde(default)]
+    pub total: Vec<Total>,
+
+    /// Memory the arena obtained from the system
+    #[serde(default)]
+    pub system: Vec<System>,
+
+    /// Address space used by the arena
+    #[serde(default)]
+    pub aspace: Vec<Aspace>,
+}
+
+impl Heap {
+    /// The arena's total of type `type`, if it reported one
+    pub fn total_by(&self, r#type: TotalType) -> Option<&Total> {
+        self.total.iter().find(|total| total.r#type == r#type)
+    }
+
+    /// Size of the arena's total of type `type`, if it reported one
+    pub fn total_size(&self, r#type: TotalType) -> Option<u64> {
+        self.total_by(r#type).map(|total| total.size)
+    }
+
+    /// The arena's system memory entry of type `type`, if it reported one
+    pub fn system_by(&self, r#type: SystemType) -> Option<&System> {
+        self.system.iter().find(|system| system.r#type == r#type)
+    }
+
+    /// Size of the arena's system memory entry of type `type`, if it reported one
+    pub fn system_size(&self, r#type: SystemType) -> Option<u64> {
+        self.system_by(r#type).map(|system| system.size)
+    }
+
+    /// The arena's address space entry of type `type`, if it reported one
+    pub fn aspace_by(&self, r#type: AspaceType) -> Option<&Aspace> {
+        self.aspace.iter().find(|aspace| aspace.r#type == r#type)
+    }
+
+    /// Size of the arena's address space entry of type `type`, if it reported one
+    pub fn aspace_size(&self, r#type: AspaceType) -> Option<u64> {
+        self.aspace_by(r#type).map(|aspace| aspace.size)
+    }
+
+    /// Address space of the arena, if it reported it
+    pub fn aspace_total(&self) -> Option<u64> {
+        self.aspace_size(AspaceType::Total)
+    }
+
+    /// Address space of the arena made accessible with `mprotect`, if it reported it
+    pub fn aspace_mprotect(&self) -> Option<u64> {
+        self.aspace_size(AspaceType::Mprotect)
+    }
+
+    /// Number of sub-heaps making up the arena, if it reported it. Only newer glibc versions do,
+    /// and only for arenas other than the main one.
+    pub fn aspace_subheaps(&self) -> Option<u64> {
+        self.aspace_size(AspaceType::Subheaps)
+    }
+
+    /// Bytes in the free chunks of the arena's size bins, unsorted bin included. Zero if the
+    /// sizes weren't parsed. Saturates at `u64::MAX`, see [`Sizes::checked_total`] to detect
+    /// overflow instead.
+    pub fn free_bytes(&self) -> u64 {
+        self.sizes.as_ref().map_or(0, Sizes::saturating_total)
+    }
+
+    /// Number of free chunks in the arena's size bins, unsorted bin included. Zero if the sizes
+    /// weren't parsed. Saturates at `u64::MAX`, see [`Sizes::checked_count`] to detect overflow
+    /// instead.
+    pub fn free_chunk_count(&self) -> u64 {
+        self.sizes.as_ref().map_or(0, Sizes::saturating_count)
+    }
+
+    /// The non-empty bin holding the largest chunks, by the upper end of its range, or `None` if
+    /// no bin holds any chunk. An allocation larger than its range can't be served from the
+    /// arena's free chunks.
+    pub fn largest_free_bin(&self) -> Option<&Size> {
+        self.free_bins().max_by_key(|size| size.to)
+    }
+
+    /// The non-empty bin holding the smallest chunks, by the lower end of its range, or `None` if
+    /// no bin holds any chunk
+    pub fn smallest_free_bin(&self) -> Option<&Size> {
+        self.free_bins().min_by_key(|size| size.from)
+    }
+
+    fn free_bins(&self) -> impl Iterator<Item = &Size> {
+        self.sizes.iter().flat_map(Sizes::free_bins)
+    }
+
+    /// How much of the arena's address space is in use, or `None` if the arena didn't report its
+    /// total address space.
+    ///
+    /// Free bytes are those in the arena's fast and rest free chunks, and everything else in its
+    /// `total` address space counts as in use.
+    pub fn utilization(&self) -> Option<Utilization> {
+        let aspace = self.aspace_total()?;
+        let free = self
+            .total
+            .iter()
+            .filter(|total| matches!(total.r#type, TotalType::Fast | TotalType::Rest))
+            .fold(0u64, |free, total| free.saturating_add(total.size));
+        let in_use = aspace.saturating_sub(free);
+        Some(Utilization {
+            in_use,
+            free,
+            ratio: if aspace == 0 {
+                0.0
+            } else {
+                in_use as f64 / aspace as f64
+            },
+        })
+    }
+
+    /// Bring the arena into a canonical form, see [`Malloc::canonicalize`]
+    pub fn canonicalize(&mut self) {
+        if let Some(sizes) = self.sizes.as_mut().and_then(|sizes| sizes.sizes.as_mut()) {
+            sizes.sort_by_key(|size| (size.kind, size.from, size.to));
+        }
+        canonicalize_totals(&mut self.total);
+        canonicalize_systems(&mut self.system);
+        canonicalize_aspaces(&mut self.aspace);
+    }
+}
+
+/// Utilization of an arena, see [`Heap::utilization`]
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serialize", derive(Serialize))]
+pub struct Utilization {
+    /// Bytes of the arena's address space in use
+    pub in_use: u64,
+
+    /// Bytes held in the arena's free chunks
+    pub free: u64,
+
+    /// Fraction of the arena's address space in use, between 0 and 1. Zero for an empty arena.
+    pub ratio: f64,
 }
 
 /// Top-level type for all stats returned from [`malloc_info`](crate::malloc_info)
-#[derive(Deserialize, Debug, PartialEq, Eq)]
+///
+/// The [`Default`] snapshot is an empty document of the version glibc emits, with no arenas and no
+/// entries.
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serialize", derive(Serialize))]
 #[serde(rename_all = "kebab-case")]
 pub struct Malloc {
-    #[serde(rename = "@version")]
+    #[serde(alias = "@version")]
     pub version: String,
-    #[serde(rename = "heap")]
+    #[serde(alias = "heap")]
     pub heaps: Vec<Heap>,
     pub total: Vec<Total>,
     pub system: Vec<System>,
     pub aspace: Vec<Aspace>,
 }
 
+impl Malloc {
+    /// Parse a snapshot from the XML output of `malloc_info`, such as a dump saved with
+    /// [`malloc_info_xml`](crate::malloc_info_xml) on another machine. The same is available
+    /// through [`str::parse`].
+    pub fn from_xml_str(xml: &str) -> Result<Self, Error> {
+        quick_xml::de::from_str(xml).map_err(|e| ErrorRepr::from(e).into())
+    }
+
+    /// Iterate over the arenas, in the order they appear in the output
+    pub fn iter(&self) -> slice::Iter<'_, Heap> {
+        self.heaps.iter()
+    }
+
+    /// The arena numbered `nr`, if there is one
+    pub fn heap(&self, nr: usize) -> Option<&Heap> {
+        self.heaps.iter().find(|heap| heap.nr == nr)
+    }
+
+    /// Iterate over the arenas in order of their number, whatever order they appear in the output
+    pub fn arenas(&self) -> impl Iterator<Item = &Heap> {
+        let mut heaps: Vec<_> = self.heaps.iter().collect();
+        heaps.sort_by_key(|heap| heap.nr);
+        heaps.into_iter()
+    }
+
+    /// The document-level total of type `type`, if there is one
+    pub fn total_by(&self, r#type: TotalType) -> Option<&Total> {
+        self.total.iter().find(|total| total.r#type == r#type)
+    }
+
+    /// Size of the document-level total of type `type`, if there is one
+    pub fn total_size(&self, r#type: TotalType) -> Option<u64> {
+        self.total_by(r#type).map(|total| total.size)
+    }
+
+    /// The document-level system memory entry of type `type`, if there is one
+    pub fn system_by(&self, r#type: SystemType) -> Option<&System> {
+        self.system.iter().find(|system| system.r#type == r#type)
+    }
+
+    /// Size of the document-level system memory entry of type `type`, if there is one
+    pub fn system_size(&self, r#type: SystemType) -> Option<u64> {
+        self.system_by(r#type).map(|system| system.size)
+    }
+
+    /// Bytes currently obtained from the system across all arenas, or `None` if the output has no
+    /// `<system type="current">` entry. A missing entry is not the same as zero bytes, so it isn't
+    /// reported as one.
+    pub fn system_current(&self) -> Option<u64> {
+        self.system_size(SystemType::Current)
+    }
+
+    /// Maximum bytes ever obtained from the system across all arenas, or `None` if the output has
+    /// no `<system type="max">` entry
+    pub fn system_max(&self) -> Option<u64> {
+        self.system_size(SystemType::Max)
+    }
+
+    /// The document-level address space entry of type `type`, if there is one
+    pub fn aspace_by(&self, r#type: AspaceType) -> Option<&Aspace> {
+        self.aspace.iter().find(|aspace| aspace.r#type == r#type)
+    }
+
+    /// Size of the document-level address space entry of type `type`, if there is one
+    pub fn aspace_size(&self, r#type: AspaceType) -> Option<u64> {
+        self.aspace_by(r#type).map(|aspace| aspace.size)
+    }
+
+    /// The non-empty bin holding the largest chunks across all arenas, see
+    /// [`Heap::largest_free_bin`]
+    pub fn largest_free_bin(&self) -> Option<&Size> {
+        self.heaps
+            .iter()
+            .filter_map(Heap::largest_free_bin)
+            .max_by_key(|size| size.to)
+    }
+
+    /// The non-empty bin holding the smallest chunks across all arenas, see
+    /// [`Heap::smallest_free_bin`]
+    pub fn smallest_free_bin(&self) -> Option<&Size> {
+        self.heaps
+            .iter()
+            .filter_map(Heap::smallest_free_bin)
+            .min_by_key(|size| size.from)
+    }
+
+    /// Address space of all arenas, if the output reports it
+    pub fn aspace_total(&self) -> Option<u64> {
+        self.aspace_size(AspaceType::Total)
+    }
+
+    /// Address space of all arenas made accessible with `mprotect`, if the output reports it
+    pub fn aspace_mprotect(&self) -> Option<u64> {
+        self.aspace_size(AspaceType::Mprotect)
+    }
+
+    /// Number of sub-heaps across all arenas, if the output reports it. Only newer glibc versions
+    /// do.
+    pub fn aspace_subheaps(&self) -> Option<u64> {
+        self.aspace_size(AspaceType::Subheaps)
+    }
+
+    /// Flatten the snapshot into a map from dotted metric names to values, for metrics and
+    /// logging systems without a schema for it. The names are:
+    ///
+    /// - `arenas`: the number of arenas
+    /// - `total.<type>.count` and `total.<type>.size`, such as `total.mmap.size`
+    /// - `system.<type>`, such as `system.current`
+    /// - `aspace.<type>`, such as `aspace.total`
+    /// - The same entries for each arena, prefixed with `heap.<nr>.`, such as
+    ///   `heap.0.system.current`
+    /// - `heap.<nr>.size.<from>-<to>.count` and `heap.<nr>.size.<from>-<to>.total` for each size
+    ///   bin, with `unsorted` in place of `size` for the unsorted bin
+    ///
+    /// Types are named as in the XML output, with unknown ones named `other`. Entries are taken
+    /// from the [canonical form](Malloc::canonicalize) of the snapshot, so entries of the same type
+    /// are merged rather than overwriting each other.
+    pub fn to_metrics(&self) -> BTreeMap<String, u64> {
+        let mut info = self.clone();
+        info.canonicalize();
+
+        let mut metrics = BTreeMap::new();
+        metrics.insert("arenas".to_owned(), info.heaps.len() as u64);
+        insert_entries(&mut metrics, "", &info.total, &info.system, &info.aspace);
+        for heap in &info.heaps {
+            let prefix = format!("heap.{}.", heap.nr);
+            insert_entries(
+                &mut metrics,
+                &prefix,
+                &heap.total,
+                &heap.system,
+                &heap.aspace,
+            );
+            for size in heap.sizes.as_deref().unwrap_or_default() {
+                let kind = match size.kind {
+                    SizeKind::Size => "size",
+                    SizeKind::Unsorted => "unsorted",
+                };
+                let bin = format!("{prefix}{kind}.{}-{}", size.from, size.to);
+                metrics.insert(format!("{bin}.count"), size.count);
+                metrics.insert(format!("{bin}.total"), size.total);
+            }
+        }
+        metrics
+    }
+
+    /// Bring the snapshot into a canonical form, so that snapshots of the same state compare equal
+    /// regardless of the order glibc emitted their elements in.
+    ///
+    /// Arenas are sorted by number, and each arena's size bins by range with the unsorted bin
+    /// last. Total, system and address space entries are sorted by type in declaration order, and
+    /// entries of the same type are merged into one: sizes and counts are added up, except for
+    /// `max` system entries, of which the largest is kept. Entries of an unknown type all merge
+    /// into a single [`Other`](TotalType::Other) entry.
+    pub fn canonicalize(&mut self) {
+        self.heaps.sort_by_key(|heap| heap.nr);
+        self.heaps.iter_mut().for_each(Heap::canonicalize);
+        canonicalize_totals(&mut self.total);
+        canonicalize_systems(&mut self.system);
+        canonicalize_aspaces(&mut self.aspace);
+    }
+}
+
+/// Insert the metrics for the given entries into `metrics`, see [`Malloc::to_metrics`]
+fn insert_entries(
+    metrics: &mut BTreeMap<String, u64>,
+    prefix: &str,
+    totals: &[Total],
+    systems: &[System],
+    aspaces: &[Aspace],
+) {
+    for total in totals {
+        let name = total.r#type.as_str();
+        metrics.insert(format!("{prefix}total.{name}.count"), total.count);
+        metrics.insert(format!("{prefix}total.{name}.size"), total.size);
+    }
+    for system in systems {
+        metrics.insert(
+            format!("{prefix}system.{}", system.r#type.as_str()),
+            system.size,
+        );
+    }
+    for aspace in aspaces {
+        metrics.insert(
+            format!("{prefix}aspace.{}", aspace.r#type.as_str()),
+            aspace.size,
+        );
+    }
+}
+
+/// Sort `entries` by `key`, then merge each run of entries with the same key into its first
+fn canonicalize_by<T, K: Ord>(
+    entries: &mut Vec<T>,
+    key: impl Fn(&T) -> K,
+    merge: impl Fn(&mut T, &T),
+) {
+    entries.sort_by_key(&key);
+    entries.dedup_by(|next, first| {
+        let duplicate = key(next) == key(first);
+        if duplicate {
+            merge(first, next);
+        }
+        duplicate
+    });
+}
+
+fn canonicalize_totals(totals: &mut Vec<Total>) {
+    canonicalize_by(
+        totals,
+        |total| total.r#type,
+        |first, next| {
+            first.count = first.count.saturating_add(next.count);
+            first.size = first.size.saturating_add(next.size);
+        },
+    );
+}
+
+fn canonicalize_systems(systems: &mut Vec<System>) {
+    canonicalize_by(
+        systems,
+        |system| system.r#type,
+        |first, next| {
+            first.size = match first.r#type {
+                SystemType::Max => first.size.max(next.size),
+                _ => first.size.saturating_add(next.size),
+            }
+        },
+    );
+}
+
+fn canonicalize_aspaces(aspaces: &mut Vec<Aspace>) {
+    canonicalize_by(
+        aspaces,
+        |aspace| aspace.r#type,
+        |first, next| first.size = first.size.saturating_add(next.size),
+    );
+}
+
+impl Default for Malloc {
+    fn default() -> Self {
+        Self {
+            version: "1".into(),
+            heaps: Vec::new(),
+            total: Vec::new(),
+            system: Vec::new(),
+            aspace: Vec::new(),
+        }
+    }
+}
+
+impl FromStr for Malloc {
+    type Err = Error;
+
+    fn from_str(xml: &str) -> Result<Self, Error> {
+        Self::from_xml_str(xml)
+    }
+}
+
+impl<'a> IntoIterator for &'a Malloc {
+    type Item = &'a Heap;
+    type IntoIter = slice::Iter<'a, Heap>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -163,6 +765,30 @@ mod test {
         assert_eq!(parsed.aspace.len(), 2);
     }
 
+    #[test]
+    fn from_xml_str() {
+        const XML: &str = r#"<malloc version="1">
+<heap nr="0">
+<sizes>
+<size from="17" to="32" total="64" count="2"/>
+</sizes>
+<total type="fast" count="2" size="64"/>
+<system type="current" size="135168"/>
+<aspace type="total" size="135168"/>
+</heap>
+<total type="fast" count="2" size="64"/>
+<system type="current" size="135168"/>
+<aspace type="total" size="135168"/>
+</malloc>"#;
+        let parsed = Malloc::from_xml_str(XML).expect("parse XML");
+        assert_eq!(parsed.heaps.len(), 1);
+        assert_eq!(parsed.system_current(), Some(135168));
+        assert_eq!(XML.parse::<Malloc>().expect("parse XML"), parsed);
+
+        assert!(Malloc::from_xml_str(&XML[..XML.len() / 2]).is_err());
+        assert!("not XML".parse::<Malloc>().is_err());
+    }
+
     #[test]
     fn parse_complex() {
         // Taken from the malloc_info(3) man-page
@@ -202,6 +828,487 @@ mod test {
         assert_eq!(parsed.total.len(), 2);
         assert_eq!(parsed.system.len(), 2);
         assert_eq!(parsed.aspace.len(), 2);
+        assert_eq!(parsed.system_current(), Some(2113536));
+        assert_eq!(parsed.system_max(), Some(2113536));
+    }
+
+    #[test]
+    fn parse_large() {
+        // More than 4 GiB, which must parse whatever the pointer width
+        const XML: &str = r#"
+<malloc version="1">
+<heap nr="0">
+<sizes>
+<size from="33" to="48" total="4294967344" count="89478486"/>
+</sizes>
+</heap>
+<total type="mmap" count="3" size="8589934592"/>
+<system type="current" size="0"/>
+<aspace type="total" size="0"/>
+</malloc>
+"#;
+        let parsed: Malloc = quick_xml::de::from_str(XML).expect("parse XML");
+        assert_eq!(parsed.total[0].size, 8 << 30);
+        assert_eq!(parsed.total_size(TotalType::Mmap), Some(8 << 30));
+        assert_eq!(
+            parsed.total_by(TotalType::Mmap).map(|total| total.count),
+            Some(3)
+        );
+        assert_eq!(parsed.total_size(TotalType::Fast), None);
+        assert_eq!(parsed.system_size(SystemType::Current), Some(0));
+        assert_eq!(parsed.system_current(), Some(0));
+        assert_eq!(parsed.system_max(), None);
+        assert_eq!(
+            parsed
+                .aspace_by(AspaceType::Total)
+                .map(|aspace| aspace.size),
+            Some(0)
+        );
+        let sizes = parsed.heaps[0]
+            .sizes
+            .as_ref()
+            .and_then(|sizes| sizes.sizes.as_ref());
+        assert!(matches!(
+            sizes.map(Vec::as_slice),
+            Some([Size {
+                kind: SizeKind::Size,
+                total: 4294967344,
+                ..
+            }])
+        ));
+    }
+
+    #[test]
+    fn free() {
+        // Based on the malloc_info(3) man-page example, with size bins added to the second arena
+        const XML: &str = r#"
+<malloc version="1">
+<heap nr="0">
+<sizes>
+</sizes>
+<total type="fast" count="0" size="0"/>
+<total type="rest" count="0" size="0"/>
+<system type="current" size="1081344"/>
+<system type="max" size="1081344"/>
+<aspace type="total" size="1081344"/>
+<aspace type="mprotect" size="1081344"/>
+</heap>
+<heap nr="1">
+<sizes>
+<size from="17" to="32" total="64" count="2"/>
+<size from="33" to="48" total="144" count="3"/>
+<unsorted from="1297" to="1297" total="1297" count="1"/>
+</sizes>
+<total type="fast" count="5" size="208"/>
+<total type="rest" count="1" size="1297"/>
+<system type="current" size="1032192"/>
+<system type="max" size="1032192"/>
+<aspace type="total" size="1032192"/>
+<aspace type="mprotect" size="1032192"/>
+</heap>
+<total type="fast" count="5" size="208"/>
+<total type="rest" count="1" size="1297"/>
+<system type="current" size="2113536"/>
+<system type="max" size="2113536"/>
+<aspace type="total" size="2113536"/>
+<aspace type="mprotect" size="2113536"/>
+</malloc>
+"#;
+        let parsed: Malloc = quick_xml::de::from_str(XML).expect("parse XML");
+        assert_eq!(
+            parsed.iter().map(|heap| heap.nr).collect::<Vec<_>>(),
+            [0, 1]
+        );
+        let mut bins = 0;
+        for heap in &parsed {
+            for size in heap.sizes.iter().flatten() {
+                assert!(size.count > 0);
+                bins += 1;
+            }
+        }
+        assert_eq!(bins, 3);
+        assert_eq!(parsed.heaps[0].free_bytes(), 0);
+        assert_eq!(parsed.heaps[0].free_chunk_count(), 0);
+        assert_eq!(parsed.heaps[1].free_bytes(), 64 + 144 + 1297);
+        assert_eq!(parsed.heaps[1].free_chunk_count(), 6);
+
+        assert_eq!(parsed.heaps[0].largest_free_bin(), None);
+        assert!(matches!(
+            parsed.heaps[1].largest_free_bin(),
+            Some(Size {
+                kind: SizeKind::Unsorted,
+                to: 1297,
+                ..
+            })
+        ));
+        assert!(matches!(
+            parsed.smallest_free_bin(),
+            Some(Size {
+                kind: SizeKind::Size,
+                from: 17,
+                ..
+            })
+        ));
+        assert_eq!(
+            parsed.largest_free_bin(),
+            parsed.heaps[1].largest_free_bin()
+        );
+
+        // The bins add up to the arena's fast and rest totals
+        let heap = &parsed.heaps[1];
+        let totals =
+            heap.total_size(TotalType::Fast).unwrap() + heap.total_size(TotalType::Rest).unwrap();
+        assert_eq!(heap.free_bytes(), totals);
+    }
+
+    #[test]
+    fn subheaps() {
+        // Newer glibc versions report the number of sub-heaps of each non-main arena
+        const XML: &str = r#"
+<malloc version="1">
+<heap nr="1">
+<sizes>
+</sizes>
+<total type="fast" count="0" size="0"/>
+<total type="rest" count="0" size="0"/>
+<system type="current" size="135168"/>
+<system type="max" size="135168"/>
+<aspace type="total" size="135168"/>
+<aspace type="mprotect" size="135168"/>
+<aspace type="subheaps" size="1"/>
+</heap>
+<total type="fast" count="0" size="0"/>
+<system type="current" size="135168"/>
+<aspace type="total" size="135168"/>
+<aspace type="mprotect" size="135168"/>
+<aspace type="subheaps" size="1"/>
+</malloc>
+"#;
+        let parsed: Malloc = quick_xml::de::from_str(XML).expect("parse XML");
+        assert_eq!(parsed.heaps[0].aspace_subheaps(), Some(1));
+        assert_eq!(parsed.aspace_subheaps(), Some(1));
+        assert_eq!(parsed.aspace_total(), Some(135168));
+    }
+
+    #[test]
+    fn utilization() {
+        const XML: &str = r#"
+<malloc version="1">
+<heap nr="1">
+<sizes>
+<size from="17" to="32" total="64" count="2"/>
+</sizes>
+<total type="fast" count="2" size="64"/>
+<total type="rest" count="1" size="258048"/>
+<system type="current" size="1032192"/>
+<system type="max" size="1032192"/>
+<aspace type="total" size="1032192"/>
+<aspace type="mprotect" size="1032192"/>
+</heap>
+<total type="fast" count="2" size="64"/>
+<system type="current" size="1032192"/>
+<aspace type="total" size="1032192"/>
+</malloc>
+"#;
+        let parsed: Malloc = quick_xml::de::from_str(XML).expect("parse XML");
+        let heap = &parsed.heaps[0];
+        assert_eq!(heap.total.len(), 2);
+        assert_eq!(heap.system.len(), 2);
+        assert_eq!(heap.aspace.len(), 2);
+
+        assert_eq!(heap.total_size(TotalType::Rest), Some(258048));
+        assert_eq!(
+            heap.total_by(TotalType::Fast).map(|total| total.count),
+            Some(2)
+        );
+        assert_eq!(heap.system_size(SystemType::Max), Some(1032192));
+        assert_eq!(heap.aspace_size(AspaceType::Subheaps), None);
+
+        assert_eq!(heap.aspace_total(), Some(1032192));
+        assert_eq!(heap.aspace_mprotect(), Some(1032192));
+        assert_eq!(heap.aspace_subheaps(), None);
+        assert_eq!(parsed.aspace_total(), Some(1032192));
+        assert_eq!(parsed.aspace_mprotect(), None);
+
+        let utilization = heap.utilization().expect("utilization");
+        assert_eq!(utilization.free, 258112);
+        assert_eq!(utilization.in_use, 1032192 - 258112);
+        assert!(
+            (utilization.ratio - 0.75).abs() < 0.001,
+            "{}",
+            utilization.ratio
+        );
+
+        let bare = Heap {
+            nr: 0,
+            sizes: None,
+            total: Vec::new(),
+            system: Vec::new(),
+            aspace: Vec::new(),
+        };
+        assert_eq!(bare.utilization(), None);
+    }
+
+    #[test]
+    fn size() {
+        const XML: &str = r#"<sizes>
+<size from="17" to="32" total="96" count="4"/>
+<unsorted from="1297" to="1297" total="0" count="0"/>
+</sizes>"#;
+        let sizes: Sizes = quick_xml::de::from_str(XML).expect("parse XML");
+        assert_eq!(sizes.len(), 2);
+        assert_eq!((&sizes).into_iter().count(), 2);
+        assert_eq!(
+            sizes[0],
+            Size {
+                kind: SizeKind::Size,
+                from: 17,
+                to: 32,
+                total: 96,
+                count: 4,
+            }
+        );
+        assert_eq!(sizes[0].range(), 17..=32);
+        assert_eq!(sizes[0].width(), 16);
+        assert!(!sizes[0].is_unsorted());
+        assert_eq!(sizes[0].average_chunk_size(), Some(24.0));
+
+        assert!(sizes[1].is_unsorted());
+        assert_eq!(sizes[1].width(), 1);
+        assert_eq!(sizes[1].average_chunk_size(), None);
+    }
+
+    #[test]
+    fn sizes_sum() {
+        let bin = |total, count| Size {
+            kind: SizeKind::Size,
+            from: 17,
+            to: 32,
+            total,
+            count,
+        };
+        let sizes = Sizes {
+            sizes: Some(vec![bin(64, 2), bin(96, 3)]),
+        };
+        assert_eq!(sizes.checked_total(), Some(160));
+        assert_eq!(sizes.saturating_total(), 160);
+        assert_eq!(sizes.checked_count(), Some(5));
+        assert_eq!(sizes.saturating_count(), 5);
+
+        let sizes = Sizes {
+            sizes: Some(vec![bin(u64::MAX, u64::MAX - 1), bin(1, 1)]),
+        };
+        assert_eq!(sizes.checked_total(), None);
+        assert_eq!(sizes.saturating_total(), u64::MAX);
+        assert_eq!(sizes.checked_count(), Some(u64::MAX));
+        assert_eq!(sizes.saturating_count(), u64::MAX);
+
+        let empty = Sizes { sizes: None };
+        assert!(empty.is_empty());
+        assert_eq!(empty.iter().next(), None);
+        assert_eq!(empty.checked_total(), Some(0));
+        assert_eq!(empty.saturating_count(), 0);
+    }
+
+    #[test]
+    fn canonicalize() {
+        const XML: &str = r#"
+<malloc version="1">
+<heap nr="1">
+<sizes>
+<unsorted from="17" to="1024" total="2048" count="2"/>
+<size from="33" to="48" total="96" count="2"/>
+<size from="17" to="32" total="64" count="2"/>
+</sizes>
+<total type="rest" count="2" size="2048"/>
+<total type="fast" count="4" size="160"/>
+<system type="max" size="100"/>
+<system type="current" size="100"/>
+<aspace type="total" size="100"/>
+</heap>
+<heap nr="0">
+</heap>
+<total type="rest" count="1" size="10"/>
+<total type="fast" count="0" size="0"/>
+<total type="rest" count="2" size="20"/>
+<system type="max" size="300"/>
+<system type="current" size="100"/>
+<system type="max" size="200"/>
+<system type="current" size="200"/>
+<aspace type="mprotect" size="5"/>
+<aspace type="total" size="10"/>
+</malloc>
+"#;
+        let mut parsed: Malloc = quick_xml::de::from_str(XML).expect("parse XML");
+        parsed.canonicalize();
+
+        assert_eq!(
+            parsed.heaps.iter().map(|heap| heap.nr).collect::<Vec<_>>(),
+            [0, 1]
+        );
+        let sizes: Vec<_> = parsed.heaps[1].sizes.iter().flatten().collect();
+        assert_eq!(
+            sizes
+                .iter()
+                .map(|size| (size.kind, size.from))
+                .collect::<Vec<_>>(),
+            [
+                (SizeKind::Size, 17),
+                (SizeKind::Size, 33),
+                (SizeKind::Unsorted, 17)
+            ]
+        );
+        assert_eq!(parsed.heaps[1].total[0].r#type, TotalType::Fast);
+        assert_eq!(parsed.heaps[1].system[0].r#type, SystemType::Current);
+
+        assert_eq!(
+            parsed.total,
+            [
+                Total {
+                    r#type: TotalType::Fast,
+                    count: 0,
+                    size: 0,
+                },
+                Total {
+                    r#type: TotalType::Rest,
+                    count: 3,
+                    size: 30,
+                },
+            ]
+        );
+        assert_eq!(parsed.system_current(), Some(300));
+        assert_eq!(parsed.system_max(), Some(300));
+        assert_eq!(parsed.system.len(), 2);
+        assert_eq!(parsed.aspace[0].r#type, AspaceType::Total);
+
+        let mut again: Malloc = quick_xml::de::from_str(XML).expect("parse XML");
+        again.heaps.reverse();
+        again.total.reverse();
+        again.system.reverse();
+        again.canonicalize();
+        assert_eq!(parsed, again);
+    }
+
+    #[test]
+    fn order() {
+        let size = |kind, from, to| Size {
+            kind,
+            from,
+            to,
+            total: 0,
+            count: 0,
+        };
+        let mut sizes = [
+            size(SizeKind::Unsorted, 17, 1024),
+            size(SizeKind::Size, 33, 48),
+            size(SizeKind::Size, 17, 32),
+        ];
+        sizes.sort();
+        assert_eq!(sizes[0], size(SizeKind::Size, 17, 32));
+        assert_eq!(sizes[2].kind, SizeKind::Unsorted);
+        assert_eq!(sizes.binary_search(&size(SizeKind::Size, 33, 48)), Ok(1));
+
+        assert!(TotalType::Fast < TotalType::Rest);
+        assert!(SystemType::Current < SystemType::Max);
+        assert!(AspaceType::Subheaps < AspaceType::Other);
+
+        let heap = |nr| Heap {
+            nr,
+            sizes: None,
+            total: Vec::new(),
+            system: Vec::new(),
+            aspace: Vec::new(),
+        };
+        let mut heaps = [heap(2), heap(0), heap(1)];
+        heaps.sort();
+        assert_eq!(heaps.binary_search_by_key(&1, |heap| heap.nr), Ok(1));
+    }
+
+    #[test]
+    fn heap_lookup() {
+        let heap = |nr| Heap {
+            nr,
+            ..Default::default()
+        };
+        let info = Malloc {
+            heaps: vec![heap(2), heap(0), heap(1)],
+            ..Default::default()
+        };
+        assert_eq!(info.heap(1), Some(&heap(1)));
+        assert_eq!(info.heap(3), None);
+        let numbers: Vec<_> = info.arenas().map(|heap| heap.nr).collect();
+        assert_eq!(numbers, [0, 1, 2]);
+        let numbers: Vec<_> = info.iter().map(|heap| heap.nr).collect();
+        assert_eq!(numbers, [2, 0, 1]);
+    }
+
+    #[test]
+    fn to_metrics() {
+        const XML: &str = r#"<malloc version="1">
+<heap nr="0">
+<sizes>
+<size from="17" to="32" total="64" count="2"/>
+<unsorted from="49" to="49" total="49" count="1"/>
+</sizes>
+<total type="fast" count="2" size="64"/>
+<total type="rest" count="1" size="49"/>
+<system type="current" size="135168"/>
+<aspace type="total" size="135168"/>
+</heap>
+<total type="fast" count="2" size="64"/>
+<total type="mmap" count="1" size="266240"/>
+<total type="mmap" count="1" size="4096"/>
+<system type="current" size="135168"/>
+<system type="max" size="135168"/>
+<aspace type="total" size="135168"/>
+</malloc>"#;
+        let info: Malloc = XML.parse().expect("parse XML");
+        let metrics = info.to_metrics();
+        let expected = [
+            ("arenas", 1),
+            ("aspace.total", 135168),
+            ("heap.0.aspace.total", 135168),
+            ("heap.0.size.17-32.count", 2),
+            ("heap.0.size.17-32.total", 64),
+            ("heap.0.system.current", 135168),
+            ("heap.0.total.fast.count", 2),
+            ("heap.0.total.fast.size", 64),
+            ("heap.0.total.rest.count", 1),
+            ("heap.0.total.rest.size", 49),
+            ("heap.0.unsorted.49-49.count", 1),
+            ("heap.0.unsorted.49-49.total", 49),
+            ("system.current", 135168),
+            ("system.max", 135168),
+            ("total.fast.count", 2),
+            ("total.fast.size", 64),
+            ("total.mmap.count", 2),
+            ("total.mmap.size", 270336),
+        ];
+        let metrics: Vec<_> = metrics
+            .iter()
+            .map(|(name, value)| (name.as_str(), *value))
+            .collect();
+        assert_eq!(metrics, expected);
+    }
+
+    #[test]
+    fn clone_hash_default() {
+        use std::collections::HashSet;
+
+        let empty = Malloc::default();
+        assert_eq!(empty.version, "1");
+        assert!(empty.heaps.is_empty());
+        assert_eq!(Heap::default().nr, 0);
+        assert_eq!(Size::default().kind, SizeKind::Size);
+
+        let mut info = empty.clone();
+        info.heaps.push(Heap {
+            nr: 1,
+            ..Default::default()
+        });
+        let snapshots: HashSet<_> = [empty.clone(), info.clone(), info].into_iter().collect();
+        assert_eq!(snapshots.len(), 2);
+        assert!(snapshots.contains(&empty));
     }
 
     #[test]
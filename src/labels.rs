@@ -0,0 +1,56 @@
+//! Checking the names of the labels attached to exported metrics.
+
+use std::io;
+
+/// Label names used by the crate itself: `type` on the samples of metrics split by type, and the
+/// [`BuildInfo`](crate::BuildInfo) labels of `malloc_info_build_info`
+const RESERVED: [&str; 4] = ["type", "glibc_version", "version", "allocator"];
+
+/// Check that every name in `names` is a valid Prometheus label name, matching
+/// `[a-zA-Z_][a-zA-Z0-9_]*` without the `__` prefix reserved for internal use, that it isn't one
+/// of the label names used by the crate itself, and that no name appears twice.
+pub(crate) fn validate<'a>(names: impl IntoIterator<Item = &'a str>) -> io::Result<()> {
+    let mut seen = Vec::new();
+    for name in names {
+        let valid = name
+            .chars()
+            .enumerate()
+            .all(|(i, c)| c == '_' || c.is_ascii_alphabetic() || (i > 0 && c.is_ascii_digit()));
+        let message = if !valid || name.is_empty() || name.starts_with("__") {
+            format!("invalid label name {name:?}")
+        } else if RESERVED.contains(&name) {
+            format!("label name {name} is reserved")
+        } else if seen.contains(&name) {
+            format!("duplicate label name {name}")
+        } else {
+            seen.push(name);
+            continue;
+        };
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, message));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn names() {
+        validate(["service", "_pod", "region_2", "TYPE"]).expect("valid names");
+        validate([]).expect("no names");
+        for invalid in [
+            ["pod-name"].as_slice(),
+            &["2xx"],
+            &[""],
+            &["__name__"],
+            &["pod name"],
+            &["type"],
+            &["version"],
+            &["service", "service"],
+        ] {
+            let error = validate(invalid.iter().copied()).expect_err(invalid[0]);
+            assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+        }
+    }
+}
@@ -16,14 +16,150 @@
 //! `malloc_info` will only report heap statistics for the glibc heap. If your program uses a
 //! different heap implementation, for example by `#[global_allocator]` or by using a different
 //! libc, `malloc_info` will not report statistics for that heap.
+//!
+//! # Features
+//! - `serialize`: implement [`serde::Serialize`] for the types in [`info`], and for the other
+//!   data this crate returns, such as [`MallocSummary`], [`MallocDelta`] and [`MallInfo2`], so
+//!   snapshots can be re-serialized into other formats.
+//! - `bincode`: compact binary encoding of snapshots with
+//!   [`Malloc::to_bincode`](info::Malloc::to_bincode) and
+//!   [`Malloc::from_bincode`](info::Malloc::from_bincode). Implies `serialize`.
+//! - `cbor`: CBOR encoding of snapshots with [`Malloc::to_cbor`](info::Malloc::to_cbor) and
+//!   [`Malloc::from_cbor`](info::Malloc::from_cbor). Implies `serialize`.
+//! - `cloudwatch`: serialize snapshots into the CloudWatch Embedded Metric Format, so services on
+//!   Lambda or ECS can publish them by printing to standard output. See the [`cloudwatch`] module.
+//! - `criterion`: a Criterion measurement reporting heap growth per iteration instead of wall
+//!   time, see the [`criterion`](mod@criterion) module.
+//! - `dbus`: a D-Bus interface exposing heap summaries as properties and the raw XML through a
+//!   `Dump()` method, served on the session bus with [`dbus::serve_session`]. See the [`dbus`]
+//!   module.
+//! - `dogstatsd`: send snapshots to the Datadog agent as DogStatsD gauges with per-arena tags, see
+//!   the [`dogstatsd`] module.
+//! - `exporter`: a standalone HTTP server serving snapshots as Prometheus metrics and JSON, started
+//!   with [`serve_exporter`]. See the [`exporter`] module. Implies `prometheus` and `serialize`.
+//! - `exporter-tls`: let the exporter server terminate TLS with rustls, using the certificate and key
+//!   files configured in [`exporter::Config`]. Implies `exporter`.
+//! - `macros`: the [`malloc_profile`] attribute, recording the heap delta and duration of every
+//!   call to a function as a `tracing` event. Implies `tracing`.
+//! - `msgpack`: MessagePack encoding of snapshots with
+//!   [`Malloc::to_msgpack`](info::Malloc::to_msgpack) and
+//!   [`Malloc::from_msgpack`](info::Malloc::from_msgpack). Implies `serialize`.
+//! - `prometheus`: render snapshots in the Prometheus text exposition format, and write them for
+//!   node_exporter's textfile collector. See the [`prometheus`] module.
+//! - `protobuf`: Protocol Buffers messages for snapshots in the [`proto`] module, generated with
+//!   prost from the schema shipped in `proto/malloc_info.proto`.
+//! - `push`: push snapshots to a collector over HTTP in batches, with retries and a bounded
+//!   queue, started with [`start_push`](push::start_push). See the [`push`] module. Implies
+//!   `serialize`.
+//! - `sentry`: attach heap summaries to Sentry events as context and breadcrumbs, see the
+//!   [`sentry`](mod@sentry) module.
+//! - `shm`: publish heap summaries in a seqlock-protected shared-memory region which external
+//!   scrapers read without the process serving any endpoint, see the [`shm`] module.
+//! - `sqlite`: store snapshots in a local SQLite database with retention pruning, see the
+//!   [`sqlite`] module. SQLite is compiled in, so no system library is needed.
+//! - `stats-socket`: a memcached-style line-protocol server on a Unix domain socket answering
+//!   `stats`, `json` and `trim` commands, started with
+//!   [`serve_stats`](stats_socket::serve_stats). See the [`stats_socket`] module. Implies
+//!   `serialize`.
+//! - `systemd`: report heap summaries as the service status shown by `systemctl status`, keep
+//!   the watchdog alive while captures succeed, and write summaries to the journal as structured
+//!   fields. See the [`systemd`] module.
+//! - `test-utils`: helpers reporting each test's heap growth and failing tests which exceed a
+//!   budget, see the [`test_utils`] module.
+//! - `tokio`: [`malloc_info_blocking`], which captures on tokio's blocking thread pool rather than
+//!   on an async worker thread.
+//! - `tracing`: instrument the capture and parse phases with `DEBUG` level spans recording the
+//!   number of bytes, the number of arenas and the elapsed time, so the overhead of this crate
+//!   shows up alongside the rest of your traces.
 
-use errno::Errno;
 use thiserror::Error;
 
+pub mod budget;
+pub mod build_info;
+pub mod capture;
+mod child;
+#[cfg(feature = "cloudwatch")]
+pub mod cloudwatch;
+#[cfg(feature = "criterion")]
+pub mod criterion;
+#[cfg(feature = "dbus")]
+pub mod dbus;
+pub mod diff;
+#[cfg(feature = "dogstatsd")]
+pub mod dogstatsd;
+#[cfg(any(feature = "bincode", feature = "cbor", feature = "msgpack"))]
+mod encoding;
+#[cfg(any(feature = "exporter", feature = "push"))]
+mod env;
+#[cfg(feature = "exporter")]
+pub mod exporter;
+#[cfg(any(feature = "prometheus", feature = "push"))]
+mod http;
 pub mod info;
+#[cfg(any(feature = "prometheus", feature = "push"))]
+mod labels;
+pub mod mallinfo;
+pub mod mallopt;
+mod measure;
+mod memfd;
 mod memstream;
+pub mod parse;
+#[cfg(feature = "prometheus")]
+pub mod prometheus;
+#[cfg(feature = "protobuf")]
+pub mod proto;
+#[cfg(feature = "push")]
+pub mod push;
+mod reader;
+#[cfg(feature = "sentry")]
+pub mod sentry;
+#[cfg(feature = "shm")]
+pub mod shm;
+pub mod snapshot;
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
+pub mod stats;
+#[cfg(feature = "stats-socket")]
+pub mod stats_socket;
+pub mod summary;
+#[cfg(feature = "systemd")]
+pub mod systemd;
+#[cfg(feature = "test-utils")]
+pub mod test_utils;
+pub mod throttle;
+mod trace;
+pub mod trim;
+pub mod usable_size;
 
+pub use budget::MemoryBudget;
+pub use build_info::{build_info, BuildInfo};
+#[cfg(feature = "tokio")]
+pub use capture::malloc_info_blocking;
+pub use capture::{capture_async, try_capture, CaptureHandle, TryCapture};
+pub use child::measure_in_child;
+#[cfg(feature = "exporter")]
+pub use exporter::serve_exporter;
+pub use mallinfo::{mallinfo2, MallInfo2};
+#[cfg(feature = "macros")]
+pub use malloc_info_macros::malloc_profile;
+pub use measure::{measure, measure_async, MallocDelta, MemoryScope};
+use memfd::MemFd;
 use memstream::MemStream;
+pub use parse::ParseOptions;
+pub use reader::MallocInfoReader;
+pub use snapshot::{snapshot, MallocSnapshot};
+pub use stats::{malloc_stats, MallocStats};
+pub use summary::MallocSummary;
+use trace::Phase;
+pub use trim::malloc_trim;
+pub use usable_size::malloc_usable_size;
+
+/// Support code for the expansions of the macros in `malloc-info-macros`. Not public API.
+#[cfg(feature = "macros")]
+#[doc(hidden)]
+pub mod __private {
+    pub use crate::measure::profile;
+}
 
 /// Internal representation for errors occurring during the [`malloc_info`] call. This is private so
 /// we can modify it without breaking the public API.
@@ -31,7 +167,7 @@ use memstream::MemStream;
 enum ErrorRepr {
     /// An error occurred when interfacing with libc
     #[error("libc error: {0}")]
-    LibC(#[from] Errno),
+    LibC(#[source] std::io::Error),
 
     /// An internal error occurred when interfacing with the memstream module
     #[error(transparent)]
@@ -40,40 +176,347 @@ enum ErrorRepr {
     /// An error occurred when parsing the XML output of `malloc_info`
     #[error("failed to parse malloc_info XML output: {0}")]
     Xml(#[from] quick_xml::DeError),
+
+    /// An error occurred when parsing the output of `malloc_stats`
+    #[error("failed to parse malloc_stats output: {0}")]
+    Stats(String),
+
+    /// The worker thread for capturing could not be spawned
+    #[error("failed to spawn capture thread: {0}")]
+    Spawn(std::io::Error),
+
+    /// The blocking task running a capture panicked or was cancelled
+    #[cfg(feature = "tokio")]
+    #[error("capture task failed: {0}")]
+    Join(tokio::task::JoinError),
+
+    /// The worker thread exited without delivering the result of a capture
+    #[error("capture thread exited without a result")]
+    WorkerExited,
+
+    /// A child process exited without delivering a snapshot
+    #[error("child process failed with wait status {0:#x}")]
+    Child(i32),
+
+    /// An I/O error occurred
+    #[error("I/O error: {0}")]
+    Io(std::io::Error),
+
+    /// An error occurred when storing snapshots in SQLite
+    #[cfg(feature = "sqlite")]
+    #[error("SQLite error: {0}")]
+    Sqlite(#[from] rusqlite::Error),
+
+    /// An error occurred on a D-Bus connection
+    #[cfg(feature = "dbus")]
+    #[error("D-Bus error: {0}")]
+    DBus(#[from] zbus::Error),
+
+    /// An error occurred when encoding a snapshot with bincode
+    #[cfg(feature = "bincode")]
+    #[error("failed to encode bincode: {0}")]
+    BincodeEncode(#[from] bincode::error::EncodeError),
+
+    /// An error occurred when decoding a snapshot with bincode
+    #[cfg(feature = "bincode")]
+    #[error("failed to decode bincode: {0}")]
+    BincodeDecode(#[from] bincode::error::DecodeError),
+
+    /// An error occurred when encoding a snapshot as CBOR
+    #[cfg(feature = "cbor")]
+    #[error("failed to encode CBOR: {0}")]
+    CborEncode(#[from] ciborium::ser::Error<std::io::Error>),
+
+    /// An error occurred when decoding a snapshot from CBOR
+    #[cfg(feature = "cbor")]
+    #[error("failed to decode CBOR: {0}")]
+    CborDecode(#[from] ciborium::de::Error<std::io::Error>),
+
+    /// An error occurred when encoding a snapshot as MessagePack
+    #[cfg(feature = "msgpack")]
+    #[error("failed to encode MessagePack: {0}")]
+    MsgpackEncode(#[from] rmp_serde::encode::Error),
+
+    /// An error occurred when decoding a snapshot from MessagePack
+    #[cfg(feature = "msgpack")]
+    #[error("failed to decode MessagePack: {0}")]
+    MsgpackDecode(#[from] rmp_serde::decode::Error),
+}
+
+impl ErrorRepr {
+    /// A [`ErrorRepr::LibC`] error from the last OS error, as set by the libc call that just failed
+    fn last_os_error() -> Self {
+        ErrorRepr::LibC(std::io::Error::last_os_error())
+    }
 }
 
-/// Custom error type for errors occurring during the [`malloc_info`] call
+/// Custom error type for errors occurring during the [`malloc_info`] call.
+///
+/// Errors from libc calls have the [`std::io::Error`] they were captured as for their
+/// [`source`](std::error::Error::source), which can be downcast to inspect the OS error code.
 #[derive(Debug, Error)]
 #[error(transparent)]
 pub struct Error(#[from] ErrorRepr);
 
+/// Run [`libc::malloc_info`] against a fresh [`MemStream`] and return the stream holding its XML
+/// output.
+fn capture() -> Result<MemStream, ErrorRepr> {
+    capture_with(write_info)
+}
+
+/// Like [`capture`], but always calling into glibc, bypassing the [`throttle`]
+fn capture_fresh() -> Result<MemStream, ErrorRepr> {
+    capture_with(write_fresh)
+}
+
+fn capture_with(
+    write: unsafe fn(*mut libc::FILE) -> Result<(), ErrorRepr>,
+) -> Result<MemStream, ErrorRepr> {
+    let phase = Phase::capture();
+    let mem_stream = MemStream::new()?;
+
+    // SAFETY: The FILE pointer is taken from the mem_stream object, which we control and have
+    // exclusive, mutable access to in this function, ensuring no other code can access it.
+    unsafe { write(mem_stream.fp)? };
+
+    phase.record_bytes(mem_stream.as_ref().len());
+    Ok(mem_stream)
+}
+
+/// Write the output of [`libc::malloc_info`] to `fp` and flush it, going through the
+/// [`throttle`].
+///
+/// # Safety
+/// `fp` must be a valid FILE pointer open for writing, which no other code accesses for the
+/// duration of the call.
+unsafe fn write_info(fp: *mut libc::FILE) -> Result<(), ErrorRepr> {
+    throttle::write_info(fp)
+}
+
+/// Write the output of [`libc::malloc_info`] to `fp` and flush it.
+///
+/// # Safety
+/// See [`write_info`].
+unsafe fn write_fresh(fp: *mut libc::FILE) -> Result<(), ErrorRepr> {
+    write_with_options(fp, 0)
+}
+
+/// Write the output of [`libc::malloc_info`] with `options` to `fp` and flush it.
+///
+/// # Safety
+/// See [`write_info`].
+unsafe fn write_with_options(fp: *mut libc::FILE, options: i32) -> Result<(), ErrorRepr> {
+    // SAFETY: `libc::malloc_info` is marked unsafe because it is in the libc crate and it deals
+    // with raw pointers. Being in the libc crate is not inherently unsafe. The raw pointer it
+    // deals with is a pointer to a FILE struct, which the caller guarantees is valid and not
+    // accessed by any other code.
+    //
+    // The same logic applies to `libc::fflush`.
+    unsafe {
+        match libc::malloc_info(options, fp) {
+            0 => {}
+            -1 => return Err(ErrorRepr::last_os_error()),
+            // Unknown options are rejected by returning EINVAL rather than setting errno
+            code => return Err(ErrorRepr::LibC(std::io::Error::from_raw_os_error(code))),
+        }
+
+        if libc::fflush(fp) != 0 {
+            return Err(ErrorRepr::last_os_error());
+        }
+    }
+    Ok(())
+}
+
 /// Safely get information from [`libc::malloc_info`]. See library-level documentation for more
 /// information.
 pub fn malloc_info() -> Result<info::Malloc, Error> {
     fn malloc_info() -> Result<info::Malloc, ErrorRepr> {
+        let mem_stream = capture()?;
+
+        let phase = Phase::parse(mem_stream.as_ref().len());
+        let info: info::Malloc = quick_xml::de::from_reader(std::io::Cursor::new(mem_stream))?;
+        phase.record_arenas(info.heaps.len());
+        Ok(info)
+    }
+    malloc_info().map_err(Error::from)
+}
+
+/// Like [`malloc_info`], but passing `options` through to [`libc::malloc_info`] as is, for
+/// experimenting with option bits defined by future glibc versions.
+///
+/// No options are defined yet, and glibc fails with `EINVAL` for anything other than zero.
+/// Captures with options other than zero bypass the [`throttle`], whose kept output was captured
+/// without them.
+pub fn malloc_info_with_options(options: i32) -> Result<info::Malloc, Error> {
+    fn malloc_info_with_options(options: i32) -> Result<info::Malloc, ErrorRepr> {
+        if options == 0 {
+            return malloc_info().map_err(|Error(e)| e);
+        }
+
+        let phase = Phase::capture();
         let mem_stream = MemStream::new()?;
-        let mut cursor = std::io::Cursor::new(mem_stream);
-
-        // SAFETY: `libc::malloc_info` is marked unsafe because it is in the libc crate and it deals
-        // with raw pointers. Being in the libc crate is not inherently unsafe. The raw pointer it
-        // deals with is a pointer to a FILE struct, taken from the mem_stream object, which we control
-        // and have exclusive, mutable access to in this function, ensuring no other code can access
-        // it.
-        //
-        // The same logic applies to `libc::fflush`.
-        unsafe {
-            if libc::malloc_info(0, cursor.get_mut().fp) != 0 {
-                return Err(errno::errno().into());
-            }
-
-            if libc::fflush(cursor.get_mut().fp) != 0 {
-                return Err(errno::errno().into());
-            }
+        // SAFETY: The FILE pointer is taken from the mem_stream object, which we control and have
+        // exclusive, mutable access to in this function, ensuring no other code can access it.
+        unsafe { write_with_options(mem_stream.fp, options)? };
+        phase.record_bytes(mem_stream.as_ref().len());
+
+        let phase = Phase::parse(mem_stream.as_ref().len());
+        let info: info::Malloc = quick_xml::de::from_reader(std::io::Cursor::new(mem_stream))?;
+        phase.record_arenas(info.heaps.len());
+        Ok(info)
+    }
+    malloc_info_with_options(options).map_err(Error::from)
+}
+
+/// Get the raw XML output of [`libc::malloc_info`], exactly as glibc wrote it and without parsing
+/// it, for tools which process the output themselves or archive it.
+///
+/// Unlike the parsing functions, this can't fail on output from future glibc versions which
+/// this crate doesn't understand.
+pub fn malloc_info_xml() -> Result<String, Error> {
+    fn malloc_info_xml() -> Result<String, ErrorRepr> {
+        let mem_stream = capture()?;
+        String::from_utf8(mem_stream.as_ref().to_vec())
+            .map_err(|e| ErrorRepr::Io(std::io::Error::new(std::io::ErrorKind::InvalidData, e)))
+    }
+    malloc_info_xml().map_err(Error::from)
+}
+
+/// Like [`malloc_info`], but parsing is controlled by `options`, trading completeness for speed
+/// and memory. See [`ParseOptions`] for what can be configured.
+///
+/// With [`ParseOptions::strict`] unset, elements of the output which fail to parse are skipped
+/// rather than failing the whole call, and are listed in the returned [`parse::Partial`]. An error
+/// is still returned if the call into libc fails or if the `<malloc>` root element can't be read.
+pub fn malloc_info_with(options: ParseOptions) -> Result<parse::Partial, Error> {
+    fn malloc_info_with(options: ParseOptions) -> Result<parse::Partial, ErrorRepr> {
+        let mem_stream = capture()?;
+
+        let phase = Phase::parse(mem_stream.as_ref().len());
+        let partial = parse::parse(mem_stream.as_ref(), &options)?;
+        phase.record_arenas(partial.info.heaps.len());
+        Ok(partial)
+    }
+    malloc_info_with(options).map_err(Error::from)
+}
+
+/// Like [`malloc_info`], but elements of the output which fail to parse are skipped rather than
+/// failing the whole call. The returned [`parse::Partial`] lists everything that was skipped.
+///
+/// An error is still returned if the call into libc fails or if the `<malloc>` root element can't
+/// be read.
+pub fn malloc_info_partial() -> Result<parse::Partial, Error> {
+    malloc_info_with(ParseOptions {
+        strict: false,
+        ..Default::default()
+    })
+}
+
+/// Like [`malloc_info`], but the per-arena `<sizes>` sections are skipped without being parsed,
+/// leaving every [`info::Heap::sizes`] as `None`. The document-level totals and the arena numbers
+/// are still parsed.
+///
+/// The size bins make up the bulk of the output on busy processes, so this is considerably
+/// cheaper when sampling frequently and only the totals are of interest.
+pub fn malloc_info_without_sizes() -> Result<info::Malloc, Error> {
+    let partial = malloc_info_with(ParseOptions {
+        include_sizes: false,
+        ..Default::default()
+    })?;
+    Ok(partial.info)
+}
+
+/// Like [`malloc_info`], but only the arena numbered `nr` is parsed and returned. Every other
+/// `<heap>` element, and the document-level totals, are skipped over. Returns `None` if there is no
+/// such arena.
+///
+/// This is intended for tools tracking a single arena at high frequency.
+pub fn malloc_info_arena(nr: usize) -> Result<Option<info::Heap>, Error> {
+    fn malloc_info_arena(nr: usize) -> Result<Option<info::Heap>, ErrorRepr> {
+        let mem_stream = capture()?;
+
+        let phase = Phase::parse(mem_stream.as_ref().len());
+        let heap = parse::parse_heap(mem_stream.as_ref(), nr)?;
+        phase.record_arenas(heap.iter().len());
+        Ok(heap)
+    }
+    malloc_info_arena(nr).map_err(Error::from)
+}
+
+/// Get the headline numbers from [`libc::malloc_info`] as a [`MallocSummary`].
+///
+/// The summary is extracted directly from the XML output without building a full
+/// [`info::Malloc`], skipping the per-arena sections entirely. It is intended as the default
+/// payload for frequent sampling and exporting, with [`malloc_info`] available when the full
+/// detail is needed.
+pub fn malloc_info_summary() -> Result<MallocSummary, Error> {
+    fn malloc_info_summary() -> Result<MallocSummary, ErrorRepr> {
+        let mem_stream = capture()?;
+
+        let phase = Phase::parse(mem_stream.as_ref().len());
+        let summary = summary::summarize(mem_stream.as_ref())?;
+        phase.record_arenas(summary.arenas);
+        Ok(summary)
+    }
+    malloc_info_summary().map_err(Error::from)
+}
+
+/// Like [`malloc_info`], but glibc writes its output into an anonymous, unlinked file (a `memfd`,
+/// or a `tmpfile` where memfd is unavailable) which is then memory-mapped for parsing.
+///
+/// The raw output never occupies space on the heap being measured, which matters on processes
+/// with many arenas, whose output can run to megabytes, and precisely when the heap is already
+/// under pressure. Only stdio's fixed-size buffer for the file and the parsed result are
+/// allocated.
+pub fn malloc_info_memfd() -> Result<info::Malloc, Error> {
+    fn malloc_info_memfd() -> Result<info::Malloc, ErrorRepr> {
+        let mapping = {
+            let phase = Phase::capture();
+            let mem_fd = MemFd::new().map_err(ErrorRepr::LibC)?;
+
+            // SAFETY: The FILE pointer is taken from the mem_fd object, which we control and have
+            // exclusive, mutable access to in this function, ensuring no other code can access
+            // it.
+            unsafe { write_info(mem_fd.fp)? };
+
+            let mapping = mem_fd.map().map_err(ErrorRepr::LibC)?;
+            phase.record_bytes(mapping.as_ref().len());
+            mapping
+        };
+
+        let phase = Phase::parse(mapping.as_ref().len());
+        let info: info::Malloc = quick_xml::de::from_reader(mapping.as_ref())?;
+        phase.record_arenas(info.heaps.len());
+        Ok(info)
+    }
+    malloc_info_memfd().map_err(Error::from)
+}
+
+/// Write the raw XML output of [`libc::malloc_info`] to a FILE the caller already owns, such as
+/// one opened with `fdopen` on a socket or created with `fopencookie`, and flush it.
+///
+/// This is the lowest-level entry point of the crate: nothing is parsed, and failures of
+/// `malloc_info` or `fflush` are reported with their OS error as for every other capture. Like
+/// every other capture it goes through the [`throttle`], if enabled. A null `fp` fails with
+/// `EINVAL`.
+///
+/// # Safety
+/// `fp` must be null or a valid FILE pointer open for writing, which no other code accesses for
+/// the duration of the call.
+pub unsafe fn malloc_info_raw(fp: *mut libc::FILE) -> Result<(), Error> {
+    unsafe fn malloc_info_raw(fp: *mut libc::FILE) -> Result<(), ErrorRepr> {
+        if fp.is_null() {
+            return Err(ErrorRepr::LibC(std::io::Error::from_raw_os_error(
+                libc::EINVAL,
+            )));
         }
 
-        Ok(quick_xml::de::from_reader(&mut cursor)?)
+        let _phase = Phase::capture();
+        // SAFETY: `fp` is non-null, and the caller guarantees it is valid and not accessed by any
+        // other code
+        write_info(fp)
     }
-    malloc_info().map_err(Error::from)
+    malloc_info_raw(fp).map_err(Error::from)
 }
 
 #[cfg(test)]
@@ -84,4 +527,110 @@ mod test {
     async fn call_from_async() {
         let _ = tokio::task::spawn(async { malloc_info().expect("malloc_info") }).await;
     }
+
+    #[test]
+    fn os_error_source() {
+        use std::error::Error as _;
+
+        let error = Error::from(ErrorRepr::LibC(std::io::Error::from_raw_os_error(
+            libc::ENOMEM,
+        )));
+        let source = error.source().expect("source");
+        let io = source
+            .downcast_ref::<std::io::Error>()
+            .expect("io::Error source");
+        assert_eq!(io.raw_os_error(), Some(libc::ENOMEM));
+        assert_eq!(io.kind(), std::io::ErrorKind::OutOfMemory);
+    }
+
+    #[test]
+    fn with_options() {
+        use std::error::Error as _;
+
+        assert_eq!(
+            malloc_info_with_options(0)
+                .expect("malloc_info_with_options")
+                .version,
+            "1"
+        );
+
+        // No option bits are defined yet, so glibc rejects them
+        let error = malloc_info_with_options(1).expect_err("undefined option");
+        let source = error.source().expect("source");
+        let io = source
+            .downcast_ref::<std::io::Error>()
+            .expect("io::Error source");
+        assert_eq!(io.raw_os_error(), Some(libc::EINVAL));
+    }
+
+    #[test]
+    fn xml() {
+        let xml = malloc_info_xml().expect("malloc_info_xml");
+        assert!(xml.starts_with("<malloc version=\"1\">"));
+        assert!(xml.trim_end().ends_with("</malloc>"));
+        let info: info::Malloc = quick_xml::de::from_str(&xml).expect("parse XML");
+        assert!(!info.heaps.is_empty());
+    }
+
+    #[test]
+    fn partial() {
+        let partial = malloc_info_partial().expect("malloc_info_partial");
+        assert!(partial.is_complete(), "{:?}", partial.skipped);
+        assert!(!partial.info.heaps.is_empty());
+    }
+
+    #[test]
+    fn without_sizes() {
+        let info = malloc_info_without_sizes().expect("malloc_info_without_sizes");
+        assert!(!info.heaps.is_empty());
+        assert!(info.heaps.iter().all(|heap| heap.sizes.is_none()));
+    }
+
+    #[test]
+    fn with_max_heaps() {
+        let partial = malloc_info_with(ParseOptions {
+            max_heaps: Some(1),
+            ..Default::default()
+        })
+        .expect("malloc_info_with");
+        assert_eq!(partial.info.heaps.len(), 1);
+    }
+
+    #[test]
+    fn summary() {
+        let summary = malloc_info_summary().expect("malloc_info_summary");
+        let info = malloc_info().expect("malloc_info");
+        assert_eq!(summary.arenas, info.heaps.len());
+        assert!(summary.system_current > 0);
+        assert!(summary.system_max >= summary.system_current);
+    }
+
+    #[test]
+    fn memfd() {
+        let info = malloc_info_memfd().expect("malloc_info_memfd");
+        assert!(!info.heaps.is_empty());
+    }
+
+    #[test]
+    fn raw() {
+        let mem_fd = MemFd::new().expect("memfd");
+        unsafe { malloc_info_raw(mem_fd.fp) }.expect("malloc_info_raw");
+        let mapping = mem_fd.map().expect("map");
+        let info: info::Malloc = quick_xml::de::from_reader(mapping.as_ref()).expect("parse XML");
+        assert!(!info.heaps.is_empty());
+
+        let error = unsafe { malloc_info_raw(std::ptr::null_mut()) }.expect_err("null FILE");
+        let source = std::error::Error::source(&error).expect("source");
+        let io = source.downcast_ref::<std::io::Error>().expect("io::Error");
+        assert_eq!(io.raw_os_error(), Some(libc::EINVAL));
+    }
+
+    #[test]
+    fn arena() {
+        let heap = malloc_info_arena(0).expect("malloc_info_arena");
+        assert_eq!(heap.map(|heap| heap.nr), Some(0));
+        assert!(malloc_info_arena(usize::MAX)
+            .expect("malloc_info_arena")
+            .is_none());
+    }
 }
@@ -0,0 +1,88 @@
+//! A safe wrapper for glibc's `mallinfo2` function. See the
+//! [mallinfo(3)](https://man7.org/linux/man-pages/man3/mallinfo.3.html) page for details.
+//!
+//! `mallinfo2` fills in a fixed-size struct, with no output to capture or parse, so it is much
+//! cheaper than `malloc_info` and suited to hot paths which only need the headline numbers. Unlike
+//! `malloc_info` it has no per-arena breakdown, and glibc only counts the main arena for some
+//! fields. `mallinfo2` was added in glibc 2.33.
+
+#[cfg(feature = "serialize")]
+use serde::Serialize;
+
+/// Allocation statistics returned by [`mallinfo2`]. Sizes are in bytes.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serialize", derive(Serialize))]
+pub struct MallInfo2 {
+    /// Non-mmapped space allocated from the system, across all arenas
+    pub arena: usize,
+
+    /// Number of ordinary (non-fastbin) free chunks
+    pub ordblks: usize,
+
+    /// Number of free fastbin chunks
+    pub smblks: usize,
+
+    /// Number of chunks currently allocated with `mmap`
+    pub hblks: usize,
+
+    /// Bytes in chunks currently allocated with `mmap`
+    pub hblkhd: usize,
+
+    /// Unused, always zero
+    pub usmblks: usize,
+
+    /// Bytes in free fastbin chunks
+    pub fsmblks: usize,
+
+    /// Bytes used by in-use allocations
+    pub uordblks: usize,
+
+    /// Bytes in free chunks
+    pub fordblks: usize,
+
+    /// Bytes of releasable free space at the top of the heap, which is the most `malloc_trim`
+    /// could release ideally
+    pub keepcost: usize,
+}
+
+impl From<libc::mallinfo2> for MallInfo2 {
+    fn from(info: libc::mallinfo2) -> Self {
+        Self {
+            arena: info.arena,
+            ordblks: info.ordblks,
+            smblks: info.smblks,
+            hblks: info.hblks,
+            hblkhd: info.hblkhd,
+            usmblks: info.usmblks,
+            fsmblks: info.fsmblks,
+            uordblks: info.uordblks,
+            fordblks: info.fordblks,
+            keepcost: info.keepcost,
+        }
+    }
+}
+
+/// Get allocation statistics from [`libc::mallinfo2()`]. See the [module documentation](self) for
+/// how this compares to [`malloc_info`](crate::malloc_info).
+pub fn mallinfo2() -> MallInfo2 {
+    // SAFETY: mallinfo2 has no preconditions and returns its struct by value
+    unsafe { libc::mallinfo2() }.into()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn allocations_show_up() {
+        let before = mallinfo2();
+        assert!(before.arena > 0);
+        assert_eq!(before.usmblks, 0);
+
+        // Large enough to be allocated with mmap, and to outweigh concurrent tests freeing memory
+        let allocation = vec![0u8; 64 << 20];
+        let after = mallinfo2();
+        assert!(after.uordblks + after.hblkhd > before.uordblks + before.hblkhd);
+        drop(allocation);
+    }
+}
@@ -0,0 +1,118 @@
+//! A typed wrapper for glibc's `mallopt` function, for tuning the allocator at runtime. See the
+//! [mallopt(3)](https://man7.org/linux/man-pages/man3/mallopt.3.html) page for what each parameter
+//! does and its default.
+//!
+//! ```rust
+//! use malloc_info::mallopt::{mallopt, MallocParam};
+//!
+//! // Cap the number of arenas after malloc_info reports too many of them
+//! if malloc_info::malloc_info_summary()?.arenas > 16 {
+//!     mallopt(MallocParam::ArenaMax(16))?;
+//! }
+//! # Ok::<(), malloc_info::Error>(())
+//! ```
+//!
+//! The environment variables listed on the man page, such as `MALLOC_ARENA_MAX`, set the same
+//! parameters at startup.
+
+use crate::{Error, ErrorRepr};
+
+/// A parameter of the allocator along with the value to set it to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum MallocParam {
+    /// `M_ARENA_MAX`: the maximum number of arenas
+    ArenaMax(usize),
+
+    /// `M_ARENA_TEST`: the number of arenas created before `M_ARENA_MAX` is derived from the
+    /// number of CPUs, when it isn't set
+    ArenaTest(usize),
+
+    /// `M_MMAP_MAX`: the maximum number of chunks allocated with `mmap` at any one time. Zero
+    /// disables the use of `mmap` for allocations.
+    MmapMax(usize),
+
+    /// `M_MMAP_THRESHOLD`: the size in bytes from which allocations are made with `mmap`. Setting
+    /// it disables the dynamic adjustment of the threshold.
+    MmapThreshold(usize),
+
+    /// `M_PERTURB`: fill allocated memory with the complement of this byte, and freed memory with
+    /// the byte itself, to expose use of uninitialized or freed memory. Zero disables it.
+    Perturb(u8),
+
+    /// `M_TOP_PAD`: the bytes of padding to obtain from the system on top of each request which
+    /// grows the heap, and to keep when trimming it
+    TopPad(usize),
+
+    /// `M_TRIM_THRESHOLD`: the size in bytes the free space at the top of the heap must reach for
+    /// `free` to release it to the system. Setting it disables the dynamic adjustment of the
+    /// threshold.
+    TrimThreshold(usize),
+}
+
+impl MallocParam {
+    /// The `M_*` constant identifying the parameter, and its value as passed to `mallopt`, or
+    /// `None` if the value doesn't fit in a C `int`
+    fn raw(self) -> (i32, Option<i32>) {
+        let (param, value) = match self {
+            Self::ArenaMax(value) => (libc::M_ARENA_MAX, value),
+            Self::ArenaTest(value) => (libc::M_ARENA_TEST, value),
+            Self::MmapMax(value) => (libc::M_MMAP_MAX, value),
+            Self::MmapThreshold(value) => (libc::M_MMAP_THRESHOLD, value),
+            Self::Perturb(value) => (libc::M_PERTURB, value.into()),
+            Self::TopPad(value) => (libc::M_TOP_PAD, value),
+            Self::TrimThreshold(value) => (libc::M_TRIM_THRESHOLD, value),
+        };
+        (param, value.try_into().ok())
+    }
+}
+
+/// Set an allocator parameter with [`libc::mallopt`].
+///
+/// Fails with `EINVAL` if the value doesn't fit in a C `int`, or if glibc rejects it.
+pub fn mallopt(param: MallocParam) -> Result<(), Error> {
+    let invalid = || ErrorRepr::LibC(std::io::Error::from_raw_os_error(libc::EINVAL));
+    let (param, value) = match param.raw() {
+        (param, Some(value)) => (param, value),
+        (_, None) => return Err(invalid().into()),
+    };
+    // SAFETY: mallopt has no preconditions, and validates its arguments itself
+    match unsafe { libc::mallopt(param, value) } {
+        1 => Ok(()),
+        _ => Err(invalid().into()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn raw() {
+        assert_eq!(MallocParam::ArenaMax(4).raw(), (libc::M_ARENA_MAX, Some(4)));
+        assert_eq!(
+            MallocParam::Perturb(0xa5).raw(),
+            (libc::M_PERTURB, Some(0xa5))
+        );
+        assert_eq!(
+            MallocParam::TopPad(usize::MAX).raw(),
+            (libc::M_TOP_PAD, None)
+        );
+    }
+
+    #[test]
+    fn set() {
+        // The default, so that other tests in the process are unaffected
+        mallopt(MallocParam::Perturb(0)).expect("mallopt");
+    }
+
+    #[test]
+    fn out_of_range() {
+        use std::error::Error as _;
+
+        let error = mallopt(MallocParam::TrimThreshold(usize::MAX)).expect_err("out of range");
+        let source = error.source().expect("source");
+        let io = source.downcast_ref::<std::io::Error>().expect("io::Error");
+        assert_eq!(io.raw_os_error(), Some(libc::EINVAL));
+    }
+}
@@ -0,0 +1,324 @@
+//! Measuring how much a piece of code changes the glibc heap, by taking a [`MallocSummary`] before
+//! and after running it.
+
+use crate::{malloc_info_summary, Error, MallocSummary};
+#[cfg(feature = "serialize")]
+use serde::Serialize;
+use std::borrow::Cow;
+use std::future::Future;
+#[cfg(feature = "macros")]
+use std::time::Instant;
+
+/// Change in the headline numbers of `malloc_info` between two snapshots, see [`measure`].
+///
+/// Every field is the value after minus the value before, so a negative value means the number
+/// went down. Differences which don't fit in an `i64` saturate.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serialize", derive(Serialize))]
+pub struct MallocDelta {
+    /// Change in the number of arenas
+    pub arenas: i64,
+
+    /// Change in bytes obtained from the system, across all arenas
+    pub system_current: i64,
+
+    /// Change in the maximum bytes ever obtained from the system, across all arenas
+    pub system_max: i64,
+
+    /// Change in bytes held in free fastbin chunks
+    pub fast: i64,
+
+    /// Change in bytes held in all other free chunks
+    pub rest: i64,
+
+    /// Change in bytes allocated directly with `mmap`
+    pub mmap: i64,
+}
+
+impl MallocDelta {
+    /// The change from `before` to `after`
+    pub fn between(before: &MallocSummary, after: &MallocSummary) -> Self {
+        Self {
+            arenas: difference(before.arenas as u64, after.arenas as u64),
+            system_current: difference(before.system_current, after.system_current),
+            system_max: difference(before.system_max, after.system_max),
+            fast: difference(before.fast, after.fast),
+            rest: difference(before.rest, after.rest),
+            mmap: difference(before.mmap, after.mmap),
+        }
+    }
+
+    /// Change in bytes in use by the program: memory obtained from the system minus what is held
+    /// in free chunks, plus chunks allocated directly with `mmap`. `malloc_info` doesn't report the
+    /// free space at the top of an arena, so it counts as in use, and the delta is approximate.
+    pub fn in_use(&self) -> i64 {
+        self.system_current
+            .saturating_sub(self.fast)
+            .saturating_sub(self.rest)
+            .saturating_add(self.mmap)
+    }
+}
+
+/// `after - before`, saturating at the bounds of `i64`
+pub(crate) fn difference(before: u64, after: u64) -> i64 {
+    let difference = i128::from(after) - i128::from(before);
+    difference.clamp(i64::MIN.into(), i64::MAX.into()) as i64
+}
+
+/// Run `f`, and return its result along with how much the glibc heap changed while it ran.
+///
+/// A [`MallocSummary`] is taken right before calling `f` and right after it returns, while its
+/// result is still alive, so memory held by the result counts towards the delta. `f` is run even
+/// if the first snapshot fails, in which case the error is returned in place of the delta.
+///
+/// The snapshots cover the whole process, so allocations made by other threads while `f` runs
+/// show up in the delta as well. See [`measure_in_child`](crate::measure_in_child) for isolating a
+/// workload from the rest of the process instead.
+///
+/// ```rust
+/// let (buffer, delta) = malloc_info::measure(|| vec![0u8; 16 << 20]);
+/// let delta = delta.expect("malloc_info");
+/// println!("allocating {} bytes cost {} bytes", buffer.len(), delta.in_use());
+/// ```
+pub fn measure<T>(f: impl FnOnce() -> T) -> (T, Result<MallocDelta, Error>) {
+    let before = malloc_info_summary();
+    let result = f();
+    (result, delta_since(before))
+}
+
+/// Await `future`, and return its output along with how much the glibc heap changed until it
+/// completed. The async counterpart of [`measure`], for profiling async handlers.
+///
+/// The first snapshot is taken when the returned future is first polled, and the second as soon
+/// as `future` completes. Both are quick, synchronous calls made from within `poll`, and nothing
+/// blocks in between, so this works with any executor. Allocations made by other tasks while
+/// `future` is pending count towards the delta.
+///
+/// ```rust
+/// # async fn handle() {
+/// let (body, delta) = malloc_info::measure_async(async { vec![0u8; 1 << 20] }).await;
+/// # }
+/// ```
+pub async fn measure_async<F: Future>(future: F) -> (F::Output, Result<MallocDelta, Error>) {
+    let before = malloc_info_summary();
+    let output = future.await;
+    (output, delta_since(before))
+}
+
+/// The change since `before`, taking a new snapshot for after
+fn delta_since(before: Result<MallocSummary, Error>) -> Result<MallocDelta, Error> {
+    let before = before?;
+    let after = malloc_info_summary()?;
+    Ok(MallocDelta::between(&before, &after))
+}
+
+/// Guard measuring the code region it lives in. A [`MallocSummary`] is taken when the guard is
+/// created, and when it is dropped the [`MallocDelta`] since then is handed to a callback along
+/// with the guard's label, to be logged or recorded as a metric.
+///
+/// ```rust
+/// # use malloc_info::MemoryScope;
+/// fn load() -> Vec<u8> {
+///     let _scope = MemoryScope::new("load", |label, delta| {
+///         if let Ok(delta) = delta {
+///             println!("{label}: {} bytes", delta.in_use());
+///         }
+///     });
+///     vec![0u8; 1 << 20]
+/// }
+/// # load();
+/// ```
+///
+/// The guard must be bound to a named variable such as `_scope`: with `let _ = ...` it is dropped,
+/// and the region ends, right away. As with [`measure`], allocations made by other threads in the
+/// meantime count towards the delta.
+#[must_use = "the region ends as soon as the guard is dropped"]
+pub struct MemoryScope<F: FnOnce(&str, Result<MallocDelta, Error>)> {
+    label: Cow<'static, str>,
+    state: Option<(Result<MallocSummary, Error>, F)>,
+}
+
+impl<F: FnOnce(&str, Result<MallocDelta, Error>)> MemoryScope<F> {
+    /// Start measuring a region called `label`, calling `report` with the delta when the guard is
+    /// dropped. If the first snapshot fails, `report` is called with its error instead.
+    pub fn new(label: impl Into<Cow<'static, str>>, report: F) -> Self {
+        let label = label.into();
+        Self {
+            label,
+            state: Some((malloc_info_summary(), report)),
+        }
+    }
+
+    /// Label of the region
+    pub fn label(&self) -> &str {
+        &self.label
+    }
+}
+
+#[cfg(feature = "tracing")]
+impl MemoryScope<fn(&str, Result<MallocDelta, Error>)> {
+    /// Start measuring a region called `label`, emitting the delta as a `DEBUG` level event with
+    /// the target `malloc_info::scope` when the guard is dropped. A failed snapshot is emitted as a
+    /// `WARN` level event instead.
+    pub fn traced(label: impl Into<Cow<'static, str>>) -> Self {
+        Self::new(label, trace)
+    }
+}
+
+#[cfg(feature = "tracing")]
+fn trace(label: &str, delta: Result<MallocDelta, Error>) {
+    match delta {
+        Ok(delta) => tracing::debug!(
+            target: "malloc_info::scope",
+            label,
+            in_use = delta.in_use(),
+            system_current = delta.system_current,
+            mmap = delta.mmap,
+            arenas = delta.arenas,
+        ),
+        Err(error) => tracing::warn!(
+            target: "malloc_info::scope",
+            label,
+            %error,
+            "failed to measure region",
+        ),
+    }
+}
+
+/// Run `f` under [`measure`], timing it, and emit the delta and duration as a `DEBUG` level event
+/// with the target `malloc_info::profile`. This is what [`malloc_profile`](crate::malloc_profile)
+/// expands to.
+#[cfg(feature = "macros")]
+pub fn profile<T>(name: &'static str, f: impl FnOnce() -> T) -> T {
+    let ((result, elapsed), delta) = measure(|| {
+        let start = Instant::now();
+        let result = f();
+        (result, start.elapsed())
+    });
+    let duration_us = elapsed.as_micros() as u64;
+    match delta {
+        Ok(delta) => tracing::debug!(
+            target: "malloc_info::profile",
+            function = name,
+            duration_us,
+            in_use = delta.in_use(),
+            system_current = delta.system_current,
+            mmap = delta.mmap,
+        ),
+        Err(error) => tracing::warn!(
+            target: "malloc_info::profile",
+            function = name,
+            duration_us,
+            %error,
+            "failed to measure call",
+        ),
+    }
+    result
+}
+
+impl<F: FnOnce(&str, Result<MallocDelta, Error>)> Drop for MemoryScope<F> {
+    fn drop(&mut self) {
+        if let Some((before, report)) = self.state.take() {
+            report(&self.label, delta_since(before));
+        }
+    }
+}
+
+impl<F: FnOnce(&str, Result<MallocDelta, Error>)> std::fmt::Debug for MemoryScope<F> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MemoryScope")
+            .field("label", &self.label)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::Mutex;
+
+    /// Serializes the tests making large allocations, which would otherwise show up in each other's
+    /// deltas. Whether an allocation is served by `mmap` or from an arena depends on glibc's dynamic
+    /// mmap threshold, so the tests only check the bytes in use, roughly.
+    static LARGE: Mutex<()> = Mutex::new(());
+
+    #[test]
+    fn large_allocation() {
+        const SIZE: usize = 16 << 20;
+        let _large = LARGE.lock().unwrap_or_else(|e| e.into_inner());
+
+        let (buffer, delta) = measure(|| vec![1u8; SIZE]);
+        let delta = delta.expect("measure");
+        assert_eq!(buffer.len(), SIZE);
+        assert!(delta.in_use() > SIZE as i64 / 2, "{delta:?}");
+    }
+
+    #[test]
+    fn large_allocation_async() {
+        const SIZE: usize = 16 << 20;
+        let _large = LARGE.lock().unwrap_or_else(|e| e.into_inner());
+
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .expect("runtime");
+        let (buffer, delta) = runtime.block_on(measure_async(async {
+            tokio::task::yield_now().await;
+            vec![1u8; SIZE]
+        }));
+        let delta = delta.expect("measure");
+        assert_eq!(buffer.len(), SIZE);
+        assert!(delta.in_use() > SIZE as i64 / 2, "{delta:?}");
+    }
+
+    #[test]
+    fn between() {
+        let before = MallocSummary {
+            arenas: 1,
+            system_current: 4096,
+            system_max: 4096,
+            fast: 64,
+            rest: 128,
+            mmap: 0,
+            mmap_count: 0,
+        };
+        let after = MallocSummary {
+            arenas: 2,
+            system_current: 8192,
+            fast: 0,
+            mmap: u64::MAX,
+            mmap_count: 1,
+            ..before
+        };
+        let delta = MallocDelta::between(&before, &after);
+        assert_eq!(delta.arenas, 1);
+        assert_eq!(delta.system_current, 4096);
+        assert_eq!(delta.system_max, 0);
+        assert_eq!(delta.fast, -64);
+        assert_eq!(delta.mmap, i64::MAX);
+        assert_eq!(MallocDelta::between(&after, &before).mmap, i64::MIN);
+        assert_eq!(
+            MallocDelta::between(&before, &before),
+            MallocDelta::default()
+        );
+    }
+
+    #[test]
+    fn scope() {
+        const SIZE: usize = 16 << 20;
+        let _large = LARGE.lock().unwrap_or_else(|e| e.into_inner());
+
+        let mut reported = None;
+        let buffer = {
+            let scope = MemoryScope::new(format!("scope-{SIZE}"), |label, delta| {
+                reported = Some((label.to_owned(), delta))
+            });
+            assert_eq!(scope.label(), "scope-16777216");
+            vec![1u8; SIZE]
+        };
+
+        let (label, delta) = reported.expect("reported on drop");
+        let delta = delta.expect("measure");
+        assert_eq!(label, "scope-16777216");
+        assert!(delta.in_use() > buffer.len() as i64 / 2, "{delta:?}");
+    }
+}
@@ -0,0 +1,190 @@
+use libc::FILE;
+use std::io;
+use std::os::raw::{c_char, c_void};
+use std::ptr;
+
+/// A FILE pointer backed by an anonymous, unlinked file rather than by heap memory. Once written,
+/// the contents can be [mapped](MemFd::map) into memory for reading.
+///
+/// The file is created with [`libc::memfd_create`], falling back to [`libc::tmpfile`] on kernels
+/// without memfd support.
+#[derive(Debug)]
+pub(crate) struct MemFd {
+    pub(crate) fp: *mut FILE,
+}
+
+impl MemFd {
+    /// Create a new, empty [`MemFd`]
+    pub(crate) fn new() -> io::Result<Self> {
+        const NAME: &[u8] = b"malloc-info\0";
+
+        // SAFETY: `NAME` is a valid NUL-terminated string which outlives the call
+        let fd = unsafe { libc::memfd_create(NAME.as_ptr() as *const c_char, libc::MFD_CLOEXEC) };
+
+        let fp = if fd < 0 {
+            // SAFETY: `tmpfile` takes no arguments and returns either NULL or a valid FILE pointer
+            unsafe { libc::tmpfile() }
+        } else {
+            const MODE: &[u8] = b"w+\0";
+
+            // SAFETY: `fd` is a valid file descriptor we own, and `MODE` is a valid NUL-terminated
+            // string
+            let fp = unsafe { libc::fdopen(fd, MODE.as_ptr() as *const c_char) };
+            if fp.is_null() {
+                let error = io::Error::last_os_error();
+                // SAFETY: `fd` is valid and has not been handed to a FILE
+                unsafe { libc::close(fd) };
+                return Err(error);
+            }
+            fp
+        };
+
+        if fp.is_null() {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self { fp })
+    }
+
+    /// Discard everything written so far, so the file can be written again from the start
+    pub(crate) fn clear(&mut self) -> io::Result<()> {
+        // SAFETY: `self.fp` is a valid FILE pointer for as long as `self` lives, and we have
+        // exclusive access to it
+        unsafe {
+            if libc::fflush(self.fp) != 0 || libc::ftruncate(libc::fileno(self.fp), 0) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            libc::rewind(self.fp);
+        }
+        Ok(())
+    }
+
+    /// Flush everything written so far and map it into memory
+    pub(crate) fn map(&self) -> io::Result<Mapping> {
+        // SAFETY: `self.fp` is a valid FILE pointer for as long as `self` lives, and `stat` is
+        // plain old data which `fstat` fills in
+        let len = unsafe {
+            if libc::fflush(self.fp) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+
+            let mut stat = std::mem::zeroed::<libc::stat>();
+            if libc::fstat(libc::fileno(self.fp), &mut stat) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            stat.st_size as usize
+        };
+
+        if len == 0 {
+            return Ok(Mapping {
+                ptr: ptr::null_mut(),
+                len,
+            });
+        }
+
+        // SAFETY: We map a private, read-only view of a file we own. The mapping stays valid
+        // independently of the file descriptor, and is unmapped when the [`Mapping`] is dropped.
+        let ptr = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                len,
+                libc::PROT_READ,
+                libc::MAP_PRIVATE,
+                libc::fileno(self.fp),
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Mapping { ptr, len })
+    }
+}
+
+// SAFETY: The FILE is owned exclusively by the MemFd, and glibc's stdio functions may be called on
+// it from any thread
+unsafe impl Send for MemFd {}
+
+impl Drop for MemFd {
+    fn drop(&mut self) {
+        // SAFETY: We can call this because we are about to drop the MemFd anyways. Closing the
+        // last reference to the unlinked file releases its storage.
+        unsafe {
+            libc::fclose(self.fp);
+        }
+        self.fp = ptr::null_mut();
+    }
+}
+
+/// A read-only memory mapping of the contents of a [`MemFd`]
+#[derive(Debug)]
+pub(crate) struct Mapping {
+    ptr: *mut c_void,
+    len: usize,
+}
+
+impl AsRef<[u8]> for Mapping {
+    fn as_ref(&self) -> &[u8] {
+        if self.ptr.is_null() {
+            return &[];
+        }
+
+        // SAFETY: `ptr` points to a readable mapping of `len` bytes which lives as long as `self`
+        unsafe { std::slice::from_raw_parts(self.ptr as *const u8, self.len) }
+    }
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        if !self.ptr.is_null() {
+            // SAFETY: `ptr` and `len` describe a mapping we created and nothing else references
+            unsafe { libc::munmap(self.ptr, self.len) };
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn hello_world() {
+        let fd = MemFd::new().unwrap();
+        let text = b"Hello, world!";
+        unsafe {
+            libc::fwrite(text.as_ptr() as _, 1, text.len(), fd.fp);
+        }
+        assert_eq!(fd.map().unwrap().as_ref(), b"Hello, world!");
+    }
+
+    #[test]
+    fn clear() {
+        let mut fd = MemFd::new().unwrap();
+        unsafe {
+            libc::fwrite(b"Hello, world!".as_ptr() as _, 1, 13, fd.fp);
+        }
+        fd.clear().unwrap();
+        assert_eq!(fd.map().unwrap().as_ref(), b"");
+        unsafe {
+            libc::fwrite(b"Bye".as_ptr() as _, 1, 3, fd.fp);
+        }
+        assert_eq!(fd.map().unwrap().as_ref(), b"Bye");
+    }
+
+    #[test]
+    fn empty() {
+        let fd = MemFd::new().unwrap();
+        assert_eq!(fd.map().unwrap().as_ref(), b"");
+    }
+
+    #[test]
+    fn mapping_outlives_file() {
+        let fd = MemFd::new().unwrap();
+        let text = b"Hello, world!";
+        unsafe {
+            libc::fwrite(text.as_ptr() as _, 1, text.len(), fd.fp);
+        }
+        let mapping = fd.map().unwrap();
+        std::mem::drop(fd);
+        assert_eq!(mapping.as_ref(), b"Hello, world!");
+    }
+}
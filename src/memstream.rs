@@ -7,7 +7,7 @@ use thiserror::Error;
 pub enum Error {
     /// An error occurred when interfacing with libc
     #[error("libc error: {0}")]
-    LibC(#[from] errno::Errno),
+    LibC(#[from] std::io::Error),
 
     /// An error occurred when managing the libc memstream buffer
     #[error("memstream invalid")]
@@ -35,7 +35,7 @@ impl MemStream {
         let fp = unsafe { libc::open_memstream(buf.as_mut(), buf_size.as_mut()) };
 
         if fp.is_null() {
-            return Err(errno::errno().into());
+            return Err(std::io::Error::last_os_error().into());
         }
 
         // SAFETY: We can call this because we know that the buffer is valid and will be expanded
@@ -44,7 +44,7 @@ impl MemStream {
         if res != 0 {
             // SAFETY: We know the file pointer is non-null, so this should be safe
             unsafe { libc::fclose(fp) };
-            return Err(errno::errno().into());
+            return Err(std::io::Error::last_os_error().into());
         }
 
         if buf.is_null() {
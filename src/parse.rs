@@ -0,0 +1,609 @@
+//! Incremental parsing of the XML output of `malloc_info`.
+//!
+//! Rather than deserializing the whole document in one go, the output is walked element by
+//! element and each leaf (`<size>`, `<total>`, `<system>`, `<aspace>`) is deserialized on its own.
+//! This allows two things the plain deserializer can't do:
+//!
+//! - Best-effort parsing: an element that fails to parse is recorded as [`Skipped`] and the walk
+//!   carries on with its siblings, so a single malformed entry doesn't cost the rest of the
+//!   snapshot.
+//! - Skipping the `<sizes>` sections, which make up the bulk of the output, when only the totals
+//!   are wanted.
+
+use crate::info::{Heap, Malloc, Size, Sizes};
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::{DeError, Reader};
+use serde::de::DeserializeOwned;
+#[cfg(feature = "serialize")]
+use serde::Serialize;
+
+/// Output of a best-effort parse: everything that could be parsed, plus a record of everything
+/// that could not
+#[derive(Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serialize", derive(Serialize))]
+pub struct Partial {
+    /// The successfully parsed portion of the output
+    pub info: Malloc,
+
+    /// Elements which were dropped from [`Partial::info`] because they failed to parse
+    pub skipped: Vec<Skipped>,
+}
+
+impl Partial {
+    /// Whether the whole document was parsed without skipping anything
+    pub fn is_complete(&self) -> bool {
+        self.skipped.is_empty()
+    }
+}
+
+/// An element which was dropped during a best-effort parse
+#[derive(Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serialize", derive(Serialize))]
+pub struct Skipped {
+    /// Name of the element which failed to parse, or empty if the XML itself was malformed and
+    /// the rest of the document had to be dropped
+    pub element: String,
+
+    /// Number of the arena the element belongs to, if it is (or is inside) a `<heap>` whose number
+    /// could be read
+    pub heap: Option<usize>,
+
+    /// Byte offset of the element in the XML output
+    pub offset: u64,
+
+    /// Why the element was skipped
+    pub reason: String,
+}
+
+/// Options controlling how the output of `malloc_info` is parsed, for use with
+/// [`malloc_info_with`](crate::malloc_info_with)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ParseOptions {
+    /// Whether to parse the per-arena `<sizes>` sections. When `false` they are skipped without
+    /// being tokenized attribute by attribute, and every [`Heap::sizes`] is `None`.
+    pub include_sizes: bool,
+
+    /// Maximum number of arenas to parse. Any further `<heap>` elements are skipped. The
+    /// document-level totals still cover every arena.
+    pub max_heaps: Option<usize>,
+
+    /// Whether to fail on the first element which can't be parsed. When `false`, such elements are
+    /// skipped and listed in [`Partial::skipped`] instead.
+    pub strict: bool,
+}
+
+impl Default for ParseOptions {
+    /// Parse everything and fail on any error, matching [`malloc_info`](crate::malloc_info)
+    fn default() -> Self {
+        Self {
+            include_sizes: true,
+            max_heaps: None,
+            strict: true,
+        }
+    }
+}
+
+/// Parse `xml` according to `options`. With [`ParseOptions::strict`] unset, an error is only
+/// returned if the `<malloc>` root element itself cannot be read.
+pub(crate) fn parse(xml: &[u8], options: &ParseOptions) -> Result<Partial, DeError> {
+    Parser::new(xml, *options).parse()
+}
+
+/// Parse `xml` strictly into `info`, reusing the capacity of its vectors, including those of its
+/// arenas. On error, `info` is left holding whatever was parsed up to that point.
+pub(crate) fn parse_into(xml: &[u8], info: &mut Malloc) -> Result<(), DeError> {
+    Parser::new(xml, ParseOptions::default()).parse_into(info)
+}
+
+/// Parse only the `<heap>` element for arena `nr` out of `xml`, skipping over every other arena
+/// and the document-level totals. Returns `None` if there is no such arena.
+pub(crate) fn parse_heap(xml: &[u8], nr: usize) -> Result<Option<Heap>, DeError> {
+    Parser::new(xml, ParseOptions::default()).find_heap(nr)
+}
+
+struct Parser<'a> {
+    xml: &'a [u8],
+    reader: Reader<&'a [u8]>,
+    skipped: Vec<Skipped>,
+    options: ParseOptions,
+
+    /// Whether the rest of the document was found unreadable, so that the loops of enclosing
+    /// elements stop rather than record it again
+    truncated: bool,
+}
+
+impl<'a> Parser<'a> {
+    fn new(xml: &'a [u8], options: ParseOptions) -> Self {
+        Self {
+            xml,
+            reader: Reader::from_reader(xml),
+            skipped: Vec::new(),
+            options,
+            truncated: false,
+        }
+    }
+
+    fn parse(mut self) -> Result<Partial, DeError> {
+        let mut info = Malloc {
+            version: String::new(),
+            heaps: Vec::new(),
+            total: Vec::new(),
+            system: Vec::new(),
+            aspace: Vec::new(),
+        };
+        self.parse_into(&mut info)?;
+
+        Ok(Partial {
+            info,
+            skipped: self.skipped,
+        })
+    }
+
+    /// Parse the document into `info`, overwriting its contents in place
+    fn parse_into(&mut self, info: &mut Malloc) -> Result<(), DeError> {
+        self.root(&mut info.version)?;
+        info.total.clear();
+        info.system.clear();
+        info.aspace.clear();
+
+        let mut heaps = 0;
+        loop {
+            let offset = self.reader.buffer_position();
+            let event = match self.reader.read_event() {
+                Ok(event) => event,
+                Err(e) => {
+                    self.truncated(offset, None, e.to_string())?;
+                    break;
+                }
+            };
+            match event {
+                Event::Start(e)
+                    if e.name().as_ref() == b"heap"
+                        && self.options.max_heaps.map_or(true, |max| heaps < max) =>
+                {
+                    let e = e.into_owned();
+                    if heaps == info.heaps.len() {
+                        info.heaps.push(empty_heap());
+                    }
+                    if self.heap(&e, offset, &mut info.heaps[heaps])? {
+                        heaps += 1;
+                    }
+                    if self.truncated {
+                        break;
+                    }
+                }
+                Event::Start(e) => {
+                    let e = e.into_owned();
+                    if let Err(err) = self.reader.read_to_end(e.name()) {
+                        self.truncated(offset, None, err.to_string())?;
+                        break;
+                    }
+                }
+                Event::Empty(e) => match e.name().as_ref() {
+                    b"total" => info.total.extend(self.leaf(offset, None)?),
+                    b"system" => info.system.extend(self.leaf(offset, None)?),
+                    b"aspace" => info.aspace.extend(self.leaf(offset, None)?),
+                    _ => {}
+                },
+                Event::End(e) if e.name().as_ref() == b"malloc" => break,
+                Event::Eof => {
+                    self.truncated(offset, None, "unexpected end of document".into())?;
+                    break;
+                }
+                _ => {}
+            }
+        }
+
+        info.heaps.truncate(heaps);
+        Ok(())
+    }
+
+    fn find_heap(mut self, nr: usize) -> Result<Option<Heap>, DeError> {
+        self.root(&mut String::new())?;
+        loop {
+            let offset = self.reader.buffer_position();
+            match self.reader.read_event()? {
+                Event::Start(e) if e.name().as_ref() == b"heap" => {
+                    let e = e.into_owned();
+                    if attribute::<usize>(&e, "nr") == Ok(nr) {
+                        let mut heap = empty_heap();
+                        return Ok(self.heap(&e, offset, &mut heap)?.then_some(heap));
+                    }
+                    self.reader.read_to_end(e.name())?;
+                }
+                Event::End(e) if e.name().as_ref() == b"malloc" => return Ok(None),
+                Event::Eof => {
+                    return Err(DeError::Custom("unexpected end of document".into()));
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Read up to and including the `<malloc>` start tag, storing its version attribute in
+    /// `version`
+    fn root(&mut self, version: &mut String) -> Result<(), DeError> {
+        loop {
+            match self.reader.read_event()? {
+                Event::Start(e) if e.name().as_ref() == b"malloc" => {
+                    return match e.try_get_attribute("version")? {
+                        Some(attr) => {
+                            version.clear();
+                            version.push_str(&attr.unescape_value()?);
+                            Ok(())
+                        }
+                        None => Err(DeError::Custom("missing field `@version`".into())),
+                    };
+                }
+                Event::Start(_) | Event::Empty(_) | Event::Eof => {
+                    return Err(DeError::Custom("expected <malloc> root element".into()));
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Parse a `<heap>` element whose start tag has just been read into `heap`, overwriting its
+    /// contents in place. Returns `false` if the arena number can't be read, in which case the
+    /// whole element is skipped.
+    fn heap(
+        &mut self,
+        start: &BytesStart<'_>,
+        offset: u64,
+        heap: &mut Heap,
+    ) -> Result<bool, DeError> {
+        let nr = match attribute::<usize>(start, "nr") {
+            Ok(nr) => nr,
+            Err(reason) => {
+                self.skip(offset, None, reason)?;
+                if let Err(e) = self.reader.read_to_end(start.name()) {
+                    self.truncated(offset, None, e.to_string())?;
+                }
+                return Ok(false);
+            }
+        };
+
+        heap.nr = nr;
+        heap.total.clear();
+        heap.system.clear();
+        heap.aspace.clear();
+        // Kept aside so its capacity can be reused if the arena has a `<sizes>` section
+        let mut previous_sizes = heap.sizes.take().and_then(|sizes| sizes.sizes);
+        loop {
+            let offset = self.reader.buffer_position();
+            match self.reader.read_event() {
+                Ok(Event::Start(e))
+                    if e.name().as_ref() == b"sizes" && self.options.include_sizes =>
+                {
+                    let mut sizes = previous_sizes.take().unwrap_or_default();
+                    sizes.clear();
+                    self.sizes(nr, &mut sizes)?;
+                    heap.sizes = Some(Sizes {
+                        sizes: if sizes.is_empty() { None } else { Some(sizes) },
+                    });
+                    if self.truncated {
+                        break;
+                    }
+                }
+                Ok(Event::Empty(e))
+                    if e.name().as_ref() == b"sizes" && self.options.include_sizes =>
+                {
+                    heap.sizes = Some(Sizes { sizes: None });
+                }
+                Ok(Event::Empty(e)) => match e.name().as_ref() {
+                    b"total" => heap.total.extend(self.leaf(offset, Some(nr))?),
+                    b"system" => heap.system.extend(self.leaf(offset, Some(nr))?),
+                    b"aspace" => heap.aspace.extend(self.leaf(offset, Some(nr))?),
+                    _ => {}
+                },
+                Ok(Event::Start(e)) => {
+                    let e = e.into_owned();
+                    if let Err(err) = self.reader.read_to_end(e.name()) {
+                        self.truncated(offset, Some(nr), err.to_string())?;
+                        break;
+                    }
+                }
+                Ok(Event::End(e)) if e.name().as_ref() == b"heap" => break,
+                Ok(Event::Eof) => {
+                    self.truncated(offset, Some(nr), "unexpected end of document".into())?;
+                    break;
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    self.truncated(offset, Some(nr), e.to_string())?;
+                    break;
+                }
+            }
+        }
+        Ok(true)
+    }
+
+    /// Parse the contents of a `<sizes>` element whose start tag has just been read, appending
+    /// the bins to `sizes`
+    fn sizes(&mut self, nr: usize, sizes: &mut Vec<Size>) -> Result<(), DeError> {
+        loop {
+            let offset = self.reader.buffer_position();
+            match self.reader.read_event() {
+                Ok(Event::Empty(_)) => sizes.extend(self.leaf::<Size>(offset, Some(nr))?),
+                Ok(Event::End(e)) if e.name().as_ref() == b"sizes" => break,
+                Ok(Event::Eof) => {
+                    self.truncated(offset, Some(nr), "unexpected end of document".into())?;
+                    break;
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    self.truncated(offset, Some(nr), e.to_string())?;
+                    break;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Deserialize the empty element which has just been read, starting at `offset`
+    fn leaf<T: DeserializeOwned>(
+        &mut self,
+        offset: u64,
+        heap: Option<usize>,
+    ) -> Result<Option<T>, DeError> {
+        let span = &self.xml[offset as usize..self.reader.buffer_position() as usize];
+        match quick_xml::de::from_reader(span) {
+            Ok(value) => Ok(Some(value)),
+            Err(e) if self.options.strict => Err(e),
+            Err(e) => {
+                self.skip(offset, heap, e.to_string())?;
+                Ok(None)
+            }
+        }
+    }
+
+    /// Record the element starting at `offset` as skipped, or fail if parsing strictly
+    fn skip(&mut self, offset: u64, heap: Option<usize>, reason: String) -> Result<(), DeError> {
+        let element = element_name(&self.xml[offset as usize..]);
+        self.record(element, offset, heap, reason)
+    }
+
+    /// Record that the rest of the document, from `offset`, could not be read, or fail if parsing
+    /// strictly
+    fn truncated(
+        &mut self,
+        offset: u64,
+        heap: Option<usize>,
+        reason: String,
+    ) -> Result<(), DeError> {
+        self.truncated = true;
+        self.record(String::new(), offset, heap, reason)
+    }
+
+    fn record(
+        &mut self,
+        element: String,
+        offset: u64,
+        heap: Option<usize>,
+        reason: String,
+    ) -> Result<(), DeError> {
+        if self.options.strict {
+            return Err(DeError::Custom(reason));
+        }
+        self.skipped.push(Skipped {
+            element,
+            heap,
+            offset,
+            reason,
+        });
+        Ok(())
+    }
+}
+
+/// A heap with nothing in it, to be parsed into
+fn empty_heap() -> Heap {
+    Heap {
+        nr: 0,
+        sizes: None,
+        total: Vec::new(),
+        system: Vec::new(),
+        aspace: Vec::new(),
+    }
+}
+
+/// Read and parse the attribute `name` from `start`
+fn attribute<T: std::str::FromStr>(start: &BytesStart<'_>, name: &str) -> Result<T, String>
+where
+    T::Err: std::fmt::Display,
+{
+    let attr = start
+        .try_get_attribute(name)
+        .map_err(|e| e.to_string())?
+        .ok_or_else(|| format!("missing field `@{name}`"))?;
+    let value = attr.unescape_value().map_err(|e| e.to_string())?;
+    value.parse().map_err(|e: T::Err| e.to_string())
+}
+
+/// Name of the element starting at the beginning of `xml`
+fn element_name(xml: &[u8]) -> String {
+    let name = xml
+        .iter()
+        .skip(1)
+        .take_while(|b| !b.is_ascii_whitespace() && !matches!(b, b'/' | b'>'))
+        .copied()
+        .collect::<Vec<_>>();
+    String::from_utf8_lossy(&name).into_owned()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const LENIENT: ParseOptions = ParseOptions {
+        include_sizes: true,
+        max_heaps: None,
+        strict: false,
+    };
+
+    const WITHOUT_SIZES: ParseOptions = ParseOptions {
+        include_sizes: false,
+        max_heaps: None,
+        strict: true,
+    };
+
+    // Taken from the malloc_info(3) man-page, with a few size bins added
+    const XML: &str = r#"<?xml version="1.0"?>
+<malloc version="1">
+<heap nr="0">
+<sizes>
+<size from="17" to="32" total="64" count="2"/>
+<unsorted from="1297" to="1297" total="1297" count="1"/>
+</sizes>
+<total type="fast" count="0" size="0"/>
+<total type="rest" count="3" size="1361"/>
+<system type="current" size="135168"/>
+<system type="max" size="135168"/>
+<aspace type="total" size="135168"/>
+<aspace type="mprotect" size="135168"/>
+</heap>
+<heap nr="1">
+<sizes>
+</sizes>
+<total type="fast" count="0" size="0"/>
+<total type="rest" count="0" size="0"/>
+<system type="current" size="135168"/>
+<system type="max" size="135168"/>
+<aspace type="total" size="135168"/>
+<aspace type="mprotect" size="135168"/>
+</heap>
+<total type="fast" count="0" size="0"/>
+<total type="rest" count="3" size="1361"/>
+<total type="mmap" count="0" size="0"/>
+<system type="current" size="270336"/>
+<system type="max" size="270336"/>
+<aspace type="total" size="270336"/>
+<aspace type="mprotect" size="270336"/>
+</malloc>
+"#;
+
+    #[test]
+    fn matches_serde() {
+        let expected: Malloc = quick_xml::de::from_str(XML).expect("parse XML");
+        let partial = parse(XML.as_bytes(), &LENIENT).expect("parse XML");
+        assert!(partial.is_complete(), "{:?}", partial.skipped);
+        assert_eq!(partial.info, expected);
+        assert_eq!(partial.info.heaps[0].total.len(), 2);
+        assert_eq!(partial.info.heaps[0].aspace.len(), 2);
+    }
+
+    #[test]
+    fn skip_malformed_size() {
+        let xml = XML.replace(r#"from="17""#, r#"from="seventeen""#);
+        let partial = parse(xml.as_bytes(), &LENIENT).expect("parse XML");
+        assert_eq!(partial.skipped.len(), 1);
+        assert_eq!(partial.skipped[0].element, "size");
+        assert_eq!(partial.skipped[0].heap, Some(0));
+        assert_eq!(&xml[partial.skipped[0].offset as usize..][..5], "<size");
+
+        let sizes = partial.info.heaps[0].sizes.as_ref().unwrap();
+        assert_eq!(sizes.sizes.as_ref().unwrap().len(), 1);
+        assert_eq!(partial.info.heaps.len(), 2);
+        assert_eq!(partial.info.total.len(), 3);
+    }
+
+    #[test]
+    fn skip_malformed_heap() {
+        let xml = XML.replace(r#"<heap nr="1">"#, r#"<heap nr="one">"#);
+        let partial = parse(xml.as_bytes(), &LENIENT).expect("parse XML");
+        assert_eq!(partial.skipped.len(), 1);
+        assert_eq!(partial.skipped[0].element, "heap");
+        assert_eq!(partial.info.heaps.len(), 1);
+        assert_eq!(partial.info.system.len(), 2);
+    }
+
+    #[test]
+    fn truncated() {
+        let xml = &XML[..XML.find("<heap nr=\"1\">").unwrap()];
+        let partial = parse(xml.as_bytes(), &LENIENT).expect("parse XML");
+        assert_eq!(partial.skipped.len(), 1);
+        assert_eq!(partial.info.heaps.len(), 1);
+        assert!(partial.info.total.is_empty());
+    }
+
+    #[test]
+    fn truncated_in_sizes() {
+        let xml = &XML[..XML.find("<unsorted").unwrap()];
+        let partial = parse(xml.as_bytes(), &LENIENT).expect("parse XML");
+        assert_eq!(partial.skipped.len(), 1, "{:?}", partial.skipped);
+        assert_eq!(partial.skipped[0].heap, Some(0));
+        assert_eq!(partial.info.heaps.len(), 1);
+        let sizes = partial.info.heaps[0].sizes.as_ref().unwrap();
+        assert_eq!(sizes.sizes.as_ref().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn without_sizes() {
+        let info = parse(XML.as_bytes(), &WITHOUT_SIZES)
+            .expect("parse XML")
+            .info;
+        assert_eq!(info.heaps.len(), 2);
+        assert!(info.heaps.iter().all(|heap| heap.sizes.is_none()));
+        assert_eq!(info.total.len(), 3);
+        assert_eq!(info.system.len(), 2);
+        assert_eq!(info.aspace.len(), 2);
+    }
+
+    #[test]
+    fn without_sizes_ignores_malformed_size() {
+        let xml = XML.replace(r#"from="17""#, r#"from="seventeen""#);
+        let info = parse(xml.as_bytes(), &WITHOUT_SIZES)
+            .expect("parse XML")
+            .info;
+        assert_eq!(info.heaps.len(), 2);
+    }
+
+    #[test]
+    fn without_sizes_strict() {
+        let xml = XML.replace(
+            r#"<system type="max" size="270336"/>"#,
+            r#"<system size="x"/>"#,
+        );
+        assert!(parse(xml.as_bytes(), &WITHOUT_SIZES).is_err());
+    }
+
+    #[test]
+    fn max_heaps() {
+        let options = ParseOptions {
+            max_heaps: Some(1),
+            ..Default::default()
+        };
+        let partial = parse(XML.as_bytes(), &options).expect("parse XML");
+        assert!(partial.is_complete());
+        assert_eq!(partial.info.heaps.len(), 1);
+        assert_eq!(partial.info.heaps[0].nr, 0);
+        assert_eq!(partial.info.total.len(), 3);
+    }
+
+    #[test]
+    fn strict() {
+        let xml = XML.replace(r#"from="17""#, r#"from="seventeen""#);
+        assert!(parse(xml.as_bytes(), &ParseOptions::default()).is_err());
+    }
+
+    #[test]
+    fn heap() {
+        let expected: Malloc = quick_xml::de::from_str(XML).expect("parse XML");
+        for heap in &expected.heaps {
+            let parsed = parse_heap(XML.as_bytes(), heap.nr).expect("parse XML");
+            assert_eq!(parsed.as_ref(), Some(heap));
+        }
+        assert_eq!(parse_heap(XML.as_bytes(), 2).expect("parse XML"), None);
+    }
+
+    #[test]
+    fn heap_ignores_other_arenas() {
+        let xml = XML.replace(r#"from="17""#, r#"from="seventeen""#);
+        assert!(parse_heap(xml.as_bytes(), 0).is_err());
+        assert!(parse_heap(xml.as_bytes(), 1).expect("parse XML").is_some());
+    }
+
+    #[test]
+    fn missing_root() {
+        assert!(parse(b"<heap nr=\"0\"></heap>", &LENIENT).is_err());
+    }
+}
@@ -0,0 +1,541 @@
+//! Rendering snapshots in the Prometheus text exposition format.
+//!
+//! Every metric is a gauge named `malloc_info_*`, taken from the document-level totals of a
+//! snapshot. Extra labels, such as the name or pid of the process, can be attached to every
+//! series so that several processes can report side by side. [`render_with`] can expose some of
+//! the metrics as counters instead, see [`Semantics`]. An info-style
+//! `malloc_info_build_info` series, always 1, carries the [`BuildInfo`](crate::BuildInfo) of the
+//! process in its labels.
+//!
+//! [`write_textfile`] writes the rendered metrics for node_exporter's textfile collector, so hosts
+//! already running node_exporter can pick up per-process malloc metrics without the process
+//! serving anything. For short-lived batch jobs, [`push`] sends them to a Pushgateway instead.
+
+use crate::http;
+use crate::info::{self, SystemType};
+use std::fmt::Write as _;
+use std::fs;
+use std::io::{self, Write as _};
+use std::path::Path;
+use std::time::Duration;
+
+/// Timeout for each network operation when pushing to a Pushgateway
+const PUSH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Metrics rendered from the totals of a snapshot
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Metric {
+    /// `malloc_info_arenas`
+    Arenas,
+
+    /// `malloc_info_total_bytes`
+    TotalBytes,
+
+    /// `malloc_info_total_chunks`
+    TotalChunks,
+
+    /// `malloc_info_system_bytes`
+    SystemBytes,
+
+    /// `malloc_info_aspace_bytes`
+    AspaceBytes,
+}
+
+impl Metric {
+    /// Every metric, in the order they are rendered
+    pub const ALL: [Metric; 5] = [
+        Metric::Arenas,
+        Metric::TotalBytes,
+        Metric::TotalChunks,
+        Metric::SystemBytes,
+        Metric::AspaceBytes,
+    ];
+
+    /// Name of the metric, when exposed as a gauge
+    pub fn name(self) -> &'static str {
+        match self {
+            Metric::Arenas => "malloc_info_arenas",
+            Metric::TotalBytes => "malloc_info_total_bytes",
+            Metric::TotalChunks => "malloc_info_total_chunks",
+            Metric::SystemBytes => "malloc_info_system_bytes",
+            Metric::AspaceBytes => "malloc_info_aspace_bytes",
+        }
+    }
+
+    /// Look up a metric by its name, with or without the `malloc_info_` prefix
+    pub fn from_name(name: &str) -> Option<Self> {
+        let name = name.strip_prefix("malloc_info_").unwrap_or(name);
+        Self::ALL
+            .into_iter()
+            .find(|metric| &metric.name()["malloc_info_".len()..] == name)
+    }
+}
+
+/// How metrics are exposed. The default exposes every metric as a gauge, exactly as [`render`]
+/// does.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Semantics {
+    /// Metrics exposed as counters, with a `_total` suffix, rather than as gauges. Only declare
+    /// metrics which never decrease in your program: `rate()` reads any drop as a counter reset.
+    pub counters: Vec<Metric>,
+
+    /// Expose the high-water mark of the memory obtained from the system as a gauge of its own,
+    /// `malloc_info_system_bytes_max`, rather than as the `max` type of `malloc_info_system_bytes`.
+    /// This keeps it apart when `malloc_info_system_bytes` is a counter.
+    pub split_max: bool,
+}
+
+/// Render `info` in the Prometheus text exposition format (version 0.0.4), attaching `labels` to
+/// every series.
+///
+/// Label names must match `[a-zA-Z_][a-zA-Z0-9_]*` and must not be `type` or one of the labels of
+/// `malloc_info_build_info`, which the crate uses itself; otherwise an error of kind
+/// [`InvalidInput`](io::ErrorKind::InvalidInput) is returned.
+pub fn render(info: &info::Malloc, labels: &[(&str, &str)]) -> io::Result<String> {
+    render_with(info, labels, &Semantics::default())
+}
+
+/// Render `info` like [`render`], exposing the metrics as declared by `semantics`
+pub fn render_with(
+    info: &info::Malloc,
+    labels: &[(&str, &str)],
+    semantics: &Semantics,
+) -> io::Result<String> {
+    crate::labels::validate(labels.iter().map(|(name, _)| *name))?;
+    let mut exposition = Exposition::new(labels, semantics);
+
+    exposition.metric(Metric::Arenas, "Number of malloc arenas");
+    exposition.sample(None, info.heaps.len() as u64);
+
+    exposition.metric(
+        Metric::TotalBytes,
+        "Bytes in free chunks (fast, rest) or allocated with mmap, by type",
+    );
+    for total in &info.total {
+        exposition.sample(Some(total.r#type.as_str()), total.size);
+    }
+    exposition.metric(
+        Metric::TotalChunks,
+        "Number of free chunks (fast, rest) or mmapped chunks, by type",
+    );
+    for total in &info.total {
+        exposition.sample(Some(total.r#type.as_str()), total.count);
+    }
+
+    exposition.metric(
+        Metric::SystemBytes,
+        if semantics.split_max {
+            "Bytes currently obtained from the system, by type"
+        } else {
+            "Bytes obtained from the system, currently and at most, by type"
+        },
+    );
+    let mut max = None;
+    for system in &info.system {
+        if semantics.split_max && system.r#type == SystemType::Max {
+            max = Some(system.size);
+        } else {
+            exposition.sample(Some(system.r#type.as_str()), system.size);
+        }
+    }
+    if let Some(max) = max {
+        exposition.gauge(
+            "malloc_info_system_bytes_max",
+            "Maximum bytes ever obtained from the system",
+        );
+        exposition.sample(None, max);
+    }
+
+    exposition.metric(
+        Metric::AspaceBytes,
+        "Address space used by the arenas, by type",
+    );
+    for aspace in &info.aspace {
+        exposition.sample(Some(aspace.r#type.as_str()), aspace.size);
+    }
+
+    let build_info = crate::build_info();
+    exposition.gauge(
+        "malloc_info_build_info",
+        "Runtime environment, by glibc version, crate version and allocator kind",
+    );
+    exposition.info(&[
+        ("glibc_version", &build_info.glibc_version),
+        ("version", build_info.crate_version),
+        ("allocator", build_info.allocator.as_str()),
+    ]);
+
+    Ok(exposition.out)
+}
+
+/// Atomically write the metrics for `info` to `path`, in the format read by node_exporter's
+/// textfile collector. The file name should end in `.prom`. `labels` are checked as by
+/// [`render`].
+///
+/// The metrics are first written to a temporary file next to `path` and then renamed over it, so
+/// the collector never sees a partially written file.
+pub fn write_textfile(
+    path: impl AsRef<Path>,
+    info: &info::Malloc,
+    labels: &[(&str, &str)],
+) -> io::Result<()> {
+    let path = path.as_ref();
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(format!(".{}.tmp", std::process::id()));
+
+    let result = (|| {
+        let mut file = fs::File::create(&tmp)?;
+        file.write_all(render(info, labels)?.as_bytes())?;
+        file.sync_all()?;
+        fs::rename(&tmp, path)
+    })();
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp);
+    }
+    result
+}
+
+/// Push the metrics for `info` to the Pushgateway at `url`, grouped under `job` and, if given,
+/// `instance`. Any metrics previously pushed to the same group are replaced.
+///
+/// `url` is the base URL of the Pushgateway, such as `http://pushgateway:9091`. Only plain HTTP is
+/// supported. Each network operation times out after ten seconds.
+pub fn push(url: &str, job: &str, instance: Option<&str>, info: &info::Malloc) -> io::Result<()> {
+    let mut path = format!("/metrics/{}", grouping_key("job", job));
+    if let Some(instance) = instance {
+        path.push('/');
+        path.push_str(&grouping_key("instance", instance));
+    }
+    http::request(
+        "Pushgateway",
+        "PUT",
+        url,
+        &path,
+        "text/plain; version=0.0.4",
+        render(info, &[])?.as_bytes(),
+        PUSH_TIMEOUT,
+    )
+}
+
+/// The path segments for the label `name` with `value` in a Pushgateway grouping key. Values the
+/// Pushgateway can't take percent-encoded, empty ones and those containing a `/`, are sent in its
+/// base64 form as `<name>@base64/<value>`.
+fn grouping_key(name: &str, value: &str) -> String {
+    if value.is_empty() {
+        // An empty segment would be dropped, so the Pushgateway takes a lone padding character
+        format!("{name}@base64/=")
+    } else if value.contains('/') {
+        format!("{name}@base64/{}", base64_url(value.as_bytes()))
+    } else {
+        format!("{name}/{}", escape_path(value))
+    }
+}
+
+/// Encode `bytes` as padded base64 with the URL and filename safe alphabet of RFC 4648
+fn base64_url(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
+
+    let mut out = String::with_capacity((bytes.len() + 2) / 3 * 4);
+    for chunk in bytes.chunks(3) {
+        let group = chunk.iter().enumerate().fold(0u32, |group, (i, &byte)| {
+            group | u32::from(byte) << (16 - 8 * i)
+        });
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[(group >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+/// Percent-encode `segment` for use as a single segment of a URL path
+fn escape_path(segment: &str) -> String {
+    let mut out = String::with_capacity(segment.len());
+    for byte in segment.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                out.push(byte as char)
+            }
+            _ => {
+                let _ = write!(out, "%{byte:02X}");
+            }
+        }
+    }
+    out
+}
+
+/// Builder for the text of an exposition
+struct Exposition<'a> {
+    out: String,
+
+    /// Formatted labels attached to every series
+    labels: String,
+
+    /// Whether each metric is a gauge or a counter
+    semantics: &'a Semantics,
+
+    /// Name of the metric currently being written
+    name: String,
+}
+
+impl<'a> Exposition<'a> {
+    fn new(labels: &[(&str, &str)], semantics: &'a Semantics) -> Self {
+        Self {
+            out: String::new(),
+            labels: format_labels(labels),
+            semantics,
+            name: String::new(),
+        }
+    }
+
+    /// Start a new metric, of the type declared for it by the semantics
+    fn metric(&mut self, metric: Metric, help: &str) {
+        if self.semantics.counters.contains(&metric) {
+            self.start(format!("{}_total", metric.name()), "counter", help);
+        } else {
+            self.gauge(metric.name(), help);
+        }
+    }
+
+    /// Start a new gauge metric
+    fn gauge(&mut self, name: &str, help: &str) {
+        self.start(name.to_owned(), "gauge", help);
+    }
+
+    fn start(&mut self, name: String, r#type: &str, help: &str) {
+        let _ = writeln!(self.out, "# HELP {name} {help}");
+        let _ = writeln!(self.out, "# TYPE {name} {type}");
+        self.name = name;
+    }
+
+    /// Write a sample of the current metric, labelled with `type` if given
+    fn sample(&mut self, r#type: Option<&str>, value: u64) {
+        let (name, labels) = (&self.name, &self.labels);
+        let _ = match (r#type, labels.is_empty()) {
+            (None, true) => writeln!(self.out, "{name} {value}"),
+            (None, false) => writeln!(self.out, "{name}{{{labels}}} {value}"),
+            (Some(r#type), true) => writeln!(self.out, "{name}{{type=\"{type}\"}} {value}"),
+            (Some(r#type), false) => {
+                writeln!(self.out, "{name}{{{labels},type=\"{type}\"}} {value}")
+            }
+        };
+    }
+
+    /// Write an info-style sample of the current metric: 1, labelled with `info`
+    fn info(&mut self, info: &[(&str, &str)]) {
+        let (name, labels) = (&self.name, &self.labels);
+        let info = format_labels(info);
+        let _ = if labels.is_empty() {
+            writeln!(self.out, "{name}{{{info}}} 1")
+        } else {
+            writeln!(self.out, "{name}{{{labels},{info}}} 1")
+        };
+    }
+}
+
+/// Format `labels` as the comma separated contents of a label set
+fn format_labels(labels: &[(&str, &str)]) -> String {
+    let mut out = String::new();
+    for (name, value) in labels {
+        if !out.is_empty() {
+            out.push(',');
+        }
+        let _ = write!(out, "{name}=\"");
+        for c in value.chars() {
+            match c {
+                '\\' => out.push_str("\\\\"),
+                '"' => out.push_str("\\\""),
+                '\n' => out.push_str("\\n"),
+                c => out.push(c),
+            }
+        }
+        out.push('"');
+    }
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // Taken from the malloc_info(3) man-page
+    const XML: &str = r#"
+<malloc version="1">
+<heap nr="0">
+<sizes>
+</sizes>
+<total type="fast" count="0" size="0"/>
+<total type="rest" count="0" size="0"/>
+<system type="current" size="135168"/>
+<system type="max" size="135168"/>
+<aspace type="total" size="135168"/>
+<aspace type="mprotect" size="135168"/>
+</heap>
+<total type="fast" count="0" size="0"/>
+<total type="rest" count="0" size="0"/>
+<total type="mmap" count="1" size="266240"/>
+<system type="current" size="135168"/>
+<system type="max" size="135168"/>
+<aspace type="total" size="135168"/>
+<aspace type="mprotect" size="135168"/>
+</malloc>
+"#;
+
+    #[test]
+    fn render_simple() {
+        let info = quick_xml::de::from_str(XML).expect("parse XML");
+        let text = render(&info, &[]).expect("render");
+        assert!(text.contains("# TYPE malloc_info_arenas gauge\n"));
+        assert!(text.contains("\nmalloc_info_arenas 1\n"));
+        assert!(text.contains("\nmalloc_info_total_bytes{type=\"mmap\"} 266240\n"));
+        assert!(text.contains("\nmalloc_info_total_chunks{type=\"mmap\"} 1\n"));
+        assert!(text.contains("\nmalloc_info_system_bytes{type=\"max\"} 135168\n"));
+        assert!(text.contains("\nmalloc_info_aspace_bytes{type=\"mprotect\"} 135168\n"));
+
+        let build_info = crate::build_info();
+        assert!(text.contains(&format!(
+            "\nmalloc_info_build_info{{glibc_version=\"{}\",version=\"{}\",allocator=\"glibc\"}} 1\n",
+            build_info.glibc_version, build_info.crate_version
+        )));
+    }
+
+    #[test]
+    fn render_labels() {
+        let info = quick_xml::de::from_str(XML).expect("parse XML");
+        let text = render(&info, &[("process", "a\"b\\c\nd"), ("pid", "1")]).expect("render");
+        assert!(text.contains("\nmalloc_info_arenas{process=\"a\\\"b\\\\c\\nd\",pid=\"1\"} 1\n"));
+        assert!(text.contains(
+            "\nmalloc_info_system_bytes{process=\"a\\\"b\\\\c\\nd\",pid=\"1\",type=\"current\"} \
+             135168\n"
+        ));
+        assert!(text.contains(
+            "\nmalloc_info_build_info{process=\"a\\\"b\\\\c\\nd\",pid=\"1\",glibc_version="
+        ));
+
+        for name in ["pod-name", "type", "version", "allocator"] {
+            let error = render(&info, &[(name, "x")]).expect_err(name);
+            assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+        }
+        let error = write_textfile(
+            std::env::temp_dir().join("malloc-info-invalid.prom"),
+            &info,
+            &[("pod-name", "x")],
+        )
+        .expect_err("invalid label");
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn render_semantics() {
+        let info = quick_xml::de::from_str(XML).expect("parse XML");
+        let semantics = Semantics {
+            counters: vec![Metric::TotalChunks, Metric::SystemBytes],
+            split_max: true,
+        };
+        let text = render_with(&info, &[], &semantics).expect("render");
+        assert!(text.contains("# TYPE malloc_info_arenas gauge\n"));
+        assert!(text.contains("# TYPE malloc_info_total_chunks_total counter\n"));
+        assert!(text.contains("\nmalloc_info_total_chunks_total{type=\"mmap\"} 1\n"));
+        assert!(text.contains("# TYPE malloc_info_system_bytes_total counter\n"));
+        assert!(text.contains("\nmalloc_info_system_bytes_total{type=\"current\"} 135168\n"));
+        assert!(!text.contains("type=\"max\""));
+        assert!(text.contains("# TYPE malloc_info_system_bytes_max gauge\n"));
+        assert!(text.contains("\nmalloc_info_system_bytes_max 135168\n"));
+
+        assert_eq!(
+            render_with(&info, &[], &Semantics::default()).expect("render"),
+            render(&info, &[]).expect("render")
+        );
+        assert_eq!(Metric::from_name("total_chunks"), Some(Metric::TotalChunks));
+        assert_eq!(
+            Metric::from_name("malloc_info_arenas"),
+            Some(Metric::Arenas)
+        );
+        assert_eq!(Metric::from_name("arena"), None);
+    }
+
+    #[test]
+    fn grouping_keys() {
+        assert_eq!(grouping_key("job", "batch job"), "job/batch%20job");
+        assert_eq!(grouping_key("job", ""), "job@base64/=");
+        assert_eq!(
+            grouping_key("instance", "host/1"),
+            "instance@base64/aG9zdC8x"
+        );
+        assert_eq!(base64_url(b"/a"), "L2E=");
+        assert_eq!(base64_url(b"??>"), "Pz8-");
+        assert_eq!(base64_url(b"a/b/c"), "YS9iL2M=");
+    }
+
+    #[test]
+    fn push_gateway() {
+        use std::io::Read;
+        use std::net::TcpListener;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/prefix/", listener.local_addr().unwrap());
+        let server = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0; 4096];
+            while !String::from_utf8_lossy(&request).contains("malloc_info_aspace_bytes{") {
+                let n = stream.read(&mut buf).unwrap();
+                assert_ne!(n, 0);
+                request.extend_from_slice(&buf[..n]);
+            }
+            stream
+                .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n")
+                .unwrap();
+            String::from_utf8(request).unwrap()
+        });
+
+        let info = quick_xml::de::from_str(XML).expect("parse XML");
+        push(&url, "batch job", Some("host/1"), &info).expect("push");
+
+        let request = server.join().unwrap();
+        assert!(
+            request.starts_with("PUT /prefix/metrics/job/batch%20job/instance@base64/aG9zdC8x ")
+        );
+        assert!(request.contains("\r\n\r\n# HELP malloc_info_arenas "));
+        assert!(request.contains("\nmalloc_info_arenas 1\n"));
+    }
+
+    #[test]
+    fn push_rejected() {
+        use std::net::TcpListener;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let server = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            stream
+                .write_all(b"HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\n\r\n")
+                .unwrap();
+        });
+
+        let info = quick_xml::de::from_str(XML).expect("parse XML");
+        assert!(push(&url, "job", None, &info).is_err());
+        server.join().unwrap();
+        assert!(push("https://localhost", "job", None, &info).is_err());
+    }
+
+    #[test]
+    fn textfile() {
+        let dir = std::env::temp_dir().join(format!("malloc-info-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("malloc.prom");
+
+        let info = crate::malloc_info().expect("malloc_info");
+        write_textfile(&path, &info, &[]).expect("write");
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            render(&info, &[]).expect("render")
+        );
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
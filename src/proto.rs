@@ -0,0 +1,274 @@
+//! Protocol Buffers representation of snapshots, for consumers written in other languages.
+//!
+//! The schema lives in `proto/malloc_info.proto` in the crate's source, package `malloc_info.v1`.
+//! The message types here are what `prost-build` generates from it; they are checked in so that
+//! building this crate doesn't require `protoc`. Convert a snapshot with [`From`], then encode it
+//! with [`prost::Message::encode_to_vec`].
+//!
+//! ```rust
+//! # use malloc_info::{malloc_info, proto};
+//! use prost::Message;
+//!
+//! let info = malloc_info().expect("malloc_info");
+//! let bytes = proto::Malloc::from(&info).encode_to_vec();
+//! ```
+
+use crate::info;
+
+/// Top-level snapshot, as returned by malloc_info
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Malloc {
+    #[prost(string, tag = "1")]
+    pub version: ::prost::alloc::string::String,
+    #[prost(message, repeated, tag = "2")]
+    pub heaps: ::prost::alloc::vec::Vec<Heap>,
+    #[prost(message, repeated, tag = "3")]
+    pub total: ::prost::alloc::vec::Vec<Total>,
+    #[prost(message, repeated, tag = "4")]
+    pub system: ::prost::alloc::vec::Vec<System>,
+    #[prost(message, repeated, tag = "5")]
+    pub aspace: ::prost::alloc::vec::Vec<Aspace>,
+}
+
+/// Arena-specific heap information
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Heap {
+    /// Arena number
+    #[prost(uint64, tag = "1")]
+    pub nr: u64,
+    /// Free chunk size bins. Empty if the arena reported none.
+    #[prost(message, repeated, tag = "2")]
+    pub sizes: ::prost::alloc::vec::Vec<Size>,
+    /// Totals of the arena
+    #[prost(message, repeated, tag = "3")]
+    pub total: ::prost::alloc::vec::Vec<Total>,
+    #[prost(message, repeated, tag = "4")]
+    pub system: ::prost::alloc::vec::Vec<System>,
+    #[prost(message, repeated, tag = "5")]
+    pub aspace: ::prost::alloc::vec::Vec<Aspace>,
+}
+
+/// A size bin of an arena
+#[derive(Clone, Copy, PartialEq, ::prost::Message)]
+pub struct Size {
+    #[prost(enumeration = "SizeKind", tag = "1")]
+    pub kind: i32,
+    #[prost(uint64, tag = "2")]
+    pub from: u64,
+    #[prost(uint64, tag = "3")]
+    pub to: u64,
+    #[prost(uint64, tag = "4")]
+    pub total: u64,
+    #[prost(uint64, tag = "5")]
+    pub count: u64,
+}
+
+/// Total memory information
+#[derive(Clone, Copy, PartialEq, ::prost::Message)]
+pub struct Total {
+    #[prost(enumeration = "TotalType", tag = "1")]
+    pub r#type: i32,
+    #[prost(uint64, tag = "2")]
+    pub count: u64,
+    #[prost(uint64, tag = "3")]
+    pub size: u64,
+}
+
+/// System memory information
+#[derive(Clone, Copy, PartialEq, ::prost::Message)]
+pub struct System {
+    #[prost(enumeration = "SystemType", tag = "1")]
+    pub r#type: i32,
+    #[prost(uint64, tag = "2")]
+    pub size: u64,
+}
+
+/// Arena space information
+#[derive(Clone, Copy, PartialEq, ::prost::Message)]
+pub struct Aspace {
+    #[prost(enumeration = "AspaceType", tag = "1")]
+    pub r#type: i32,
+    #[prost(uint64, tag = "2")]
+    pub size: u64,
+}
+
+/// Kinds of size bins
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, ::prost::Enumeration)]
+#[repr(i32)]
+pub enum SizeKind {
+    Unspecified = 0,
+    Size = 1,
+    Unsorted = 2,
+}
+
+/// Types of total memory
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, ::prost::Enumeration)]
+#[repr(i32)]
+pub enum TotalType {
+    Unspecified = 0,
+    Fast = 1,
+    Rest = 2,
+    Mmap = 3,
+    Other = 4,
+}
+
+/// Types of system memory
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, ::prost::Enumeration)]
+#[repr(i32)]
+pub enum SystemType {
+    Unspecified = 0,
+    Current = 1,
+    Max = 2,
+    Other = 3,
+}
+
+/// Types of arena space
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, ::prost::Enumeration)]
+#[repr(i32)]
+pub enum AspaceType {
+    Unspecified = 0,
+    Total = 1,
+    Mprotect = 2,
+    Subheaps = 3,
+    Other = 4,
+}
+
+impl From<&info::Malloc> for Malloc {
+    fn from(info: &info::Malloc) -> Self {
+        Self {
+            version: info.version.clone(),
+            heaps: info.heaps.iter().map(Heap::from).collect(),
+            total: info.total.iter().map(Total::from).collect(),
+            system: info.system.iter().map(System::from).collect(),
+            aspace: info.aspace.iter().map(Aspace::from).collect(),
+        }
+    }
+}
+
+impl From<info::Malloc> for Malloc {
+    fn from(info: info::Malloc) -> Self {
+        Self::from(&info)
+    }
+}
+
+impl From<&info::Heap> for Heap {
+    fn from(heap: &info::Heap) -> Self {
+        Self {
+            nr: heap.nr as u64,
+            sizes: heap.sizes.iter().flatten().map(Size::from).collect(),
+            total: heap.total.iter().map(Total::from).collect(),
+            system: heap.system.iter().map(System::from).collect(),
+            aspace: heap.aspace.iter().map(Aspace::from).collect(),
+        }
+    }
+}
+
+impl From<&info::Size> for Size {
+    fn from(size: &info::Size) -> Self {
+        let kind = match size.kind {
+            info::SizeKind::Size => SizeKind::Size,
+            info::SizeKind::Unsorted => SizeKind::Unsorted,
+        };
+        Self {
+            kind: kind as i32,
+            from: size.from,
+            to: size.to,
+            total: size.total,
+            count: size.count,
+        }
+    }
+}
+
+impl From<&info::Total> for Total {
+    fn from(total: &info::Total) -> Self {
+        let r#type = match total.r#type {
+            info::TotalType::Fast => TotalType::Fast,
+            info::TotalType::Rest => TotalType::Rest,
+            info::TotalType::Mmap => TotalType::Mmap,
+            info::TotalType::Other => TotalType::Other,
+        };
+        Self {
+            r#type: r#type as i32,
+            count: total.count,
+            size: total.size,
+        }
+    }
+}
+
+impl From<&info::System> for System {
+    fn from(system: &info::System) -> Self {
+        let r#type = match system.r#type {
+            info::SystemType::Current => SystemType::Current,
+            info::SystemType::Max => SystemType::Max,
+            info::SystemType::Other => SystemType::Other,
+        };
+        Self {
+            r#type: r#type as i32,
+            size: system.size,
+        }
+    }
+}
+
+impl From<&info::Aspace> for Aspace {
+    fn from(aspace: &info::Aspace) -> Self {
+        let r#type = match aspace.r#type {
+            info::AspaceType::Total => AspaceType::Total,
+            info::AspaceType::Mprotect => AspaceType::Mprotect,
+            info::AspaceType::Subheaps => AspaceType::Subheaps,
+            info::AspaceType::Other => AspaceType::Other,
+        };
+        Self {
+            r#type: r#type as i32,
+            size: aspace.size,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use prost::Message;
+
+    #[test]
+    fn round_trip() {
+        let info = crate::malloc_info().expect("malloc_info");
+        let proto = Malloc::from(&info);
+        assert_eq!(proto.heaps.len(), info.heaps.len());
+        assert_eq!(proto.total.len(), info.total.len());
+
+        let decoded = Malloc::decode(proto.encode_to_vec().as_slice()).expect("decode");
+        assert_eq!(decoded, proto);
+    }
+
+    #[test]
+    fn convert() {
+        let info: info::Malloc = quick_xml::de::from_str(
+            r#"<malloc version="1">
+<heap nr="3">
+<sizes>
+<unsorted from="1297" to="1297" total="1297" count="1"/>
+</sizes>
+<total type="rest" count="1" size="1297"/>
+<system type="current" size="135168"/>
+<aspace type="total" size="135168"/>
+</heap>
+<total type="mmap" count="1" size="266240"/>
+<system type="max" size="135168"/>
+<aspace type="subheaps" size="1"/>
+</malloc>"#,
+        )
+        .expect("parse XML");
+        let proto = Malloc::from(info);
+        assert_eq!(proto.version, "1");
+        assert_eq!(proto.heaps[0].nr, 3);
+        assert_eq!(proto.heaps[0].sizes[0].kind(), SizeKind::Unsorted);
+        assert_eq!(proto.heaps[0].sizes[0].total, 1297);
+        assert_eq!(proto.heaps[0].total[0].r#type(), TotalType::Rest);
+        assert_eq!(proto.heaps[0].system[0].size, 135168);
+        assert_eq!(proto.heaps[0].aspace[0].r#type(), AspaceType::Total);
+        assert_eq!(proto.total[0].r#type(), TotalType::Mmap);
+        assert_eq!(proto.total[0].size, 266240);
+        assert_eq!(proto.system[0].r#type(), SystemType::Max);
+        assert_eq!(proto.aspace[0].r#type(), AspaceType::Subheaps);
+    }
+}
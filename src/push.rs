@@ -0,0 +1,430 @@
+//! Pushing snapshots to a collector over HTTP, for sidecar and daemonset collection models where
+//! nothing is allowed to scrape into the process.
+//!
+//! A [`Pusher`] queues snapshots and sends them from its own thread in batches, as a single JSON
+//! `POST` per batch:
+//!
+//! ```json
+//! {
+//!   "labels": {"pod": "api-7d9f"},
+//!   "snapshots": [{"timestamp_ms": 1700000000000, "malloc": {"version": "1", ...}}]
+//! }
+//! ```
+//!
+//! where each `malloc` is a snapshot serialized as with the `serialize` feature. A batch is sent
+//! once it holds [`Config::batch_size`] snapshots, or [`Config::max_delay`] after its first
+//! snapshot was queued. Failed requests are retried with exponential backoff, and a batch is
+//! dropped once [`Config::max_retries`] is exhausted. While the collector is slow or down, the
+//! queue fills up to [`Config::queue_capacity`], after which new snapshots are rejected instead of
+//! piling up in memory.
+//!
+//! ```no_run
+//! # use malloc_info::push::{start_push, Config};
+//! use std::time::Duration;
+//!
+//! let pusher = start_push(Config::new("http://collector:8080/ingest"))?;
+//! loop {
+//!     pusher.capture()?;
+//!     std::thread::sleep(Duration::from_secs(15));
+//! }
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+
+use crate::{env, http, info, Error};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError, SyncSender, TrySendError};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Environment variable holding the URL of the collector
+pub const ENV_URL: &str = "MALLOC_INFO_PUSH_URL";
+
+/// Environment variable holding labels for every batch, as comma separated `name=value` pairs
+pub const ENV_LABELS: &str = "MALLOC_INFO_PUSH_LABELS";
+
+/// Configuration of a [`Pusher`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct Config {
+    /// URL to `POST` batches to. Only plain HTTP is supported.
+    pub url: String,
+
+    /// Labels sent with every batch, identifying the process to the collector. Names must
+    /// match `[a-zA-Z_][a-zA-Z0-9_]*` and not be one of the label names the crate uses itself in
+    /// its Prometheus metrics: `type`, `glibc_version`, `version` and `allocator`.
+    pub labels: Vec<(String, String)>,
+
+    /// Number of snapshots sent together
+    pub batch_size: usize,
+
+    /// Longest time a snapshot waits for its batch to fill up before being sent anyway
+    pub max_delay: Duration,
+
+    /// Number of snapshots which can be queued while a batch is being sent
+    pub queue_capacity: usize,
+
+    /// Number of times a failed request is retried before its batch is dropped
+    pub max_retries: u32,
+
+    /// Delay before the first retry, doubling with every further retry
+    pub retry_backoff: Duration,
+
+    /// Timeout of each network operation of a request
+    pub timeout: Duration,
+}
+
+impl Config {
+    /// Push to `url` without labels, in batches of 10 sent at least every minute, retrying three
+    /// times starting after a second, with room for 100 queued snapshots
+    pub fn new(url: impl Into<String>) -> Self {
+        Self {
+            url: url.into(),
+            labels: Vec::new(),
+            batch_size: 10,
+            max_delay: Duration::from_secs(60),
+            queue_capacity: 100,
+            max_retries: 3,
+            retry_backoff: Duration::from_secs(1),
+            timeout: Duration::from_secs(10),
+        }
+    }
+
+    /// Read the URL from [`ENV_URL`], which must be set, and the labels from [`ENV_LABELS`].
+    /// Everything else is as in [`Config::new`].
+    pub fn from_env() -> io::Result<Self> {
+        let url = env::var(ENV_URL)?
+            .ok_or_else(|| env::invalid_input(format!("{ENV_URL} is not set")))?;
+        let mut config = Self::new(url);
+        config.labels = env::labels(ENV_LABELS)?;
+        Ok(config)
+    }
+}
+
+/// Handle to a running pusher. Dropping the handle stops accepting snapshots, while the ones
+/// already queued are still sent in the background; call [`Pusher::shutdown`] to wait for them.
+#[derive(Debug)]
+pub struct Pusher {
+    sender: SyncSender<Entry>,
+    counters: Arc<Counters>,
+    thread: thread::JoinHandle<()>,
+}
+
+impl Pusher {
+    /// Queue `info` for sending. Returns `false` without queueing it if the queue is full, which
+    /// happens when the collector can't keep up.
+    pub fn push(&self, info: info::Malloc) -> bool {
+        let timestamp_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_millis() as u64);
+        match self.sender.try_send(Entry {
+            timestamp_ms,
+            malloc: info,
+        }) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_) | TrySendError::Disconnected(_)) => {
+                self.counters.dropped.fetch_add(1, Ordering::Relaxed);
+                false
+            }
+        }
+    }
+
+    /// Take a snapshot and [`push`](Self::push) it
+    pub fn capture(&self) -> Result<bool, Error> {
+        Ok(self.push(crate::malloc_info()?))
+    }
+
+    /// Number of snapshots the collector accepted so far
+    pub fn sent(&self) -> u64 {
+        self.counters.sent.load(Ordering::Relaxed)
+    }
+
+    /// Number of snapshots dropped so far, because the queue was full or their batch failed
+    pub fn dropped(&self) -> u64 {
+        self.counters.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Stop accepting snapshots, send the ones already queued, and wait for the pusher's thread to
+    /// exit
+    pub fn shutdown(self) {
+        drop(self.sender);
+        let _ = self.thread.join();
+    }
+}
+
+/// Start a pusher sending to `config.url` on a new thread. An error is returned if
+/// `config.labels` has an invalid or reserved name.
+pub fn start_push(config: Config) -> io::Result<Pusher> {
+    crate::labels::validate(config.labels.iter().map(|(name, _)| name.as_str()))?;
+    let (sender, receiver) = mpsc::sync_channel(config.queue_capacity);
+    let counters = Arc::new(Counters::default());
+    let thread = {
+        let counters = Arc::clone(&counters);
+        thread::Builder::new()
+            .name("malloc-info-push".into())
+            .spawn(move || Worker { config, counters }.run(receiver))?
+    };
+    Ok(Pusher {
+        sender,
+        counters,
+        thread,
+    })
+}
+
+#[derive(Debug, Serialize)]
+struct Entry {
+    timestamp_ms: u64,
+    malloc: info::Malloc,
+}
+
+#[derive(Serialize)]
+struct Batch<'a> {
+    labels: BTreeMap<&'a str, &'a str>,
+    snapshots: &'a [Entry],
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    sent: AtomicU64,
+    dropped: AtomicU64,
+}
+
+struct Worker {
+    config: Config,
+    counters: Arc<Counters>,
+}
+
+impl Worker {
+    /// Batch and send entries until the sender is dropped and the queue drained
+    fn run(self, receiver: mpsc::Receiver<Entry>) {
+        let mut batch = Vec::with_capacity(self.config.batch_size);
+        let mut deadline = Instant::now();
+        loop {
+            let received = if batch.is_empty() {
+                receiver.recv().map_err(|_| RecvTimeoutError::Disconnected)
+            } else {
+                receiver.recv_timeout(deadline.saturating_duration_since(Instant::now()))
+            };
+            let disconnected = match received {
+                Ok(entry) => {
+                    if batch.is_empty() {
+                        deadline = Instant::now() + self.config.max_delay;
+                    }
+                    batch.push(entry);
+                    if batch.len() < self.config.batch_size {
+                        continue;
+                    }
+                    false
+                }
+                Err(RecvTimeoutError::Timeout) => false,
+                Err(RecvTimeoutError::Disconnected) => true,
+            };
+
+            if !batch.is_empty() {
+                self.send(&batch);
+                batch.clear();
+            }
+            if disconnected {
+                return;
+            }
+        }
+    }
+
+    /// Send `batch`, retrying as configured
+    fn send(&self, batch: &[Entry]) {
+        let body = serde_json::to_vec(&Batch {
+            labels: self
+                .config
+                .labels
+                .iter()
+                .map(|(name, value)| (name.as_str(), value.as_str()))
+                .collect(),
+            snapshots: batch,
+        })
+        .expect("snapshots serialize to JSON");
+
+        let mut backoff = self.config.retry_backoff;
+        for attempt in 0..=self.config.max_retries {
+            if attempt > 0 {
+                thread::sleep(backoff);
+                backoff = backoff.saturating_mul(2);
+            }
+            let result = http::request(
+                "collector",
+                "POST",
+                &self.config.url,
+                "",
+                "application/json",
+                &body,
+                self.config.timeout,
+            );
+            if result.is_ok() {
+                self.counters
+                    .sent
+                    .fetch_add(batch.len() as u64, Ordering::Relaxed);
+                return;
+            }
+        }
+        self.counters
+            .dropped
+            .fetch_add(batch.len() as u64, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+
+    /// Serve requests on `listener`, answering each with the next of `statuses`, and return the
+    /// bodies of the requests
+    fn collector(
+        listener: TcpListener,
+        statuses: &[&'static str],
+    ) -> thread::JoinHandle<Vec<String>> {
+        let statuses = statuses.to_vec();
+        thread::spawn(move || {
+            let mut bodies = Vec::new();
+            for status in statuses {
+                let (stream, _) = listener.accept().expect("accept");
+                let mut reader = BufReader::new(&stream);
+                let mut length = 0;
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).expect("read head");
+                    if line == "\r\n" {
+                        break;
+                    }
+                    if let Some(value) = line.strip_prefix("Content-Length: ") {
+                        length = value.trim().parse().expect("length");
+                    }
+                }
+                let mut body = vec![0; length];
+                reader.read_exact(&mut body).expect("read body");
+                bodies.push(String::from_utf8(body).expect("UTF-8"));
+                write!(&stream, "HTTP/1.1 {status}\r\nContent-Length: 0\r\n\r\n").expect("respond");
+            }
+            bodies
+        })
+    }
+
+    fn config(listener: &TcpListener) -> Config {
+        let mut config = Config::new(format!(
+            "http://{}/ingest",
+            listener.local_addr().expect("address")
+        ));
+        config.labels = vec![("pod".into(), "test".into())];
+        config.batch_size = 2;
+        config.retry_backoff = Duration::from_millis(1);
+        config
+    }
+
+    #[test]
+    fn default_config() {
+        let config = Config::new("http://collector/ingest");
+        assert_eq!(config.url, "http://collector/ingest");
+        assert!(config.labels.is_empty());
+        assert!(config.batch_size > 0);
+        assert!(config.queue_capacity > 0);
+    }
+
+    #[test]
+    fn invalid_labels() {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("bind");
+        let mut config = config(&listener);
+        config.labels.push(("pod-name".into(), "x".into()));
+        let error = start_push(config).map(drop).expect_err("invalid label");
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn batches() {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("bind");
+        let pusher = start_push(config(&listener)).expect("start");
+        let collector = collector(listener, &["200 OK", "204 No Content"]);
+
+        for _ in 0..3 {
+            assert!(pusher.capture().expect("capture"));
+        }
+        pusher.shutdown();
+
+        let bodies = collector.join().expect("collector");
+        let batches: Vec<serde_json::Value> = bodies
+            .iter()
+            .map(|body| serde_json::from_str(body).expect("JSON"))
+            .collect();
+        assert_eq!(batches[0]["labels"]["pod"], "test");
+        assert_eq!(batches[0]["snapshots"].as_array().expect("array").len(), 2);
+        assert_eq!(batches[1]["snapshots"].as_array().expect("array").len(), 1);
+        let snapshot = &batches[1]["snapshots"][0];
+        assert!(snapshot["timestamp_ms"].as_u64().expect("timestamp") > 0);
+        assert!(snapshot["malloc"]["heaps"].is_array());
+    }
+
+    #[test]
+    fn retry() {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("bind");
+        let mut config = config(&listener);
+        config.batch_size = 1;
+        config.max_retries = 1;
+        let collector = collector(
+            listener,
+            &[
+                "503 Service Unavailable",
+                "200 OK",
+                "500 Error",
+                "500 Error",
+            ],
+        );
+
+        let pusher = start_push(config).expect("start");
+        assert!(pusher.capture().expect("capture"));
+        assert!(pusher.capture().expect("capture"));
+        let counters = Arc::clone(&pusher.counters);
+        pusher.shutdown();
+
+        assert_eq!(collector.join().expect("collector").len(), 4);
+        assert_eq!(counters.sent.load(Ordering::Relaxed), 1);
+        assert_eq!(counters.dropped.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn max_delay() {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("bind");
+        let mut config = config(&listener);
+        config.batch_size = 100;
+        config.max_delay = Duration::from_millis(10);
+        let collector = collector(listener, &["200 OK"]);
+
+        let pusher = start_push(config).expect("start");
+        assert!(pusher.capture().expect("capture"));
+        // Sent by the deadline, with the pusher still running
+        assert_eq!(collector.join().expect("collector").len(), 1);
+        pusher.shutdown();
+    }
+
+    #[test]
+    fn backpressure() {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("bind");
+        let mut config = config(&listener);
+        config.batch_size = 1;
+        config.queue_capacity = 1;
+        config.max_retries = 0;
+
+        let pusher = start_push(config).expect("start");
+        // Nothing answers yet, so the first request is stuck until the collector starts
+        let queued = (0..4)
+            .filter(|_| pusher.push(crate::malloc_info().expect("malloc_info")))
+            .count();
+        assert!(queued <= 2, "{queued} queued");
+        assert_eq!(pusher.dropped(), 4 - queued as u64);
+
+        let collector = collector(listener, &["200 OK"; 2][..queued]);
+        pusher.shutdown();
+        assert_eq!(collector.join().expect("collector").len(), queued);
+    }
+}
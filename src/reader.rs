@@ -0,0 +1,124 @@
+//! Repeated captures with reused buffers, for sampling at a high rate.
+
+use crate::memfd::MemFd;
+use crate::trace::Phase;
+use crate::{info, parse, write_info, Error, ErrorRepr};
+
+/// Reusable state for capturing `malloc_info` over and over.
+///
+/// glibc writes its output into an anonymous file which is created once and rewritten on every
+/// capture, and [`read_into`](MallocInfoReader::read_into) parses it into an existing snapshot,
+/// reusing the capacity of its vectors. Once the number of arenas and bins settles, a capture
+/// makes next to no allocations on the heap it is measuring.
+///
+/// ```rust
+/// # use malloc_info::MallocInfoReader;
+/// let mut reader = MallocInfoReader::new().expect("reader");
+/// let mut info = reader.read().expect("malloc_info");
+/// for _ in 0..3 {
+///     reader.read_into(&mut info).expect("malloc_info");
+/// }
+/// ```
+#[derive(Debug)]
+pub struct MallocInfoReader {
+    mem_fd: MemFd,
+}
+
+impl MallocInfoReader {
+    /// Create the file the output is written to
+    pub fn new() -> Result<Self, Error> {
+        let mem_fd = MemFd::new().map_err(ErrorRepr::LibC)?;
+        Ok(Self { mem_fd })
+    }
+
+    /// Capture a new snapshot
+    pub fn read(&mut self) -> Result<info::Malloc, Error> {
+        let mut info = info::Malloc {
+            version: String::new(),
+            heaps: Vec::new(),
+            total: Vec::new(),
+            system: Vec::new(),
+            aspace: Vec::new(),
+        };
+        self.read_into(&mut info)?;
+        Ok(info)
+    }
+
+    /// Capture a new snapshot into `info`, overwriting its previous contents in place. On error,
+    /// the contents of `info` are unspecified.
+    pub fn read_into(&mut self, info: &mut info::Malloc) -> Result<(), Error> {
+        fn read_into(mem_fd: &mut MemFd, info: &mut info::Malloc) -> Result<(), ErrorRepr> {
+            let mapping = {
+                let phase = Phase::capture();
+                mem_fd.clear().map_err(ErrorRepr::LibC)?;
+
+                // SAFETY: The FILE pointer is taken from the mem_fd object, which we have
+                // exclusive, mutable access to in this function, ensuring no other code can
+                // access it.
+                unsafe { write_info(mem_fd.fp)? };
+
+                let mapping = mem_fd.map().map_err(ErrorRepr::LibC)?;
+                phase.record_bytes(mapping.as_ref().len());
+                mapping
+            };
+
+            let phase = Phase::parse(mapping.as_ref().len());
+            parse::parse_into(mapping.as_ref(), info)?;
+            phase.record_arenas(info.heaps.len());
+            Ok(())
+        }
+        read_into(&mut self.mem_fd, info).map_err(Error::from)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn read_into() {
+        let mut reader = MallocInfoReader::new().expect("reader");
+        let mut info = reader.read().expect("malloc_info");
+        let expected = crate::malloc_info().expect("malloc_info");
+        // Arenas are never freed, but other tests running in parallel may create new ones
+        assert!(info.heaps.len() <= expected.heaps.len());
+        assert_eq!(info.version, expected.version);
+
+        let (heaps, capacity) = (info.heaps.as_ptr(), info.heaps.capacity());
+        for _ in 0..3 {
+            reader.read_into(&mut info).expect("malloc_info");
+        }
+        if info.heaps.len() <= capacity {
+            assert_eq!(info.heaps.as_ptr(), heaps);
+        }
+        assert!(info.heaps.len() >= expected.heaps.len());
+        assert!(!info.total.is_empty());
+    }
+
+    #[test]
+    fn overwrite() {
+        let stale = r#"<malloc version="0">
+<heap nr="7"><sizes><size from="1" to="2" total="3" count="4"/></sizes></heap>
+<heap nr="8"></heap>
+<heap nr="9"></heap>
+<heap nr="10"></heap>
+<heap nr="11"></heap>
+<heap nr="12"></heap>
+<heap nr="13"></heap>
+<heap nr="14"></heap>
+<heap nr="15"></heap>
+<total type="fast" count="0" size="0"/>
+<system type="current" size="1"/>
+<aspace type="total" size="1"/>
+</malloc>"#;
+        let mut info: info::Malloc = quick_xml::de::from_str(stale).expect("parse XML");
+        let mut reader = MallocInfoReader::new().expect("reader");
+        reader.read_into(&mut info).expect("malloc_info");
+
+        let fresh = reader.read().expect("malloc_info");
+        assert_eq!(info.version, fresh.version);
+        assert_eq!(info.heaps.len(), fresh.heaps.len());
+        assert_eq!(info.heaps[0].nr, 0);
+        assert_eq!(info.total.len(), fresh.total.len());
+    }
+}
@@ -0,0 +1,124 @@
+//! Attaching heap summaries to Sentry events.
+//!
+//! [`install`] registers an event processor on the current Sentry scope, which takes a
+//! [`MallocSummary`] whenever an event is captured and attaches it as the `malloc` context, so
+//! error reports show the state of the heap at the time of the error. [`add_breadcrumb`] records
+//! a summary as a breadcrumb, for services which want a trail of heap states leading up to an
+//! event, for example by calling it on a timer or at the end of every request.
+//!
+//! ```rust,no_run
+//! // After initializing Sentry with `sentry::init`
+//! malloc_info::sentry::install();
+//! ```
+//!
+//! The context and the breadcrumb data hold the fields of [`MallocSummary`] under their own names,
+//! with sizes in bytes.
+
+use crate::{malloc_info_summary, Error, MallocSummary};
+use sentry_core::protocol::{Breadcrumb, Context, Map, Value};
+
+/// Key of the context attached by [`install`]
+pub const CONTEXT: &str = "malloc";
+
+/// Category of the breadcrumbs recorded by [`add_breadcrumb`]
+pub const CATEGORY: &str = "malloc";
+
+/// Attach a fresh [`MallocSummary`] as the [`CONTEXT`] context to every event captured through
+/// the current scope from now on. Events are sent without it if the summary can't be taken.
+pub fn install() {
+    sentry_core::configure_scope(|scope| {
+        scope.add_event_processor(|mut event| {
+            if let Ok(summary) = malloc_info_summary() {
+                event.contexts.insert(CONTEXT.into(), context(&summary));
+            }
+            Some(event)
+        });
+    });
+}
+
+/// Take a [`MallocSummary`] and record it as a breadcrumb on the current hub
+pub fn add_breadcrumb() -> Result<(), Error> {
+    let summary = malloc_info_summary()?;
+    sentry_core::add_breadcrumb(breadcrumb(&summary));
+    Ok(())
+}
+
+/// `summary` as a Sentry context
+pub fn context(summary: &MallocSummary) -> Context {
+    Context::Other(fields(summary))
+}
+
+/// `summary` as a Sentry breadcrumb in the [`CATEGORY`] category
+pub fn breadcrumb(summary: &MallocSummary) -> Breadcrumb {
+    Breadcrumb {
+        category: Some(CATEGORY.into()),
+        message: Some(format!(
+            "{} bytes obtained from the system in {} arenas",
+            summary.system_current, summary.arenas
+        )),
+        data: fields(summary),
+        ..Default::default()
+    }
+}
+
+fn fields(summary: &MallocSummary) -> Map<String, Value> {
+    let mut fields = Map::new();
+    fields.insert("arenas".into(), summary.arenas.into());
+    fields.insert("system_current".into(), summary.system_current.into());
+    fields.insert("system_max".into(), summary.system_max.into());
+    fields.insert("fast".into(), summary.fast.into());
+    fields.insert("rest".into(), summary.rest.into());
+    fields.insert("mmap".into(), summary.mmap.into());
+    fields.insert("mmap_count".into(), summary.mmap_count.into());
+    fields
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use sentry_core::test::with_captured_events;
+
+    #[test]
+    fn event_context() {
+        let events = with_captured_events(|| {
+            install();
+            add_breadcrumb().expect("add_breadcrumb");
+            sentry_core::capture_message("out of memory", sentry_core::Level::Error);
+        });
+        assert_eq!(events.len(), 1);
+        let event = &events[0];
+
+        let context = match event.contexts.get(CONTEXT) {
+            Some(Context::Other(context)) => context,
+            other => panic!("unexpected context {other:?}"),
+        };
+        assert!(context["arenas"].as_u64().expect("arenas") > 0);
+        assert!(context["system_current"].as_u64().expect("system_current") > 0);
+
+        let breadcrumb = event.breadcrumbs.iter().next().expect("breadcrumb");
+        assert_eq!(breadcrumb.category.as_deref(), Some(CATEGORY));
+        assert_eq!(breadcrumb.data.len(), 7);
+    }
+
+    #[test]
+    fn conversions() {
+        let summary = MallocSummary {
+            arenas: 2,
+            system_current: 4096,
+            system_max: 8192,
+            fast: 16,
+            rest: 32,
+            mmap: 64,
+            mmap_count: 1,
+        };
+        let breadcrumb = breadcrumb(&summary);
+        assert_eq!(
+            breadcrumb.message.as_deref(),
+            Some("4096 bytes obtained from the system in 2 arenas")
+        );
+        assert_eq!(breadcrumb.data["system_max"], 8192);
+        assert_eq!(breadcrumb.data["mmap"], 64);
+        assert_eq!(breadcrumb.data["mmap_count"], 1);
+        assert_eq!(context(&summary), Context::Other(breadcrumb.data));
+    }
+}
@@ -0,0 +1,412 @@
+//! Publishing heap summaries in a shared-memory region, for external scrapers which read them
+//! without the process serving any endpoint.
+//!
+//! A [`StatsRegion`] owns a small file, by default in `/dev/shm`, holding the latest
+//! [`MallocSummary`] and when it was taken. The process refreshes it with
+//! [`StatsRegion::update`], for example from a timer thread, and any process allowed to read the
+//! file, typically an unprivileged scraper, maps it with a [`StatsReader`]:
+//!
+//! ```rust,no_run
+//! use malloc_info::shm::{StatsReader, StatsRegion};
+//!
+//! // In the monitored process
+//! let mut region = StatsRegion::create(StatsRegion::default_path(std::process::id()))?;
+//! region.update()?;
+//!
+//! // In the scraper
+//! # let pid = std::process::id();
+//! let reader = StatsReader::open(StatsRegion::default_path(pid))?;
+//! if let Some((summary, taken_at)) = reader.read()? {
+//!     println!("{} bytes from the system as of {taken_at:?}", summary.system_current);
+//! }
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+//!
+//! The region is laid out as consecutive native-endian 64 bit words:
+//!
+//! | Word | |
+//! |---|---|
+//! | 0 | [`MAGIC`] |
+//! | 1 | Layout [`VERSION`] |
+//! | 2 | Sequence number, odd while an update is in progress |
+//! | 3 | When the summary was taken, in nanoseconds since the Unix epoch, or 0 before the first update |
+//! | 4 to 10 | `arenas`, `system_current`, `system_max`, `fast`, `rest`, `mmap` and `mmap_count` |
+//!
+//! Updates are published with a seqlock: the writer makes the sequence number odd, writes the
+//! fields and makes it even again, and readers retry until they see the same even number before
+//! and after reading the fields. Readers never block the writer.
+
+use crate::{malloc_info_summary, Error, MallocSummary};
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{fence, AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Identifies a stats region, `malloc-i` in ASCII
+pub const MAGIC: u64 = u64::from_be_bytes(*b"malloc-i");
+
+/// Version of the layout of the region
+pub const VERSION: u64 = 2;
+
+/// Number of words in the region
+const WORDS: usize = 11;
+
+const WORD_MAGIC: usize = 0;
+const WORD_VERSION: usize = 1;
+const WORD_SEQ: usize = 2;
+const WORD_TAKEN_AT: usize = 3;
+const WORD_FIELDS: usize = 4;
+
+/// Attempts a reader makes to get a consistent read before giving up, in case the writer died
+/// while updating
+const MAX_READ_ATTEMPTS: usize = 10_000;
+
+/// The writing side of a stats region. The file is removed when the region is dropped, while
+/// readers which have already mapped it keep reading the last summary.
+#[derive(Debug)]
+pub struct StatsRegion {
+    path: PathBuf,
+    map: Map,
+}
+
+impl StatsRegion {
+    /// Conventional path of the region of the process `pid`: `/dev/shm/malloc-info-<pid>`
+    pub fn default_path(pid: u32) -> PathBuf {
+        PathBuf::from(format!("/dev/shm/malloc-info-{pid}"))
+    }
+
+    /// Create the region at `path`, readable by everyone and writable only by the owner. Restrict
+    /// access through the permissions of its directory if needed.
+    ///
+    /// The file is always created afresh, and symbolic links are never followed. A file left
+    /// behind at `path`, such as by an earlier process with the same pid, is only replaced if it is
+    /// a regular file owned by the current user; otherwise this fails with
+    /// [`io::ErrorKind::AlreadyExists`].
+    pub fn create(path: impl Into<PathBuf>) -> io::Result<Self> {
+        let path = path.into();
+        let open = || {
+            OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create_new(true)
+                .mode(0o644)
+                .custom_flags(libc::O_NOFOLLOW)
+                .open(&path)
+        };
+        let file = match open() {
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists && is_leftover(&path)? => {
+                std::fs::remove_file(&path)?;
+                open()?
+            }
+            result => result?,
+        };
+        file.set_len((WORDS * 8) as u64)?;
+        let map = Map::new(&file, true)?;
+        map.word(WORD_VERSION).store(VERSION, Ordering::Relaxed);
+        // Readers check the magic first, so it is written last
+        map.word(WORD_MAGIC).store(MAGIC, Ordering::Release);
+        Ok(Self { path, map })
+    }
+
+    /// Path of the region
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Take a [`MallocSummary`] and publish it
+    pub fn update(&mut self) -> Result<MallocSummary, Error> {
+        let summary = malloc_info_summary()?;
+        self.publish(&summary, SystemTime::now());
+        Ok(summary)
+    }
+
+    /// Publish `summary` as taken at `taken_at`
+    pub fn publish(&mut self, summary: &MallocSummary, taken_at: SystemTime) {
+        let taken_at = taken_at
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_nanos() as u64)
+            .max(1);
+        let seq = self.map.word(WORD_SEQ);
+        let start = seq.load(Ordering::Relaxed);
+        seq.store(start.wrapping_add(1), Ordering::Relaxed);
+        fence(Ordering::Release);
+
+        self.map
+            .word(WORD_TAKEN_AT)
+            .store(taken_at, Ordering::Relaxed);
+        for (i, value) in fields(summary).into_iter().enumerate() {
+            self.map
+                .word(WORD_FIELDS + i)
+                .store(value, Ordering::Relaxed);
+        }
+
+        seq.store(start.wrapping_add(2), Ordering::Release);
+    }
+}
+
+impl Drop for StatsRegion {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// Whether the file at `path` is a regular file owned by the current user, which can be replaced
+fn is_leftover(path: &Path) -> io::Result<bool> {
+    let metadata = std::fs::symlink_metadata(path)?;
+    // SAFETY: getuid has no preconditions and cannot fail
+    let uid = unsafe { libc::getuid() };
+    Ok(metadata.file_type().is_file() && metadata.uid() == uid)
+}
+
+/// The reading side of a stats region
+#[derive(Debug)]
+pub struct StatsReader {
+    map: Map,
+}
+
+impl StatsReader {
+    /// Map the region at `path` for reading. Fails with [`io::ErrorKind::InvalidData`] if the file
+    /// isn't a stats region of a supported version.
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let file = File::open(path)?;
+        if file.metadata()?.len() < (WORDS * 8) as u64 {
+            return Err(invalid_data("file too small for a stats region"));
+        }
+        let map = Map::new(&file, false)?;
+        if map.word(WORD_MAGIC).load(Ordering::Acquire) != MAGIC {
+            return Err(invalid_data("not a stats region"));
+        }
+        let version = map.word(WORD_VERSION).load(Ordering::Relaxed);
+        if version != VERSION {
+            return Err(invalid_data(&format!(
+                "unsupported stats region version {version}"
+            )));
+        }
+        Ok(Self { map })
+    }
+
+    /// Read the latest summary and when it was taken, or `None` if none was published yet. Fails
+    /// with [`io::ErrorKind::WouldBlock`] if no consistent read could be made, which happens if
+    /// the writer died in the middle of an update.
+    pub fn read(&self) -> io::Result<Option<(MallocSummary, SystemTime)>> {
+        let seq = self.map.word(WORD_SEQ);
+        for _ in 0..MAX_READ_ATTEMPTS {
+            let start = seq.load(Ordering::Acquire);
+            if start % 2 == 1 {
+                std::hint::spin_loop();
+                continue;
+            }
+
+            let taken_at = self.map.word(WORD_TAKEN_AT).load(Ordering::Relaxed);
+            let mut values = [0; WORDS - WORD_FIELDS];
+            for (i, value) in values.iter_mut().enumerate() {
+                *value = self.map.word(WORD_FIELDS + i).load(Ordering::Relaxed);
+            }
+
+            fence(Ordering::Acquire);
+            if seq.load(Ordering::Relaxed) != start {
+                std::hint::spin_loop();
+                continue;
+            }
+            if taken_at == 0 {
+                return Ok(None);
+            }
+            let [arenas, system_current, system_max, fast, rest, mmap, mmap_count] = values;
+            let summary = MallocSummary {
+                arenas: arenas as usize,
+                system_current,
+                system_max,
+                fast,
+                rest,
+                mmap,
+                mmap_count,
+            };
+            return Ok(Some((summary, UNIX_EPOCH + Duration::from_nanos(taken_at))));
+        }
+        Err(io::Error::new(
+            io::ErrorKind::WouldBlock,
+            "stats region is being updated",
+        ))
+    }
+}
+
+/// The fields of `summary` in the order of the region
+fn fields(summary: &MallocSummary) -> [u64; WORDS - WORD_FIELDS] {
+    [
+        summary.arenas as u64,
+        summary.system_current,
+        summary.system_max,
+        summary.fast,
+        summary.rest,
+        summary.mmap,
+        summary.mmap_count,
+    ]
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// A shared mapping of the words of a region
+#[derive(Debug)]
+struct Map {
+    ptr: *mut AtomicU64,
+}
+
+// SAFETY: The mapping is only accessed through atomics, and lives as long as the `Map`
+unsafe impl Send for Map {}
+unsafe impl Sync for Map {}
+
+impl Map {
+    fn new(file: &File, writable: bool) -> io::Result<Self> {
+        let prot = if writable {
+            libc::PROT_READ | libc::PROT_WRITE
+        } else {
+            libc::PROT_READ
+        };
+        // SAFETY: Mapping a valid file descriptor has no preconditions, the file is at least
+        // `WORDS * 8` bytes long, and the mapping outlives the descriptor
+        let ptr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                WORDS * 8,
+                prot,
+                libc::MAP_SHARED,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self { ptr: ptr.cast() })
+    }
+
+    /// The word at `index`. Loads are fine on read-only mappings, as they don't write.
+    fn word(&self, index: usize) -> &AtomicU64 {
+        assert!(index < WORDS);
+        // SAFETY: The mapping is page aligned and `WORDS` words long, and AtomicU64 has the size
+        // and alignment of u64
+        unsafe { &*self.ptr.add(index) }
+    }
+}
+
+impl Drop for Map {
+    fn drop(&mut self) {
+        // SAFETY: `ptr` was returned by a successful mmap of `WORDS * 8` bytes, and is not used
+        // after this
+        unsafe { libc::munmap(self.ptr.cast(), WORDS * 8) };
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn region_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("malloc-info-{name}-{}", std::process::id()))
+    }
+
+    #[test]
+    fn publish_and_read() {
+        let path = region_path("shm");
+        let mut region = StatsRegion::create(&path).expect("create");
+        assert_eq!(region.path(), path);
+        let reader = StatsReader::open(&path).expect("open");
+        assert_eq!(reader.read().expect("read"), None);
+
+        let summary = MallocSummary {
+            arenas: 2,
+            system_current: 4096,
+            system_max: 8192,
+            fast: 16,
+            rest: 32,
+            mmap: 64,
+            mmap_count: 1,
+        };
+        let taken_at = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        region.publish(&summary, taken_at);
+        assert_eq!(reader.read().expect("read"), Some((summary, taken_at)));
+
+        let summary = region.update().expect("update");
+        let (read, _) = reader.read().expect("read").expect("published");
+        assert_eq!(read, summary);
+
+        drop(region);
+        assert!(!path.exists());
+        // The mapping outlives the file
+        assert_eq!(
+            reader.read().expect("read").map(|(read, _)| read),
+            Some(summary)
+        );
+    }
+
+    #[test]
+    fn concurrent() {
+        let path = region_path("shm-concurrent");
+        let mut region = StatsRegion::create(&path).expect("create");
+        let reader = StatsReader::open(&path).expect("open");
+
+        std::thread::scope(|scope| {
+            scope.spawn(|| {
+                for i in 1..=10_000u64 {
+                    let summary = MallocSummary {
+                        arenas: i as usize,
+                        system_current: i,
+                        system_max: i,
+                        fast: i,
+                        rest: i,
+                        mmap: i,
+                        mmap_count: i,
+                    };
+                    region.publish(&summary, UNIX_EPOCH + Duration::from_nanos(i));
+                }
+            });
+            for _ in 0..10_000 {
+                if let Ok(Some((summary, taken_at))) = reader.read() {
+                    let i = summary.system_current;
+                    assert_eq!(fields(&summary), [i; 7]);
+                    assert_eq!(taken_at, UNIX_EPOCH + Duration::from_nanos(i));
+                }
+            }
+        });
+    }
+
+    #[test]
+    fn invalid() {
+        let path = region_path("shm-invalid");
+        std::fs::write(&path, [0u8; WORDS * 8]).expect("write");
+        let error = StatsReader::open(&path).expect_err("not a region");
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+
+        std::fs::write(&path, b"short").expect("write");
+        let error = StatsReader::open(&path).expect_err("too small");
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        std::fs::remove_file(&path).expect("remove");
+    }
+
+    #[test]
+    fn existing_file() {
+        let path = region_path("shm-existing");
+        let target = region_path("shm-target");
+        let _ = std::fs::remove_file(&path);
+
+        // A leftover region of our own is replaced
+        std::fs::write(&path, b"leftover").expect("write");
+        let region = StatsRegion::create(&path).expect("replace leftover");
+        drop(region);
+
+        // A symbolic link is neither followed nor replaced
+        std::fs::write(&target, b"victim").expect("write");
+        std::os::unix::fs::symlink(&target, &path).expect("symlink");
+        let error = StatsRegion::create(&path).expect_err("symlink");
+        assert_eq!(error.kind(), io::ErrorKind::AlreadyExists);
+        assert_eq!(std::fs::read(&target).expect("read"), b"victim");
+
+        std::fs::remove_file(&path).expect("remove");
+        std::fs::remove_file(&target).expect("remove");
+    }
+}
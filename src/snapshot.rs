@@ -0,0 +1,60 @@
+//! Snapshots of the heap along with when they were taken, for comparing snapshots over time.
+
+use crate::{info, malloc_info, Error};
+use std::time::{Duration, Instant, SystemTime};
+
+/// A parsed snapshot of the heap, as returned by [`malloc_info`], with the time it was taken
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MallocSnapshot {
+    /// Wall-clock time the capture started, for display and for correlating with other data
+    pub taken_at: SystemTime,
+
+    /// Monotonic time the capture started, for measuring the time between snapshots
+    pub monotonic: Instant,
+
+    /// The snapshot itself
+    pub info: info::Malloc,
+}
+
+impl MallocSnapshot {
+    /// Time elapsed between `earlier` and this snapshot, measured on the monotonic clock, or zero
+    /// if `earlier` was actually taken later
+    pub fn since(&self, earlier: &MallocSnapshot) -> Duration {
+        self.monotonic.saturating_duration_since(earlier.monotonic)
+    }
+
+    /// Time elapsed since the snapshot was taken, measured on the monotonic clock
+    pub fn age(&self) -> Duration {
+        self.monotonic.elapsed()
+    }
+}
+
+/// Take a [`MallocSnapshot`] with [`malloc_info`], timestamped when the capture starts
+pub fn snapshot() -> Result<MallocSnapshot, Error> {
+    let taken_at = SystemTime::now();
+    let monotonic = Instant::now();
+    Ok(MallocSnapshot {
+        taken_at,
+        monotonic,
+        info: malloc_info()?,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn timestamps() {
+        let before = SystemTime::now();
+        let first = snapshot().expect("snapshot");
+        let second = snapshot().expect("snapshot");
+        assert!(first.taken_at >= before);
+        assert!(!first.info.heaps.is_empty());
+
+        assert!(second.monotonic >= first.monotonic);
+        assert_eq!(second.since(&first), second.monotonic - first.monotonic);
+        assert_eq!(first.since(&second), Duration::ZERO);
+        assert!(first.age() >= second.since(&first));
+    }
+}
@@ -0,0 +1,381 @@
+//! Durable, queryable snapshot history in a local SQLite database.
+//!
+//! [`SqliteSink`] inserts each snapshot into three tables, created if they don't exist yet:
+//!
+//! ```sql
+//! -- One row per snapshot. `taken_at` is in milliseconds since the Unix epoch.
+//! CREATE TABLE snapshots (
+//!     id INTEGER PRIMARY KEY,
+//!     taken_at INTEGER NOT NULL,
+//!     version TEXT NOT NULL
+//! );
+//!
+//! -- One row per <total>, <system> and <aspace> element. `arena` is the arena number, or NULL
+//! -- for the document-level entries covering all arenas. `element` is `total`, `system` or
+//! -- `aspace`, and `type` its type attribute. `count` is NULL for elements without one.
+//! CREATE TABLE entries (
+//!     snapshot_id INTEGER NOT NULL REFERENCES snapshots (id) ON DELETE CASCADE,
+//!     arena INTEGER,
+//!     element TEXT NOT NULL,
+//!     type TEXT NOT NULL,
+//!     count INTEGER,
+//!     size INTEGER NOT NULL
+//! );
+//!
+//! -- One row per free chunk size bin of an arena. `kind` is `size` or `unsorted`.
+//! CREATE TABLE bins (
+//!     snapshot_id INTEGER NOT NULL REFERENCES snapshots (id) ON DELETE CASCADE,
+//!     arena INTEGER NOT NULL,
+//!     kind TEXT NOT NULL,
+//!     "from" INTEGER NOT NULL,
+//!     "to" INTEGER NOT NULL,
+//!     total INTEGER NOT NULL,
+//!     count INTEGER NOT NULL
+//! );
+//! ```
+//!
+//! Sizes and counts larger than `i64::MAX` are stored as `i64::MAX`. Element types this crate
+//! doesn't know are stored as `other`.
+//!
+//! ```rust
+//! use malloc_info::sqlite::SqliteSink;
+//! use std::time::Duration;
+//!
+//! let mut sink = SqliteSink::open_in_memory()
+//!     .expect("open database")
+//!     .with_retention(Duration::from_secs(7 * 24 * 60 * 60));
+//! sink.insert(&malloc_info::malloc_info().expect("malloc_info"))
+//!     .expect("insert snapshot");
+//! ```
+
+use crate::info::{Heap, Malloc, SizeKind};
+use crate::{Error, ErrorRepr};
+use rusqlite::{params, Connection, Transaction};
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const SCHEMA: &str = r#"
+CREATE TABLE IF NOT EXISTS snapshots (
+    id INTEGER PRIMARY KEY,
+    taken_at INTEGER NOT NULL,
+    version TEXT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS snapshots_taken_at ON snapshots (taken_at);
+CREATE TABLE IF NOT EXISTS entries (
+    snapshot_id INTEGER NOT NULL REFERENCES snapshots (id) ON DELETE CASCADE,
+    arena INTEGER,
+    element TEXT NOT NULL,
+    type TEXT NOT NULL,
+    count INTEGER,
+    size INTEGER NOT NULL
+);
+CREATE INDEX IF NOT EXISTS entries_snapshot_id ON entries (snapshot_id);
+CREATE TABLE IF NOT EXISTS bins (
+    snapshot_id INTEGER NOT NULL REFERENCES snapshots (id) ON DELETE CASCADE,
+    arena INTEGER NOT NULL,
+    kind TEXT NOT NULL,
+    "from" INTEGER NOT NULL,
+    "to" INTEGER NOT NULL,
+    total INTEGER NOT NULL,
+    count INTEGER NOT NULL
+);
+CREATE INDEX IF NOT EXISTS bins_snapshot_id ON bins (snapshot_id);
+"#;
+
+/// Inserts snapshots into a SQLite database, see the [module documentation](self) for the
+/// schema.
+#[derive(Debug)]
+pub struct SqliteSink {
+    conn: Connection,
+    retention: Option<Duration>,
+}
+
+impl SqliteSink {
+    /// Open or create the database at `path`
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, Error> {
+        let conn = Connection::open(path).map_err(ErrorRepr::from)?;
+        Self::from_connection(conn)
+    }
+
+    /// Create a database in memory, which is gone once the sink is dropped
+    pub fn open_in_memory() -> Result<Self, Error> {
+        let conn = Connection::open_in_memory().map_err(ErrorRepr::from)?;
+        Self::from_connection(conn)
+    }
+
+    /// Use an already open connection, creating the tables if they don't exist yet. Foreign keys
+    /// are enabled on the connection, for deleting snapshots to cascade to their rows.
+    pub fn from_connection(conn: Connection) -> Result<Self, Error> {
+        fn from_connection(conn: &Connection) -> Result<(), rusqlite::Error> {
+            conn.pragma_update(None, "foreign_keys", true)?;
+            conn.execute_batch(SCHEMA)
+        }
+        from_connection(&conn).map_err(ErrorRepr::from)?;
+        Ok(Self {
+            conn,
+            retention: None,
+        })
+    }
+
+    /// Delete snapshots older than `retention` every time one is inserted
+    pub fn with_retention(mut self, retention: Duration) -> Self {
+        self.retention = Some(retention);
+        self
+    }
+
+    /// The underlying connection, for querying the database
+    pub fn connection(&self) -> &Connection {
+        &self.conn
+    }
+
+    /// Insert `info` as taken now, see [`insert_at`](Self::insert_at)
+    pub fn insert(&mut self, info: &Malloc) -> Result<i64, Error> {
+        self.insert_at(info, SystemTime::now())
+    }
+
+    /// Insert `info` as taken at `taken_at` in a single transaction, and return the id of its row
+    /// in `snapshots`. With a retention set, snapshots which have expired by `taken_at` are
+    /// deleted in the same transaction.
+    pub fn insert_at(&mut self, info: &Malloc, taken_at: SystemTime) -> Result<i64, Error> {
+        fn insert_at(
+            tx: &Transaction<'_>,
+            info: &Malloc,
+            taken_at: SystemTime,
+            retention: Option<Duration>,
+        ) -> Result<i64, rusqlite::Error> {
+            tx.execute(
+                "INSERT INTO snapshots (taken_at, version) VALUES (?1, ?2)",
+                params![millis(taken_at), info.version],
+            )?;
+            let id = tx.last_insert_rowid();
+
+            insert_entries(tx, id, None, info.total.iter().map(total))?;
+            insert_entries(tx, id, None, info.system.iter().map(system))?;
+            insert_entries(tx, id, None, info.aspace.iter().map(aspace))?;
+            for heap in &info.heaps {
+                insert_heap(tx, id, heap)?;
+            }
+
+            if let Some(retention) = retention {
+                prune(tx, taken_at, retention)?;
+            }
+            Ok(id)
+        }
+
+        let tx = self.conn.transaction().map_err(ErrorRepr::from)?;
+        let id = insert_at(&tx, info, taken_at, self.retention).map_err(ErrorRepr::from)?;
+        tx.commit().map_err(ErrorRepr::from)?;
+        Ok(id)
+    }
+
+    /// Delete snapshots taken longer than `retention` before `now`, returning how many were
+    /// deleted
+    pub fn prune(&mut self, now: SystemTime, retention: Duration) -> Result<usize, Error> {
+        prune(&self.conn, now, retention).map_err(|e| ErrorRepr::from(e).into())
+    }
+}
+
+/// An `entries` row: element, type, count and size
+type Entry = (&'static str, &'static str, Option<u64>, u64);
+
+fn insert_entries(
+    tx: &Transaction<'_>,
+    id: i64,
+    arena: Option<usize>,
+    entries: impl Iterator<Item = Entry>,
+) -> Result<(), rusqlite::Error> {
+    let mut insert = tx.prepare_cached(
+        "INSERT INTO entries (snapshot_id, arena, element, type, count, size)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+    )?;
+    for (element, r#type, count, size) in entries {
+        insert.execute(params![
+            id,
+            arena.map(|nr| int(nr as u64)),
+            element,
+            r#type,
+            count.map(int),
+            int(size)
+        ])?;
+    }
+    Ok(())
+}
+
+fn insert_heap(tx: &Transaction<'_>, id: i64, heap: &Heap) -> Result<(), rusqlite::Error> {
+    insert_entries(tx, id, Some(heap.nr), heap.total.iter().map(total))?;
+    insert_entries(tx, id, Some(heap.nr), heap.system.iter().map(system))?;
+    insert_entries(tx, id, Some(heap.nr), heap.aspace.iter().map(aspace))?;
+
+    let mut insert = tx.prepare_cached(
+        r#"INSERT INTO bins (snapshot_id, arena, kind, "from", "to", total, count)
+           VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)"#,
+    )?;
+    for size in heap.sizes.iter().flatten() {
+        let kind = match size.kind {
+            SizeKind::Size => "size",
+            SizeKind::Unsorted => "unsorted",
+        };
+        insert.execute(params![
+            id,
+            int(heap.nr as u64),
+            kind,
+            int(size.from),
+            int(size.to),
+            int(size.total),
+            int(size.count)
+        ])?;
+    }
+    Ok(())
+}
+
+fn prune(
+    conn: &Connection,
+    now: SystemTime,
+    retention: Duration,
+) -> Result<usize, rusqlite::Error> {
+    let cutoff = now.checked_sub(retention).unwrap_or(UNIX_EPOCH);
+    conn.execute(
+        "DELETE FROM snapshots WHERE taken_at < ?1",
+        params![millis(cutoff)],
+    )
+}
+
+fn total(total: &crate::info::Total) -> Entry {
+    (
+        "total",
+        total.r#type.as_str(),
+        Some(total.count),
+        total.size,
+    )
+}
+
+fn system(system: &crate::info::System) -> Entry {
+    ("system", system.r#type.as_str(), None, system.size)
+}
+
+fn aspace(aspace: &crate::info::Aspace) -> Entry {
+    ("aspace", aspace.r#type.as_str(), None, aspace.size)
+}
+
+/// `value` as an SQLite integer, saturating at `i64::MAX`
+fn int(value: u64) -> i64 {
+    i64::try_from(value).unwrap_or(i64::MAX)
+}
+
+/// Milliseconds since the Unix epoch, negative before it
+fn millis(time: SystemTime) -> i64 {
+    match time.duration_since(UNIX_EPOCH) {
+        Ok(since) => i64::try_from(since.as_millis()).unwrap_or(i64::MAX),
+        Err(e) => i64::try_from(e.duration().as_millis()).map_or(i64::MIN, |before| -before),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // Based on the malloc_info(3) man-page example, with a size bin added to the second arena
+    const XML: &str = r#"
+<malloc version="1">
+<heap nr="0">
+<sizes>
+</sizes>
+<total type="fast" count="0" size="0"/>
+<total type="rest" count="0" size="0"/>
+<system type="current" size="1081344"/>
+<system type="max" size="1081344"/>
+<aspace type="total" size="1081344"/>
+<aspace type="mprotect" size="1081344"/>
+</heap>
+<heap nr="1">
+<sizes>
+<size from="17" to="32" total="64" count="2"/>
+</sizes>
+<total type="fast" count="2" size="64"/>
+<total type="rest" count="0" size="0"/>
+<system type="current" size="1032192"/>
+<system type="max" size="1032192"/>
+<aspace type="total" size="1032192"/>
+<aspace type="mprotect" size="1032192"/>
+</heap>
+<total type="fast" count="2" size="64"/>
+<total type="rest" count="0" size="0"/>
+<system type="current" size="2113536"/>
+<system type="max" size="2113536"/>
+<aspace type="total" size="2113536"/>
+<aspace type="mprotect" size="2113536"/>
+</malloc>
+"#;
+
+    fn count(sink: &SqliteSink, table: &str) -> i64 {
+        sink.connection()
+            .query_row(&format!("SELECT COUNT(*) FROM {table}"), [], |row| {
+                row.get(0)
+            })
+            .expect("count rows")
+    }
+
+    #[test]
+    fn insert() {
+        let info: Malloc = quick_xml::de::from_str(XML).expect("parse XML");
+        let mut sink = SqliteSink::open_in_memory().expect("open");
+        let id = sink.insert(&info).expect("insert");
+
+        assert_eq!(count(&sink, "snapshots"), 1);
+        assert_eq!(count(&sink, "entries"), 6 * 3);
+        assert_eq!(count(&sink, "bins"), 1);
+
+        let size: i64 = sink
+            .connection()
+            .query_row(
+                "SELECT size FROM entries
+                 WHERE snapshot_id = ?1 AND arena IS NULL AND element = 'system' AND type = 'current'",
+                [id],
+                |row| row.get(0),
+            )
+            .expect("document-level entry");
+        assert_eq!(size, 2113536);
+
+        let (arena, total): (i64, i64) = sink
+            .connection()
+            .query_row(
+                r#"SELECT arena, total FROM bins WHERE kind = 'size' AND "from" = 17"#,
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .expect("bin");
+        assert_eq!((arena, total), (1, 64));
+
+        sink.insert(&crate::malloc_info().expect("malloc_info"))
+            .expect("insert live snapshot");
+        assert_eq!(count(&sink, "snapshots"), 2);
+    }
+
+    #[test]
+    fn retention() {
+        let info: Malloc = quick_xml::de::from_str(XML).expect("parse XML");
+        let day = Duration::from_secs(24 * 60 * 60);
+        let mut sink = SqliteSink::open_in_memory()
+            .expect("open")
+            .with_retention(7 * day);
+
+        let start = UNIX_EPOCH + 1000 * day;
+        for days in 0..10 {
+            sink.insert_at(&info, start + days * day).expect("insert");
+        }
+        // Snapshots from days 2 through 9 are within a week of day 9
+        assert_eq!(count(&sink, "snapshots"), 8);
+        assert_eq!(count(&sink, "bins"), 8);
+
+        let pruned = sink.prune(start + 20 * day, day).expect("prune");
+        assert_eq!(pruned, 8);
+        assert_eq!(count(&sink, "entries"), 0);
+    }
+
+    #[test]
+    fn times() {
+        assert_eq!(millis(UNIX_EPOCH + Duration::from_millis(1500)), 1500);
+        assert_eq!(millis(UNIX_EPOCH - Duration::from_millis(1500)), -1500);
+        assert_eq!(int(u64::MAX), i64::MAX);
+    }
+}
@@ -0,0 +1,222 @@
+//! Capturing and parsing the output of glibc's `malloc_stats` function. See the
+//! [malloc_stats(3)](https://man7.org/linux/man-pages/man3/malloc_stats.3.html) page for details.
+//!
+//! `malloc_stats` prints a short per-arena summary to standard error:
+//!
+//! ```text
+//! Arena 0:
+//! system bytes     =     135168
+//! in use bytes     =      74352
+//! Total (incl. mmap):
+//! system bytes     =     135168
+//! in use bytes     =      74352
+//! max mmap regions =          0
+//! max mmap bytes   =          0
+//! ```
+//!
+//! [`malloc_stats`] captures that output into a memory stream instead, by pointing the C library's
+//! global `stderr` stream at it for the duration of the call, and parses it into [`MallocStats`].
+//! The standard error file descriptor is never touched, so Rust's own standard error, as written
+//! to by `eprintln!` and panic messages, is unaffected. Replacing `stderr` is only sound while no
+//! other thread uses it, which is why [`malloc_stats`] is `unsafe`.
+
+use crate::memstream::MemStream;
+use crate::{Error, ErrorRepr};
+#[cfg(feature = "serialize")]
+use serde::Serialize;
+use std::sync::{Mutex, PoisonError};
+
+extern "C" {
+    /// The C library's standard error stream, which `malloc_stats` prints to
+    static mut stderr: *mut libc::FILE;
+}
+
+/// Serializes captures, so that concurrent calls don't restore each other's stream
+static CAPTURE: Mutex<()> = Mutex::new(());
+
+/// Statistics for one arena, as printed by `malloc_stats`
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serialize", derive(Serialize))]
+pub struct ArenaStats {
+    /// Number of the arena
+    pub nr: usize,
+
+    /// Bytes obtained from the system for the arena
+    pub system: u64,
+
+    /// Bytes in use by allocations in the arena
+    pub in_use: u64,
+}
+
+/// The parsed output of `malloc_stats`
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serialize", derive(Serialize))]
+pub struct MallocStats {
+    /// Every arena, in the order they were printed
+    pub arenas: Vec<ArenaStats>,
+
+    /// Bytes obtained from the system across all arenas, including chunks allocated with `mmap`
+    pub system: u64,
+
+    /// Bytes in use across all arenas, including chunks allocated with `mmap`
+    pub in_use: u64,
+
+    /// Maximum number of chunks allocated with `mmap` at any one time
+    pub max_mmap_regions: u64,
+
+    /// Maximum bytes allocated with `mmap` at any one time
+    pub max_mmap_bytes: u64,
+}
+
+/// Capture the output of [`libc::malloc_stats`] without printing it, and parse it. See the
+/// [module documentation](self) for how the output is captured.
+///
+/// # Safety
+/// No other thread may use C's `stderr` stream during the call, such as by reading the `stderr`
+/// global or writing to it with `fprintf`. Such a thread could write into the memory stream after
+/// it is freed. Concurrent calls to this function are fine, as they are serialized.
+pub unsafe fn malloc_stats() -> Result<MallocStats, Error> {
+    /// # Safety
+    /// See [`malloc_stats`]
+    unsafe fn malloc_stats() -> Result<MallocStats, ErrorRepr> {
+        let mem_stream = MemStream::new()?;
+        {
+            let _capture = CAPTURE.lock().unwrap_or_else(PoisonError::into_inner);
+            // SAFETY: The caller guarantees that no other thread uses `stderr` during the call, and
+            // the lock keeps other calls out. `stderr` is restored before the memory stream is
+            // dropped, which is valid for the duration of the call.
+            unsafe {
+                let saved = stderr;
+                stderr = mem_stream.fp;
+                libc::malloc_stats();
+                stderr = saved;
+            }
+        }
+        // SAFETY: The FILE pointer is taken from the mem_stream object, which we have exclusive
+        // access to in this function
+        if unsafe { libc::fflush(mem_stream.fp) } != 0 {
+            return Err(ErrorRepr::last_os_error());
+        }
+
+        let output = String::from_utf8_lossy(mem_stream.as_ref());
+        parse(&output).map_err(ErrorRepr::Stats)
+    }
+    // SAFETY: Forwarded to the caller
+    unsafe { malloc_stats() }.map_err(Error::from)
+}
+
+/// Parse the output of `malloc_stats`, returning a description of the problem if it is malformed
+fn parse(output: &str) -> Result<MallocStats, String> {
+    let mut stats = MallocStats::default();
+    let mut arena = None;
+    let mut in_total = false;
+
+    for line in output.lines().filter(|line| !line.trim().is_empty()) {
+        if let Some(nr) = line
+            .strip_prefix("Arena ")
+            .and_then(|rest| rest.strip_suffix(':'))
+        {
+            let nr = nr
+                .parse()
+                .map_err(|_| format!("invalid arena number in `{line}`"))?;
+            stats.arenas.extend(arena.take());
+            arena = Some(ArenaStats {
+                nr,
+                ..Default::default()
+            });
+            continue;
+        }
+        if line.starts_with("Total") {
+            stats.arenas.extend(arena.take());
+            in_total = true;
+            continue;
+        }
+
+        let (name, value) = line
+            .split_once('=')
+            .ok_or_else(|| format!("unexpected line `{line}`"))?;
+        let value = value
+            .trim()
+            .parse()
+            .map_err(|_| format!("invalid number in `{line}`"))?;
+        let field = match (name.trim(), arena.as_mut()) {
+            ("system bytes", Some(arena)) => &mut arena.system,
+            ("in use bytes", Some(arena)) => &mut arena.in_use,
+            ("system bytes", None) if in_total => &mut stats.system,
+            ("in use bytes", None) if in_total => &mut stats.in_use,
+            ("max mmap regions", None) => &mut stats.max_mmap_regions,
+            ("max mmap bytes", None) => &mut stats.max_mmap_bytes,
+            _ => return Err(format!("unexpected line `{line}`")),
+        };
+        *field = value;
+    }
+
+    if !in_total {
+        return Err("missing totals".into());
+    }
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const OUTPUT: &str = "\
+Arena 0:
+system bytes     =     135168
+in use bytes     =      74352
+Arena 1:
+system bytes     =     135168
+in use bytes     =       2336
+Total (incl. mmap):
+system bytes     =     405504
+in use bytes     =     210832
+max mmap regions =          1
+max mmap bytes   =     135168
+";
+
+    #[test]
+    fn parse_output() {
+        let stats = parse(OUTPUT).expect("parse");
+        assert_eq!(
+            stats,
+            MallocStats {
+                arenas: vec![
+                    ArenaStats {
+                        nr: 0,
+                        system: 135168,
+                        in_use: 74352,
+                    },
+                    ArenaStats {
+                        nr: 1,
+                        system: 135168,
+                        in_use: 2336,
+                    },
+                ],
+                system: 405504,
+                in_use: 210832,
+                max_mmap_regions: 1,
+                max_mmap_bytes: 135168,
+            }
+        );
+    }
+
+    #[test]
+    fn parse_invalid() {
+        assert!(parse("").is_err());
+        assert!(parse(&OUTPUT.replace("74352", "lots")).is_err());
+        assert!(parse(&OUTPUT.replace("Arena 1:", "Arena one:")).is_err());
+        assert!(parse(&OUTPUT.replace("system bytes", "other bytes")).is_err());
+        assert!(parse(&OUTPUT[..OUTPUT.find("Total").expect("totals")]).is_err());
+    }
+
+    #[test]
+    fn capture() {
+        // SAFETY: Nothing in the tests uses C's `stderr`
+        let stats = unsafe { malloc_stats() }.expect("malloc_stats");
+        assert!(!stats.arenas.is_empty());
+        assert_eq!(stats.arenas[0].nr, 0);
+        assert!(stats.system >= stats.arenas[0].system);
+        assert!(stats.in_use > 0);
+    }
+}
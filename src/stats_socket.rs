@@ -0,0 +1,300 @@
+//! A line-protocol server on a Unix domain socket, in the style of memcached's `stats` command, for
+//! interrogating a running process without an HTTP stack.
+//!
+//! Clients send one command per line and get one response per command:
+//!
+//! - `stats`: a fresh [`MallocSummary`](crate::MallocSummary), one `STAT <name> <value>` line per
+//!   field, followed by `END`.
+//! - `json`: a fresh snapshot as JSON, on a single line.
+//! - `trim`: release free memory back to the system with [`malloc_trim(0)`](crate::malloc_trim).
+//!   The response is `OK released` if any memory was released, and `OK nothing released`
+//!   otherwise.
+//! - `quit`: close the connection.
+//!
+//! Failed captures are answered with `SERVER_ERROR <message>`, and unknown commands with `ERROR`.
+//! Lines end in `\r\n`, and either `\n` or `\r\n` is accepted from clients.
+//!
+//! ```no_run
+//! # use malloc_info::stats_socket::serve_stats;
+//! let server = serve_stats("/run/myservice/malloc.sock")?;
+//! # Ok::<(), std::io::Error>(())
+//! ```
+//!
+//! ```text
+//! $ echo stats | socat - UNIX-CONNECT:/run/myservice/malloc.sock
+//! STAT arenas 4
+//! STAT system_current 2310144
+//! ...
+//! END
+//! ```
+//!
+//! Anyone who can connect to the socket can run every command, so restrict access with the
+//! permissions of the socket file or of its directory.
+
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+/// Time a connection may stay idle before the server closes it
+const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Upper bound on the length of a command line
+const MAX_LINE: u64 = 1 << 10;
+
+/// Handle to a running stats server. Dropping the handle leaves the server running for the rest of
+/// the process; call [`StatsServer::shutdown`] to stop it.
+#[derive(Debug)]
+pub struct StatsServer {
+    path: PathBuf,
+    shutdown: Arc<AtomicBool>,
+    thread: thread::JoinHandle<()>,
+}
+
+impl StatsServer {
+    /// Path of the socket the server is listening on
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Stop accepting connections, wait for the server's thread to exit and remove the socket
+    /// file. Connections already open are served until the client closes them.
+    pub fn shutdown(self) {
+        self.shutdown.store(true, Ordering::SeqCst);
+        // Wake the server up from accept(). If this fails, the server is already gone.
+        let _ = UnixStream::connect(&self.path);
+        let _ = self.thread.join();
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// Listen on the Unix socket at `path` and serve commands on a new thread, with a thread per
+/// connection. See the [module documentation](self) for the commands.
+///
+/// A socket file left behind at `path` by a process which no longer listens on it is replaced,
+/// while an error is returned if another process is still listening on it.
+pub fn serve_stats(path: impl AsRef<Path>) -> io::Result<StatsServer> {
+    let path = path.as_ref().to_owned();
+    let listener = match UnixListener::bind(&path) {
+        Err(e) if e.kind() == io::ErrorKind::AddrInUse && is_stale(&path) => {
+            std::fs::remove_file(&path)?;
+            UnixListener::bind(&path)?
+        }
+        result => result?,
+    };
+    let shutdown = Arc::new(AtomicBool::new(false));
+
+    let thread = {
+        let shutdown = Arc::clone(&shutdown);
+        thread::Builder::new()
+            .name("malloc-info-stats".into())
+            .spawn(move || {
+                for stream in listener.incoming() {
+                    if shutdown.load(Ordering::SeqCst) {
+                        break;
+                    }
+                    // A misbehaving client only affects its own connection
+                    if let Ok(stream) = stream {
+                        let _ = thread::Builder::new()
+                            .name("malloc-info-stats-client".into())
+                            .spawn(move || serve(stream));
+                    }
+                }
+            })?
+    };
+
+    Ok(StatsServer {
+        path,
+        shutdown,
+        thread,
+    })
+}
+
+/// Whether the socket at `path` is left over from a process which no longer listens on it.
+/// Connecting to a file which isn't a socket is refused too, so the file type is checked first.
+fn is_stale(path: &Path) -> bool {
+    let is_socket = std::fs::symlink_metadata(path)
+        .map(|metadata| metadata.file_type().is_socket())
+        .unwrap_or(false);
+    is_socket
+        && matches!(
+            UnixStream::connect(path),
+            Err(e) if e.kind() == io::ErrorKind::ConnectionRefused
+        )
+}
+
+/// Serve commands on a newly accepted connection until the client closes it or quits
+fn serve(stream: UnixStream) -> io::Result<()> {
+    stream.set_read_timeout(Some(IDLE_TIMEOUT))?;
+    stream.set_write_timeout(Some(IDLE_TIMEOUT))?;
+    let mut reader = BufReader::new(&stream);
+    let mut writer = &stream;
+
+    let mut line = String::new();
+    loop {
+        line.clear();
+        if (&mut reader).take(MAX_LINE).read_line(&mut line)? == 0 {
+            return Ok(());
+        }
+        if !line.ends_with('\n') {
+            return writer.write_all(b"CLIENT_ERROR line too long\r\n");
+        }
+        let response = match line.trim() {
+            "" => continue,
+            "quit" => return Ok(()),
+            command => respond(command),
+        };
+        writer.write_all(response.as_bytes())?;
+    }
+}
+
+/// Build the response to `command`
+fn respond(command: &str) -> String {
+    match command {
+        "stats" => match crate::malloc_info_summary() {
+            Ok(summary) => {
+                let mut response = String::new();
+                for (name, value) in [
+                    ("arenas", summary.arenas as u64),
+                    ("system_current", summary.system_current),
+                    ("system_max", summary.system_max),
+                    ("fast", summary.fast),
+                    ("rest", summary.rest),
+                    ("mmap", summary.mmap),
+                    ("mmap_count", summary.mmap_count),
+                ] {
+                    response.push_str(&format!("STAT {name} {value}\r\n"));
+                }
+                response + "END\r\n"
+            }
+            Err(e) => server_error(e),
+        },
+        "json" => match crate::malloc_info()
+            .map_err(|e| e.to_string())
+            .and_then(|info| serde_json::to_string(&info).map_err(|e| e.to_string()))
+        {
+            Ok(json) => json + "\r\n",
+            Err(e) => server_error(e),
+        },
+        "trim" => match crate::malloc_trim(0) {
+            Ok(true) => "OK released\r\n".into(),
+            Ok(false) => "OK nothing released\r\n".into(),
+            Err(e) => server_error(e),
+        },
+        _ => "ERROR\r\n".into(),
+    }
+}
+
+/// A `SERVER_ERROR` response, on a single line
+fn server_error(e: impl ToString) -> String {
+    format!(
+        "SERVER_ERROR {}\r\n",
+        e.to_string().replace(['\r', '\n'], " ")
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn socket_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("malloc-info-{name}-{}.sock", std::process::id()))
+    }
+
+    /// Send `commands` to the server at `path` and return everything it answered
+    fn session(path: &Path, commands: &str) -> String {
+        let mut stream = UnixStream::connect(path).expect("connect");
+        stream.write_all(commands.as_bytes()).expect("write");
+        let mut response = String::new();
+        stream.read_to_string(&mut response).expect("read");
+        response
+    }
+
+    #[test]
+    fn commands() {
+        let path = socket_path("stats");
+        let _ = std::fs::remove_file(&path);
+        let server = serve_stats(&path).expect("serve");
+        assert_eq!(server.path(), path);
+
+        let response = session(&path, "stats\r\n\njson\ntrim\nbogus\nquit\nstats\n");
+        let mut lines = response.split_terminator("\r\n");
+        let stats = lines.by_ref().take_while(|line| *line != "END");
+        let names: Vec<_> = stats
+            .map(|line| {
+                let mut fields = line.split(' ');
+                assert_eq!(fields.next(), Some("STAT"));
+                let name = fields.next().expect("name");
+                fields
+                    .next()
+                    .expect("value")
+                    .parse::<u64>()
+                    .expect("number");
+                name
+            })
+            .collect();
+        assert_eq!(
+            names,
+            [
+                "arenas",
+                "system_current",
+                "system_max",
+                "fast",
+                "rest",
+                "mmap",
+                "mmap_count"
+            ]
+        );
+
+        let json: serde_json::Value =
+            serde_json::from_str(lines.next().expect("json")).expect("valid JSON");
+        assert!(json["heaps"].is_array());
+        assert!(lines.next().expect("trim").starts_with("OK "));
+        assert_eq!(lines.next(), Some("ERROR"));
+        // Nothing after quit
+        assert_eq!(lines.next(), None);
+
+        server.shutdown();
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn long_line() {
+        let path = socket_path("stats-long");
+        let _ = std::fs::remove_file(&path);
+        let server = serve_stats(&path).expect("serve");
+        let response = session(&path, &"x".repeat(MAX_LINE as usize + 1));
+        assert_eq!(response, "CLIENT_ERROR line too long\r\n");
+        server.shutdown();
+    }
+
+    #[test]
+    fn stale_socket() {
+        let path = socket_path("stats-stale");
+        let _ = std::fs::remove_file(&path);
+        drop(UnixListener::bind(&path).expect("bind"));
+        assert!(path.exists());
+
+        let server = serve_stats(&path).expect("replace stale socket");
+        assert!(serve_stats(&path).is_err());
+        assert!(session(&path, "stats\nquit\n").ends_with("END\r\n"));
+        server.shutdown();
+    }
+
+    #[test]
+    fn regular_file() {
+        let path = socket_path("stats-file");
+        std::fs::write(&path, "not a socket").expect("write");
+
+        assert!(serve_stats(&path).is_err());
+        assert_eq!(
+            std::fs::read_to_string(&path).expect("read"),
+            "not a socket"
+        );
+        std::fs::remove_file(&path).expect("clean up");
+    }
+}
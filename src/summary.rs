@@ -0,0 +1,360 @@
+//! A lightweight summary of the output of `malloc_info`, holding just the headline numbers.
+//!
+//! [`MallocSummary`] is extracted straight from the XML without building an
+//! [`info::Malloc`](crate::info::Malloc), so it is cheap enough to produce on every sample. Rather
+//! than tokenizing the document, the extractor relies on the fixed layout of the output: arenas
+//! are counted by searching for `<heap ` with [`memchr::memmem`], and the document-level entries
+//! following the last arena are picked apart by scanning for their `type="` and `size="`
+//! attributes. Nothing is allocated, and the per-arena sections are never looked at.
+
+use crate::info::{Malloc, TotalType};
+use memchr::{memchr, memmem};
+use quick_xml::DeError;
+#[cfg(feature = "serialize")]
+use serde::Serialize;
+
+/// The headline numbers from `malloc_info`, taken from the document-level totals
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serialize", derive(Serialize))]
+pub struct MallocSummary {
+    /// Number of arenas
+    pub arenas: usize,
+
+    /// Bytes currently obtained from the system, across all arenas
+    pub system_current: u64,
+
+    /// Maximum bytes ever obtained from the system, across all arenas
+    pub system_max: u64,
+
+    /// Bytes held in free fastbin chunks
+    pub fast: u64,
+
+    /// Bytes held in all other free chunks
+    pub rest: u64,
+
+    /// Bytes allocated directly with `mmap`
+    pub mmap: u64,
+
+    /// Number of chunks allocated directly with `mmap`
+    pub mmap_count: u64,
+}
+
+impl MallocSummary {
+    /// The summary as a handful of short key/value pairs, a few hundred bytes in total, suitable
+    /// for attaching to crash reports as annotations (Crashpad, Breakpad, sentry-native and the
+    /// like). Values are in decimal, sizes in bytes.
+    pub fn annotations(&self) -> [(&'static str, String); 7] {
+        [
+            ("malloc.arenas", self.arenas.to_string()),
+            ("malloc.system_current", self.system_current.to_string()),
+            ("malloc.system_max", self.system_max.to_string()),
+            ("malloc.free_fast", self.fast.to_string()),
+            ("malloc.free_rest", self.rest.to_string()),
+            ("malloc.mmap", self.mmap.to_string()),
+            ("malloc.mmap_count", self.mmap_count.to_string()),
+        ]
+    }
+
+    /// Merge two summaries, such as those of two processes, by adding up every field. Returns
+    /// `None` if any field overflows.
+    pub fn checked_add(&self, other: &Self) -> Option<Self> {
+        Some(Self {
+            arenas: self.arenas.checked_add(other.arenas)?,
+            system_current: self.system_current.checked_add(other.system_current)?,
+            system_max: self.system_max.checked_add(other.system_max)?,
+            fast: self.fast.checked_add(other.fast)?,
+            rest: self.rest.checked_add(other.rest)?,
+            mmap: self.mmap.checked_add(other.mmap)?,
+            mmap_count: self.mmap_count.checked_add(other.mmap_count)?,
+        })
+    }
+
+    /// Merge two summaries by adding up every field, saturating at the maximum value of each
+    /// field instead of overflowing
+    pub fn saturating_add(&self, other: &Self) -> Self {
+        Self {
+            arenas: self.arenas.saturating_add(other.arenas),
+            system_current: self.system_current.saturating_add(other.system_current),
+            system_max: self.system_max.saturating_add(other.system_max),
+            fast: self.fast.saturating_add(other.fast),
+            rest: self.rest.saturating_add(other.rest),
+            mmap: self.mmap.saturating_add(other.mmap),
+            mmap_count: self.mmap_count.saturating_add(other.mmap_count),
+        }
+    }
+
+    /// Merge any number of summaries with [`checked_add`](Self::checked_add). The sum of no
+    /// summaries is all zeroes.
+    pub fn checked_sum<'a>(summaries: impl IntoIterator<Item = &'a Self>) -> Option<Self> {
+        summaries
+            .into_iter()
+            .try_fold(Self::default(), |sum, summary| sum.checked_add(summary))
+    }
+
+    /// Merge any number of summaries with [`saturating_add`](Self::saturating_add). The sum of no
+    /// summaries is all zeroes.
+    pub fn saturating_sum<'a>(summaries: impl IntoIterator<Item = &'a Self>) -> Self {
+        summaries
+            .into_iter()
+            .fold(Self::default(), |sum, summary| sum.saturating_add(summary))
+    }
+}
+
+impl Malloc {
+    /// The headline numbers of the snapshot, as a [`MallocSummary`]. This gives the same numbers
+    /// as [`malloc_info_summary`](crate::malloc_info_summary) would have for the same output:
+    /// the document-level entries, with missing ones as zero.
+    pub fn summary(&self) -> MallocSummary {
+        let total = |r#type| self.total_size(r#type).unwrap_or_default();
+        MallocSummary {
+            arenas: self.heaps.len(),
+            system_current: self.system_current().unwrap_or_default(),
+            system_max: self.system_max().unwrap_or_default(),
+            fast: total(TotalType::Fast),
+            rest: total(TotalType::Rest),
+            mmap: total(TotalType::Mmap),
+            mmap_count: self
+                .total_by(TotalType::Mmap)
+                .map_or(0, |total| total.count),
+        }
+    }
+}
+
+/// Extract a [`MallocSummary`] from `xml`. Entries missing from the output are left as zero.
+pub(crate) fn summarize(xml: &[u8]) -> Result<MallocSummary, DeError> {
+    let root = memmem::find(xml, b"<malloc ")
+        .ok_or_else(|| DeError::Custom("expected <malloc> root element".into()))?;
+    let xml = &xml[root..];
+
+    let mut summary = MallocSummary {
+        arenas: memmem::find_iter(xml, b"<heap ").count(),
+        ..Default::default()
+    };
+
+    // The document-level entries all follow the last arena
+    let tail = match memmem::rfind(xml, b"</heap>") {
+        Some(last) => &xml[last..],
+        None => xml,
+    };
+    let end = memmem::find(tail, b"</malloc>")
+        .ok_or_else(|| DeError::Custom("unexpected end of document".into()))?;
+
+    let mut rest = &tail[..end];
+    while let Some(start) = memchr(b'<', rest) {
+        let len = memchr(b'>', &rest[start..])
+            .ok_or_else(|| DeError::Custom("unexpected end of document".into()))?;
+        let tag = &rest[start + 1..start + len];
+        rest = &rest[start + len..];
+
+        let (field, count) = match (element_name(tag), attribute(tag, b"type")) {
+            (b"total", Some(b"fast")) => (&mut summary.fast, None),
+            (b"total", Some(b"rest")) => (&mut summary.rest, None),
+            (b"total", Some(b"mmap")) => (&mut summary.mmap, Some(&mut summary.mmap_count)),
+            (b"system", Some(b"current")) => (&mut summary.system_current, None),
+            (b"system", Some(b"max")) => (&mut summary.system_max, None),
+            _ => continue,
+        };
+        let size = attribute(tag, b"size")
+            .ok_or_else(|| DeError::Custom("missing field `@size`".into()))?;
+        *field = number(size)?;
+        if let Some(count) = count {
+            let value = attribute(tag, b"count")
+                .ok_or_else(|| DeError::Custom("missing field `@count`".into()))?;
+            *count = number(value)?;
+        }
+    }
+
+    Ok(summary)
+}
+
+/// Name of the element whose tag (without the angle brackets) is `tag`
+fn element_name(tag: &[u8]) -> &[u8] {
+    let end = tag
+        .iter()
+        .position(|b| b.is_ascii_whitespace() || *b == b'/')
+        .unwrap_or(tag.len());
+    &tag[..end]
+}
+
+/// Raw value of the attribute `name` in `tag`. `malloc_info` never escapes its attribute values,
+/// so they are used as-is.
+fn attribute<'a>(tag: &'a [u8], name: &[u8]) -> Option<&'a [u8]> {
+    memmem::find_iter(tag, name).find_map(|at| {
+        if at == 0 || !tag[at - 1].is_ascii_whitespace() {
+            return None;
+        }
+        let value = tag[at + name.len()..].strip_prefix(b"=\"")?;
+        memchr(b'"', value).map(|end| &value[..end])
+    })
+}
+
+/// Parse a decimal attribute value
+fn number(value: &[u8]) -> Result<u64, DeError> {
+    std::str::from_utf8(value)
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .ok_or_else(|| {
+            DeError::Custom(format!(
+                "invalid number `{}`",
+                String::from_utf8_lossy(value)
+            ))
+        })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // Taken from the malloc_info(3) man-page, with a mmap total added
+    const XML: &str = r#"
+<malloc version="1">
+<heap nr="0">
+<sizes>
+</sizes>
+<total type="fast" count="0" size="0"/>
+<total type="rest" count="0" size="0"/>
+<system type="current" size="1081344"/>
+<system type="max" size="1081344"/>
+<aspace type="total" size="1081344"/>
+<aspace type="mprotect" size="1081344"/>
+</heap>
+<heap nr="1">
+<sizes>
+<size from="17" to="32" total="64" count="2"/>
+</sizes>
+<total type="fast" count="2" size="64"/>
+<total type="rest" count="0" size="0"/>
+<system type="current" size="1032192"/>
+<system type="max" size="1032192"/>
+<aspace type="total" size="1032192"/>
+<aspace type="mprotect" size="1032192"/>
+</heap>
+<total type="fast" count="2" size="64"/>
+<total type="rest" count="1" size="4096"/>
+<total type="mmap" count="1" size="266240"/>
+<system type="current" size="2113536"/>
+<system type="max" size="2113536"/>
+<aspace type="total" size="2113536"/>
+<aspace type="mprotect" size="2113536"/>
+</malloc>
+"#;
+
+    #[test]
+    fn summarize_complex() {
+        let summary = summarize(XML.as_bytes()).expect("parse XML");
+        assert_eq!(
+            summary,
+            MallocSummary {
+                arenas: 2,
+                system_current: 2113536,
+                system_max: 2113536,
+                fast: 64,
+                rest: 4096,
+                mmap: 266240,
+                mmap_count: 1,
+            }
+        );
+    }
+
+    #[test]
+    fn from_malloc() {
+        let info: Malloc = XML.parse().expect("parse XML");
+        let summary = info.summary();
+        assert_eq!(summary, summarize(XML.as_bytes()).expect("parse XML"));
+        assert_eq!(summary.mmap, 266240);
+        assert_eq!(summary.mmap_count, 1);
+        assert_eq!(Malloc::default().summary(), MallocSummary::default());
+    }
+
+    #[test]
+    fn summarize_missing() {
+        let xml = XML.replace(r#"<total type="mmap" count="1" size="266240"/>"#, "");
+        let summary = summarize(xml.as_bytes()).expect("parse XML");
+        assert_eq!(summary.mmap, 0);
+    }
+
+    #[test]
+    fn summarize_invalid() {
+        let xml = XML.replace(r#"size="2113536""#, r#"size="lots""#);
+        assert!(summarize(xml.as_bytes()).is_err());
+        assert!(summarize(&XML.as_bytes()[..XML.len() / 2]).is_err());
+        assert!(summarize(b"<heap nr=\"0\"></heap>").is_err());
+    }
+
+    #[test]
+    fn summarize_no_arenas() {
+        let xml = r#"<malloc version="1">
+<total type="fast" count="0" size="0"/>
+<total type="rest" count="1" size="4096"/>
+<system type="current" size="135168"/>
+<system type="max" size="135168"/>
+</malloc>"#;
+        let summary = summarize(xml.as_bytes()).expect("parse XML");
+        assert_eq!(summary.arenas, 0);
+        assert_eq!(summary.rest, 4096);
+        assert_eq!(summary.system_max, 135168);
+    }
+
+    #[test]
+    fn annotations() {
+        let summary = summarize(XML.as_bytes()).expect("parse XML");
+        let annotations = summary.annotations();
+        assert!(annotations.contains(&("malloc.arenas", "2".into())));
+        assert!(annotations.contains(&("malloc.mmap", "266240".into())));
+        assert!(annotations.contains(&("malloc.mmap_count", "1".into())));
+
+        let max = MallocSummary {
+            arenas: usize::MAX,
+            system_current: u64::MAX,
+            system_max: u64::MAX,
+            fast: u64::MAX,
+            rest: u64::MAX,
+            mmap: u64::MAX,
+            mmap_count: u64::MAX,
+        };
+        let len: usize = max
+            .annotations()
+            .iter()
+            .map(|(key, value)| key.len() + value.len())
+            .sum();
+        assert!(len < 256, "{len}");
+    }
+
+    #[test]
+    fn add() {
+        let summary = summarize(XML.as_bytes()).expect("parse XML");
+        let sum = MallocSummary::checked_sum(&[summary, summary, summary]).expect("sum");
+        assert_eq!(sum.arenas, 6);
+        assert_eq!(sum.mmap, 3 * 266240);
+        assert_eq!(sum.mmap_count, 3);
+        assert_eq!(
+            MallocSummary::saturating_sum(&[summary, summary, summary]),
+            sum
+        );
+        assert_eq!(
+            MallocSummary::checked_sum(&[]),
+            Some(MallocSummary::default())
+        );
+
+        let full = MallocSummary {
+            mmap: u64::MAX - 1,
+            ..summary
+        };
+        assert_eq!(full.checked_add(&summary), None);
+        assert_eq!(MallocSummary::checked_sum(&[summary, full]), None);
+        let saturated = full.saturating_add(&summary);
+        assert_eq!(saturated.mmap, u64::MAX);
+        assert_eq!(saturated.fast, 128);
+        assert_eq!(MallocSummary::saturating_sum(&[summary, full]), saturated);
+    }
+
+    #[test]
+    fn attributes() {
+        let tag = br#"total type="rest" count="1" size="4096"/"#;
+        assert_eq!(element_name(tag), b"total");
+        assert_eq!(attribute(tag, b"type"), Some(&b"rest"[..]));
+        assert_eq!(attribute(tag, b"size"), Some(&b"4096"[..]));
+        assert_eq!(attribute(tag, b"ize"), None);
+        assert_eq!(attribute(tag, b"from"), None);
+    }
+}
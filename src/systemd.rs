@@ -0,0 +1,443 @@
+//! Reporting heap health to systemd: as the service status through the `sd_notify` protocol, and
+//! as structured journal entries.
+//!
+//! A [`Notifier`] sends a status line such as `heap 1.2GiB/4GiB, 12 arenas` to the service
+//! manager, which shows it in `systemctl status`. For services with `WatchdogSec=` set, it can
+//! also keep the watchdog alive, but only while captures succeed, so a service whose heap can no
+//! longer be inspected is restarted.
+//!
+//! ```rust,no_run
+//! use malloc_info::systemd::Notifier;
+//! use std::time::Duration;
+//!
+//! if let Some(notifier) = Notifier::from_env() {
+//!     let notifier = notifier.with_limit(4 << 30).with_watchdog(true);
+//!     loop {
+//!         notifier.notify().expect("sd_notify");
+//!         std::thread::sleep(Duration::from_secs(10));
+//!     }
+//! }
+//! ```
+//!
+//! A [`Journal`] writes summaries to the journal with every field of [`MallocSummary`] as a
+//! separate journal field, `MALLOC_ARENAS`, `MALLOC_SYSTEM_CURRENT`, `MALLOC_SYSTEM_MAX`,
+//! `MALLOC_FAST`, `MALLOC_REST`, `MALLOC_MMAP` and `MALLOC_MMAP_COUNT`, so they can be queried
+//! directly:
+//!
+//! ```text
+//! journalctl -o json MALLOC_ARENAS=12
+//! ```
+//!
+//! Both protocols are implemented directly on Unix datagram sockets, so libsystemd is not needed.
+
+use crate::{malloc_info_summary, Error, ErrorRepr, MallocSummary};
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+
+/// Environment variable holding the path of the service manager's notification socket
+pub const ENV_SOCKET: &str = "NOTIFY_SOCKET";
+
+/// Path of the journal's native protocol socket
+pub const JOURNAL_SOCKET: &str = "/run/systemd/journal/socket";
+
+/// Sends heap summaries to the service manager as the service status
+#[derive(Debug, Clone)]
+pub struct Notifier {
+    socket: PathBuf,
+    limit: Option<u64>,
+    watchdog: bool,
+}
+
+impl Notifier {
+    /// Notify the service manager listening on `socket`. A path starting with `@` refers to a
+    /// socket in the abstract namespace.
+    pub fn new(socket: impl Into<PathBuf>) -> Self {
+        Self {
+            socket: socket.into(),
+            limit: None,
+            watchdog: false,
+        }
+    }
+
+    /// Notify the service manager named by [`ENV_SOCKET`], or `None` if the variable isn't set,
+    /// as when the process wasn't started by systemd or the unit isn't `Type=notify`.
+    pub fn from_env() -> Option<Self> {
+        std::env::var_os(ENV_SOCKET)
+            .filter(|socket| !socket.is_empty())
+            .map(Self::new)
+    }
+
+    /// Show the heap size against `limit` bytes, such as the `MemoryMax=` of the unit
+    pub fn with_limit(mut self, limit: u64) -> Self {
+        self.limit = Some(limit);
+        self
+    }
+
+    /// Also send `WATCHDOG=1` with every successful capture
+    pub fn with_watchdog(mut self, watchdog: bool) -> Self {
+        self.watchdog = watchdog;
+        self
+    }
+
+    /// Take a [`MallocSummary`] and report it as the service status. If the capture fails, the
+    /// error is reported as the status instead, and the watchdog isn't extended.
+    pub fn notify(&self) -> io::Result<()> {
+        let message = match malloc_info_summary() {
+            Ok(summary) if self.watchdog => format!("STATUS={}\nWATCHDOG=1", self.status(&summary)),
+            Ok(summary) => format!("STATUS={}", self.status(&summary)),
+            Err(error) => format!("STATUS=heap unavailable: {error}"),
+        };
+        self.send(&message)
+    }
+
+    /// The status line for `summary`: the bytes obtained from the system, against the limit if one
+    /// is set, and the number of arenas
+    pub fn status(&self, summary: &MallocSummary) -> String {
+        describe(summary, self.limit)
+    }
+
+    /// Send `message`, a newline-separated list of assignments, to the service manager
+    pub fn send(&self, message: &str) -> io::Result<()> {
+        send(&self.socket, message.as_bytes())
+    }
+}
+
+/// Writes heap summaries to the journal as structured entries
+#[derive(Debug, Clone)]
+pub struct Journal {
+    socket: PathBuf,
+    priority: u8,
+    fields: Vec<(String, String)>,
+}
+
+impl Default for Journal {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Journal {
+    /// Write to the journal of the system at [`JOURNAL_SOCKET`], at the `info` priority
+    pub fn new() -> Self {
+        Self {
+            socket: JOURNAL_SOCKET.into(),
+            priority: 6,
+            fields: Vec::new(),
+        }
+    }
+
+    /// Write to the journal listening on `socket` instead
+    pub fn with_socket(mut self, socket: impl Into<PathBuf>) -> Self {
+        self.socket = socket.into();
+        self
+    }
+
+    /// Write entries at the syslog `priority`, from 0 (`emerg`) to 7 (`debug`). Larger values
+    /// are clamped to 7.
+    pub fn with_priority(mut self, priority: u8) -> Self {
+        self.priority = priority.min(7);
+        self
+    }
+
+    /// Add the field `name=value` to every entry, such as `SYSLOG_IDENTIFIER`. The name is
+    /// upper-cased, and characters other than letters, digits and underscores are replaced with
+    /// underscores, as journal field names allow nothing else.
+    pub fn with_field(mut self, name: &str, value: &str) -> Self {
+        self.fields.push((field_name(name), value.into()));
+        self
+    }
+
+    /// Take a [`MallocSummary`] and write it to the journal
+    pub fn log(&self) -> Result<(), Error> {
+        let summary = malloc_info_summary()?;
+        self.send(&summary)
+            .map_err(|error| Error::from(ErrorRepr::LibC(error)))
+    }
+
+    /// Write `summary` to the journal
+    pub fn send(&self, summary: &MallocSummary) -> io::Result<()> {
+        send(&self.socket, &self.entry(summary))
+    }
+
+    /// The journal entry for `summary`, in the native protocol's serialization
+    fn entry(&self, summary: &MallocSummary) -> Vec<u8> {
+        let numbers = [
+            ("MALLOC_ARENAS", summary.arenas as u64),
+            ("MALLOC_SYSTEM_CURRENT", summary.system_current),
+            ("MALLOC_SYSTEM_MAX", summary.system_max),
+            ("MALLOC_FAST", summary.fast),
+            ("MALLOC_REST", summary.rest),
+            ("MALLOC_MMAP", summary.mmap),
+            ("MALLOC_MMAP_COUNT", summary.mmap_count),
+        ];
+
+        let mut entry = Vec::new();
+        append_field(&mut entry, "MESSAGE", &describe(summary, None));
+        append_field(&mut entry, "PRIORITY", &self.priority.to_string());
+        for (name, value) in numbers {
+            append_field(&mut entry, name, &value.to_string());
+        }
+        for (name, value) in &self.fields {
+            append_field(&mut entry, name, value);
+        }
+        entry
+    }
+}
+
+/// Describe `summary` by the bytes obtained from the system, against `limit` if given, and the
+/// number of arenas
+fn describe(summary: &MallocSummary, limit: Option<u64>) -> String {
+    let heap = match limit {
+        Some(limit) => format!(
+            "{}/{}",
+            format_bytes(summary.system_current),
+            format_bytes(limit)
+        ),
+        None => format_bytes(summary.system_current),
+    };
+    let arenas = match summary.arenas {
+        1 => "arena",
+        _ => "arenas",
+    };
+    format!("heap {heap}, {} {arenas}", summary.arenas)
+}
+
+/// Append the field `name=value` to a journal entry, in the binary form if `value` spans lines
+fn append_field(entry: &mut Vec<u8>, name: &str, value: &str) {
+    entry.extend_from_slice(name.as_bytes());
+    if value.contains('\n') {
+        entry.push(b'\n');
+        entry.extend_from_slice(&(value.len() as u64).to_le_bytes());
+    } else {
+        entry.push(b'=');
+    }
+    entry.extend_from_slice(value.as_bytes());
+    entry.push(b'\n');
+}
+
+/// Turn `name` into a valid journal field name
+fn field_name(name: &str) -> String {
+    let name: String = name
+        .chars()
+        .map(|c| match c {
+            'a'..='z' => c.to_ascii_uppercase(),
+            'A'..='Z' | '0'..='9' | '_' => c,
+            _ => '_',
+        })
+        .collect();
+    // Leading underscores are reserved for trusted fields set by the journal itself
+    let name = name.trim_start_matches('_');
+    match name.chars().next() {
+        Some('0'..='9') | None => format!("F{name}"),
+        Some(_) => name.into(),
+    }
+}
+
+/// Send `message` as a single datagram to the Unix socket at `socket`
+fn send(socket: &Path, message: &[u8]) -> io::Result<()> {
+    let (addr, len) = socket_addr(socket)?;
+    // SAFETY: The socket is closed below on every path, `message` is valid for reads of its
+    // length, and `addr` is a valid sockaddr_un of at least `len` bytes
+    unsafe {
+        let fd = libc::socket(libc::AF_UNIX, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0);
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let sent = libc::sendto(
+            fd,
+            message.as_ptr().cast(),
+            message.len(),
+            libc::MSG_NOSIGNAL,
+            std::ptr::addr_of!(addr).cast(),
+            len,
+        );
+        let result = if sent < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(())
+        };
+        libc::close(fd);
+        result
+    }
+}
+
+/// Format `bytes` with a binary prefix and one decimal, as in `1.2GiB`
+fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["KiB", "MiB", "GiB", "TiB", "PiB"];
+
+    if bytes < 1024 {
+        return format!("{bytes}B");
+    }
+    let mut value = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    let value = format!("{value:.1}");
+    let value = value.strip_suffix(".0").unwrap_or(&value);
+    format!("{value}{}", UNITS[unit])
+}
+
+/// Build the address of the socket at `path`, translating a leading `@` into the NUL byte which
+/// marks the abstract namespace
+fn socket_addr(socket: &Path) -> io::Result<(libc::sockaddr_un, libc::socklen_t)> {
+    // SAFETY: sockaddr_un is plain old data, for which all zeroes is a valid value
+    let mut addr: libc::sockaddr_un = unsafe { std::mem::zeroed() };
+    addr.sun_family = libc::AF_UNIX as libc::sa_family_t;
+
+    let path = socket.as_os_str().as_bytes();
+    let (path, abstract_namespace) = match path.strip_prefix(b"@") {
+        Some(name) => (name, true),
+        None => (path, false),
+    };
+    let offset = usize::from(abstract_namespace);
+    // Leave room for the terminating NUL byte of filesystem paths
+    if path.is_empty() || offset + path.len() >= addr.sun_path.len() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("invalid notification socket {socket:?}"),
+        ));
+    }
+    for (dst, src) in addr.sun_path[offset..].iter_mut().zip(path) {
+        *dst = *src as _;
+    }
+
+    let len = std::mem::size_of::<libc::sa_family_t>()
+        + offset
+        + path.len()
+        + usize::from(!abstract_namespace);
+    Ok((addr, len as libc::socklen_t))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::os::unix::net::UnixDatagram;
+
+    #[test]
+    fn notify() {
+        let path = std::env::temp_dir().join(format!("malloc-info-notify-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let manager = UnixDatagram::bind(&path).expect("bind");
+
+        Notifier::new(&path)
+            .with_limit(4 << 30)
+            .with_watchdog(true)
+            .notify()
+            .expect("notify");
+        let mut buf = [0u8; 1024];
+        let len = manager.recv(&mut buf).expect("receive");
+        let message = std::str::from_utf8(&buf[..len]).expect("UTF-8");
+        assert!(message.starts_with("STATUS=heap "), "{message}");
+        assert!(message.contains("/4GiB, "), "{message}");
+        assert!(message.ends_with("\nWATCHDOG=1"), "{message}");
+
+        Notifier::new(&path).notify().expect("notify");
+        let len = manager.recv(&mut buf).expect("receive");
+        let message = std::str::from_utf8(&buf[..len]).expect("UTF-8");
+        assert!(!message.contains("WATCHDOG"), "{message}");
+
+        std::fs::remove_file(&path).expect("remove socket");
+    }
+
+    #[test]
+    fn journal() {
+        let path = std::env::temp_dir().join(format!("malloc-info-journal-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let journald = UnixDatagram::bind(&path).expect("bind");
+
+        let journal = Journal::new()
+            .with_socket(&path)
+            .with_priority(9)
+            .with_field("syslog_identifier", "test");
+        journal.log().expect("log");
+        let mut buf = [0u8; 4096];
+        let len = journald.recv(&mut buf).expect("receive");
+        let entry = std::str::from_utf8(&buf[..len]).expect("UTF-8");
+        assert!(entry.starts_with("MESSAGE=heap "), "{entry}");
+        assert!(entry.contains("\nPRIORITY=7\n"), "{entry}");
+        assert!(entry.contains("\nMALLOC_SYSTEM_CURRENT="), "{entry}");
+        assert!(entry.ends_with("\nSYSLOG_IDENTIFIER=test\n"), "{entry}");
+
+        std::fs::remove_file(&path).expect("remove socket");
+    }
+
+    #[test]
+    fn journal_entry() {
+        let summary = MallocSummary {
+            arenas: 2,
+            system_current: 4096,
+            system_max: 8192,
+            fast: 16,
+            rest: 32,
+            mmap: 64,
+            mmap_count: 1,
+        };
+        let entry = Journal::new()
+            .with_field("note", "two\nlines")
+            .entry(&summary);
+        let mut expected = b"MESSAGE=heap 4KiB, 2 arenas\nPRIORITY=6\nMALLOC_ARENAS=2\n\
+            MALLOC_SYSTEM_CURRENT=4096\nMALLOC_SYSTEM_MAX=8192\nMALLOC_FAST=16\nMALLOC_REST=32\n\
+            MALLOC_MMAP=64\nMALLOC_MMAP_COUNT=1\nNOTE\n"
+            .to_vec();
+        expected.extend_from_slice(&9u64.to_le_bytes());
+        expected.extend_from_slice(b"two\nlines\n");
+        assert_eq!(entry, expected);
+    }
+
+    #[test]
+    fn field_names() {
+        assert_eq!(field_name("syslog_identifier"), "SYSLOG_IDENTIFIER");
+        assert_eq!(field_name("_pid"), "PID");
+        assert_eq!(field_name("k8s.pod-name"), "K8S_POD_NAME");
+        assert_eq!(field_name("1st"), "F1ST");
+        assert_eq!(field_name("__"), "F");
+    }
+
+    #[test]
+    fn status() {
+        let summary = MallocSummary {
+            arenas: 12,
+            system_current: 1288490189,
+            ..Default::default()
+        };
+        let notifier = Notifier::new("/run/systemd/notify");
+        assert_eq!(notifier.status(&summary), "heap 1.2GiB, 12 arenas");
+        let notifier = notifier.with_limit(4 << 30);
+        assert_eq!(notifier.status(&summary), "heap 1.2GiB/4GiB, 12 arenas");
+
+        let summary = MallocSummary {
+            arenas: 1,
+            system_current: 512,
+            ..Default::default()
+        };
+        assert_eq!(notifier.status(&summary), "heap 512B/4GiB, 1 arena");
+    }
+
+    #[test]
+    fn bytes() {
+        assert_eq!(format_bytes(0), "0B");
+        assert_eq!(format_bytes(1023), "1023B");
+        assert_eq!(format_bytes(1024), "1KiB");
+        assert_eq!(format_bytes(1536), "1.5KiB");
+        assert_eq!(format_bytes(135168), "132KiB");
+        assert_eq!(format_bytes(u64::MAX), "16384PiB");
+    }
+
+    #[test]
+    fn addresses() {
+        let (addr, len) = socket_addr(Path::new("/run/systemd/notify")).expect("path");
+        assert_eq!(addr.sun_path[0], b'/' as libc::c_char);
+        assert_eq!(len as usize, 2 + 19 + 1);
+
+        let (addr, len) = socket_addr(Path::new("@notify")).expect("abstract");
+        assert_eq!(addr.sun_path[0], 0);
+        assert_eq!(addr.sun_path[1], b'n' as libc::c_char);
+        assert_eq!(len as usize, 2 + 1 + 6);
+
+        assert!(socket_addr(Path::new("@")).is_err());
+        assert!(socket_addr(Path::new(&"x".repeat(108))).is_err());
+    }
+}
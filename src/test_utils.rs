@@ -0,0 +1,80 @@
+//! Helpers for memory-regression gates in ordinary `cargo test` runs.
+//!
+//! Wrap the body of a test in [`report`] to print how much it grew the glibc heap, or in
+//! [`within_budget`] to also fail it when the growth exceeds a budget:
+//!
+//! ```rust
+//! # use malloc_info::test_utils::within_budget;
+//! # fn parse_config(_: &str) -> Vec<String> { Vec::new() }
+//! // In a #[test] function
+//! let config = within_budget(64 << 10, || parse_config("[server]\nport = 80\n"));
+//! assert!(config.is_empty());
+//! ```
+//!
+//! The growth is the change in bytes in use, as in [`MallocDelta::in_use`], measured with
+//! [`measure`] while the test body's result is still alive. Snapshots cover the whole process, and
+//! the test harness runs tests in parallel by default, so other tests' allocations show up in the
+//! delta as well. Run budgeted tests with `--test-threads=1`, or in their own test binary, for
+//! reliable numbers.
+//!
+//! The delta is printed to standard output, which the test harness captures: it is shown for
+//! failing tests, or for every test with `--nocapture`.
+
+use crate::{measure, MallocDelta};
+
+/// Run `f` and print how much it grew the glibc heap, labelled with the name of the current test.
+///
+/// # Panics
+/// Panics if a snapshot can't be taken.
+pub fn report<T>(f: impl FnOnce() -> T) -> T {
+    run(f).0
+}
+
+/// Run `f` like [`report`], and panic if it grew the glibc heap by more than `budget` bytes.
+///
+/// # Panics
+/// Panics if the growth exceeds `budget`, or if a snapshot can't be taken.
+pub fn within_budget<T>(budget: u64, f: impl FnOnce() -> T) -> T {
+    let (result, delta, name) = run(f);
+    let growth = delta.in_use();
+    if growth > 0 && growth as u64 > budget {
+        panic!("{name} grew the heap by {growth} bytes, over its budget of {budget} bytes");
+    }
+    result
+}
+
+/// Run `f` under [`measure`] and print the delta. Returns the result, the delta and the name it was
+/// printed under.
+fn run<T>(f: impl FnOnce() -> T) -> (T, MallocDelta, String) {
+    let (result, delta) = measure(f);
+    let delta = delta.expect("malloc_info_summary");
+    let name = std::thread::current()
+        .name()
+        .unwrap_or("<unnamed>")
+        .to_owned();
+    println!(
+        "{name}: heap in use {:+} bytes (system {:+}, mmap {:+})",
+        delta.in_use(),
+        delta.system_current,
+        delta.mmap
+    );
+    (result, delta, name)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn under_budget() {
+        let value = within_budget(u64::MAX, || 42);
+        assert_eq!(value, 42);
+        assert_eq!(report(|| "report"), "report");
+    }
+
+    #[test]
+    #[should_panic(expected = "over its budget of 1024 bytes")]
+    fn over_budget() {
+        within_budget(1024, || vec![1u8; 64 << 20]);
+    }
+}
@@ -0,0 +1,175 @@
+//! An opt-in, process-wide guard against stampeding glibc with captures.
+//!
+//! `malloc_info` takes every arena lock in turn. When several libraries in one process each sample
+//! the heap on their own schedule, their captures pile up on those locks at the same time. Once
+//! the application sets a minimum interval with [`set_min_interval`], every capture made through
+//! this crate is serialized, and a capture requested sooner than the interval after the previous
+//! one is answered from the previous one's output instead of calling into glibc again.
+//!
+//! The guard applies to every capture function of the crate, including
+//! [`MallocInfoReader`](crate::MallocInfoReader) and [`malloc_info_raw`](crate::malloc_info_raw),
+//! except [`measure_in_child`](crate::measure_in_child), whose child process always captures its
+//! own heap. Enabling it costs a copy of the output per capture, which is kept for reuse. While
+//! output is being reused, [`measure`](crate::measure) and friends report no change.
+//!
+//! ```rust
+//! use std::time::Duration;
+//!
+//! malloc_info::throttle::set_min_interval(Some(Duration::from_secs(1)));
+//! let first = malloc_info::malloc_info().expect("malloc_info");
+//! let second = malloc_info::malloc_info().expect("malloc_info");
+//! assert_eq!(first, second);
+//! # malloc_info::throttle::set_min_interval(None);
+//! ```
+
+use crate::memstream::MemStream;
+use crate::{write_fresh, ErrorRepr};
+use std::sync::{Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+/// State of the guard, shared by the whole process
+static STATE: Mutex<State> = Mutex::new(State::new());
+
+/// Set the minimum interval between captures which actually call into glibc, or disable the guard
+/// with `None`, which is the default.
+///
+/// With an interval set, captures are serialized, and a capture starting less than the interval
+/// after the previous one reuses its output. A zero interval only serializes captures. Changing
+/// the interval discards any output kept for reuse.
+pub fn set_min_interval(min_interval: Option<Duration>) {
+    let mut state = STATE.lock().unwrap_or_else(PoisonError::into_inner);
+    state.min_interval = min_interval;
+    state.last = None;
+}
+
+/// The minimum interval between captures, as set by [`set_min_interval`]
+pub fn min_interval() -> Option<Duration> {
+    STATE
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .min_interval
+}
+
+/// Write the output of `malloc_info` to `fp` through the guard.
+///
+/// # Safety
+/// `fp` must be a valid FILE pointer open for writing, which no other code accesses for the
+/// duration of the call.
+pub(crate) unsafe fn write_info(fp: *mut libc::FILE) -> Result<(), ErrorRepr> {
+    let mut state = STATE.lock().unwrap_or_else(PoisonError::into_inner);
+    if state.min_interval.is_none() {
+        drop(state);
+        return write_fresh(fp);
+    }
+    state.write_info(fp, Instant::now())
+}
+
+struct State {
+    min_interval: Option<Duration>,
+
+    /// When the last capture which called into glibc started, and its output
+    last: Option<(Instant, Vec<u8>)>,
+}
+
+impl State {
+    const fn new() -> Self {
+        Self {
+            min_interval: None,
+            last: None,
+        }
+    }
+
+    /// Write the output of `malloc_info` to `fp` for a capture starting at `now`, reusing the
+    /// last output if it is recent enough.
+    ///
+    /// # Safety
+    /// See [`write_info`].
+    unsafe fn write_info(&mut self, fp: *mut libc::FILE, now: Instant) -> Result<(), ErrorRepr> {
+        let min_interval = match self.min_interval {
+            Some(min_interval) if !min_interval.is_zero() => min_interval,
+            _ => return write_fresh(fp),
+        };
+
+        if let Some((at, xml)) = &self.last {
+            if now.saturating_duration_since(*at) < min_interval {
+                return write_all(fp, xml);
+            }
+        }
+
+        let mem_stream = MemStream::new()?;
+        // SAFETY: The FILE pointer is taken from the mem_stream object, which we have exclusive
+        // access to in this function
+        write_fresh(mem_stream.fp)?;
+        let xml = mem_stream.as_ref().to_vec();
+        drop(mem_stream);
+
+        let result = write_all(fp, &xml);
+        self.last = Some((now, xml));
+        result
+    }
+}
+
+/// Write `xml` to `fp` and flush it
+///
+/// # Safety
+/// See [`write_info`].
+unsafe fn write_all(fp: *mut libc::FILE, xml: &[u8]) -> Result<(), ErrorRepr> {
+    // SAFETY: `xml` is valid for reads of its length, and the caller guarantees `fp` is valid
+    unsafe {
+        if libc::fwrite(xml.as_ptr().cast(), 1, xml.len(), fp) != xml.len() {
+            return Err(ErrorRepr::last_os_error());
+        }
+        if libc::fflush(fp) != 0 {
+            return Err(ErrorRepr::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Capture through `state` at `now`, returning the output
+    fn capture(state: &mut State, now: Instant) -> Vec<u8> {
+        let mem_stream = MemStream::new().expect("memstream");
+        unsafe { state.write_info(mem_stream.fp, now) }.expect("write_info");
+        mem_stream.as_ref().to_vec()
+    }
+
+    #[test]
+    fn reuse() {
+        let mut state = State::new();
+        state.min_interval = Some(Duration::from_secs(60));
+        let start = Instant::now();
+
+        let first = capture(&mut state, start);
+        assert!(first.starts_with(b"<malloc "));
+        let (at, _) = state.last.as_ref().expect("kept for reuse");
+        assert_eq!(*at, start);
+
+        // Allocate something to change the output of a fresh capture
+        let garbage = vec![vec![0u8; 100]; 100];
+        let second = capture(&mut state, start + Duration::from_secs(30));
+        assert_eq!(first, second);
+        assert_eq!(state.last.as_ref().map(|(at, _)| *at), Some(start));
+
+        let later = start + Duration::from_secs(60);
+        capture(&mut state, later);
+        assert_eq!(state.last.as_ref().map(|(at, _)| *at), Some(later));
+        drop(garbage);
+    }
+
+    #[test]
+    fn zero_interval() {
+        let mut state = State::new();
+        state.min_interval = Some(Duration::ZERO);
+        assert!(capture(&mut state, Instant::now()).starts_with(b"<malloc "));
+        assert!(state.last.is_none());
+    }
+
+    #[test]
+    fn disabled() {
+        assert_eq!(min_interval(), None);
+    }
+}
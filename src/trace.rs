@@ -0,0 +1,72 @@
+//! Self-instrumentation for the capture and parse phases of [`malloc_info`](crate::malloc_info).
+//!
+//! With the `tracing` feature enabled, each phase runs inside a `DEBUG` level span carrying the
+//! number of bytes handled, the number of arenas parsed, and the elapsed time in microseconds.
+//! Without the feature, everything here compiles down to nothing.
+
+#[cfg(feature = "tracing")]
+use std::time::Instant;
+
+/// Guard for an instrumented phase. The span is exited and its duration recorded on drop.
+pub(crate) struct Phase {
+    #[cfg(feature = "tracing")]
+    span: tracing::span::EnteredSpan,
+    #[cfg(feature = "tracing")]
+    start: Instant,
+}
+
+impl Phase {
+    /// Enter the span covering the call into glibc
+    pub(crate) fn capture() -> Self {
+        Self::enter(
+            #[cfg(feature = "tracing")]
+            tracing::debug_span!(
+                "malloc_info::capture",
+                bytes = tracing::field::Empty,
+                duration_us = tracing::field::Empty,
+            ),
+        )
+    }
+
+    /// Enter the span covering deserialization of the captured XML
+    pub(crate) fn parse(#[allow(unused_variables)] bytes: usize) -> Self {
+        Self::enter(
+            #[cfg(feature = "tracing")]
+            tracing::debug_span!(
+                "malloc_info::parse",
+                bytes,
+                arenas = tracing::field::Empty,
+                duration_us = tracing::field::Empty,
+            ),
+        )
+    }
+
+    fn enter(#[cfg(feature = "tracing")] span: tracing::Span) -> Self {
+        Self {
+            #[cfg(feature = "tracing")]
+            span: span.entered(),
+            #[cfg(feature = "tracing")]
+            start: Instant::now(),
+        }
+    }
+
+    /// Record the number of bytes produced by `malloc_info`
+    pub(crate) fn record_bytes(&self, #[allow(unused_variables)] bytes: usize) {
+        #[cfg(feature = "tracing")]
+        self.span.record("bytes", bytes);
+    }
+
+    /// Record the number of arenas found in the output
+    pub(crate) fn record_arenas(&self, #[allow(unused_variables)] arenas: usize) {
+        #[cfg(feature = "tracing")]
+        self.span.record("arenas", arenas);
+    }
+}
+
+#[cfg(feature = "tracing")]
+impl Drop for Phase {
+    fn drop(&mut self) {
+        let elapsed = self.start.elapsed().as_micros() as u64;
+        self.span.record("duration_us", elapsed);
+    }
+}
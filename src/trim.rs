@@ -0,0 +1,45 @@
+//! A safe wrapper for glibc's `malloc_trim` function, releasing free heap memory back to the
+//! system. See the [malloc_trim(3)](https://man7.org/linux/man-pages/man3/malloc_trim.3.html) page
+//! for details.
+//!
+//! Free chunks show up in the `fast` and `rest` totals of `malloc_info`, so trimming can be made
+//! conditional on how much memory the heap is holding on to:
+//!
+//! ```rust
+//! use malloc_info::{malloc_info_summary, malloc_trim};
+//!
+//! let summary = malloc_info_summary()?;
+//! if summary.fast + summary.rest > 64 << 20 {
+//!     let released = malloc_trim(0)?;
+//!     println!("released memory: {released}");
+//! }
+//! # Ok::<(), malloc_info::Error>(())
+//! ```
+
+use crate::Error;
+
+/// Release free memory from the heap back to the system with [`libc::malloc_trim`], keeping `pad`
+/// bytes of free space at the top of the main arena for future allocations.
+///
+/// Every arena is trimmed, and glibc releases whole free pages from anywhere in the heap, not just
+/// from the top. Returns whether any memory was released. This takes every arena's lock in turn,
+/// like `malloc_info`, and walks all free chunks, so it isn't meant for hot paths.
+pub fn malloc_trim(pad: usize) -> Result<bool, Error> {
+    // SAFETY: malloc_trim has no preconditions
+    Ok(unsafe { libc::malloc_trim(pad) } != 0)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn trim() {
+        // Free a lot of memory from the heap, in chunks too small to be allocated with mmap. Free
+        // may already release some of it itself, so whether trimming releases any isn't checked.
+        let chunks: Vec<_> = (0..1024).map(|_| vec![1u8; 16 << 10]).collect();
+        drop(chunks);
+        malloc_trim(0).expect("malloc_trim");
+        malloc_trim(usize::MAX).expect("malloc_trim");
+    }
+}
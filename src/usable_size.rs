@@ -0,0 +1,58 @@
+//! A wrapper for glibc's `malloc_usable_size` function, reporting the size of an allocation as the
+//! allocator sees it. See the
+//! [malloc_usable_size(3)](https://man7.org/linux/man-pages/man3/malloc_usable_size.3.html) page
+//! for details.
+//!
+//! The usable size is at least the requested size, and the difference is space lost to rounding
+//! up to the allocator's chunk sizes. Comparing the two for the objects of a program gives its
+//! per-object overhead, to correlate with the free and in-use totals of `malloc_info`.
+//!
+//! Rust allocations go through glibc's allocator as long as the program uses the default
+//! [`System`](std::alloc::System) allocator, so their usable size can be queried as well:
+//!
+//! ```rust
+//! use malloc_info::malloc_usable_size;
+//! use std::ptr::NonNull;
+//!
+//! let mut buffer = Vec::<u8>::with_capacity(100);
+//! let ptr = NonNull::new(buffer.as_mut_ptr()).expect("allocated");
+//! // SAFETY: This program uses the System allocator, which allocates with glibc's malloc, and
+//! // `buffer` is alive
+//! let usable = unsafe { malloc_usable_size(ptr) };
+//! assert!(usable >= 100);
+//! ```
+
+use std::ptr::NonNull;
+
+/// Get the number of usable bytes in the block of memory at `ptr` with
+/// [`libc::malloc_usable_size`].
+///
+/// # Safety
+/// `ptr` must point to the start of a block allocated by glibc's allocator, with `malloc` and
+/// friends or through the [`System`](std::alloc::System) allocator, which hasn't been freed yet.
+/// Memory from any other allocator, such as one installed with `#[global_allocator]`, isn't
+/// allowed.
+pub unsafe fn malloc_usable_size(ptr: NonNull<u8>) -> usize {
+    // SAFETY: The caller guarantees `ptr` is a live block allocated by glibc
+    unsafe { libc::malloc_usable_size(ptr.as_ptr().cast()) }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn usable_size() {
+        // SAFETY: The block is allocated with glibc's malloc, and freed after its size is taken
+        unsafe {
+            let ptr = NonNull::new(libc::malloc(100).cast()).expect("malloc");
+            assert!(malloc_usable_size(ptr) >= 100);
+            libc::free(ptr.as_ptr().cast());
+        }
+
+        let mut boxed = Box::new([0u64; 64]);
+        let ptr = NonNull::from(&mut *boxed).cast();
+        // SAFETY: Tests use the System allocator, and the box is alive
+        assert!(unsafe { malloc_usable_size(ptr) } >= 512);
+    }
+}